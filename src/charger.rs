@@ -0,0 +1,254 @@
+use crate::config::{ChargerType, Config, PhaseSwitchOutput, WallboxConfig};
+use crate::mennekes::{Mennekes, MennekesParams};
+use crate::poller::PollerStats;
+use log::{error, info};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// The lowest current a vehicle can be charged with, in Amps
+pub const MIN_CHARGING_CURRENT: u16 = 6;
+/// A phase the vehicle draws less than this many Amps on doesn't count
+/// as one it charges on
+pub const MIN_PHASE_CURRENT: f32 = 1.0;
+/// Seconds the readings need to reflect a reduction of the charging
+/// current before it is reduced further
+const REDUCTION_SETTLE_TIME: u64 = 5;
+
+/// The charging current to reduce to, given the Amps that are left for
+/// the vehicle: none at all if that is too little to charge with
+pub fn reduced_current(amps: i32) -> u16 {
+    if amps < MIN_CHARGING_CURRENT as i32 {
+        0
+    } else {
+        amps as u16
+    }
+}
+
+/// Holds back further reductions of the charging current until the
+/// readings reflect the last one, so the same excess isn't corrected
+/// twice
+#[derive(Debug, Clone, Default)]
+pub struct Settling {
+    until: u64,
+}
+
+impl Settling {
+    /// Whether the readings reflect the last reduction
+    pub fn settled(&self, now: u64) -> bool {
+        now >= self.until
+    }
+
+    /// Record a reduction of the charging current
+    pub fn reduced(&mut self, now: u64) {
+        self.until = now + REDUCTION_SETTLE_TIME;
+    }
+}
+
+/// A vehicle charging at the grid connection, as the guards of the
+/// main fuse and the unbalanced load see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct VehicleLoad {
+    /// The current the vehicle draws per phase, if the charger knows
+    pub currents: Option<[f32; 3]>,
+    /// The number of phases the charger is switched to, for vehicles
+    /// that don't draw any current yet
+    pub phases: u16,
+    pub setpoint: u16,
+    pub priority: u32,
+    /// When the session started, in seconds since the UNIX epoch
+    pub since: u64,
+}
+
+impl VehicleLoad {
+    /// The phases the vehicle's own currents show it drawing on
+    fn measured_phases(&self) -> Option<[bool; 3]> {
+        self.currents
+            .map(|currents| currents.map(|current| current >= MIN_PHASE_CURRENT))
+    }
+
+    /// Whether the vehicle may draw current on the phase. Vehicles whose
+    /// own currents are unknown are assumed to draw on all phases.
+    pub fn draws_on(&self, phase: usize) -> bool {
+        self.setpoint > 0 && self.measured_phases().is_none_or(|phases| phases[phase])
+    }
+
+    /// The phases the vehicle charges on, including those it will draw
+    /// on once it starts. Single phase chargers are assumed to be
+    /// connected to the first phase.
+    pub fn phases(&self) -> [bool; 3] {
+        match self.measured_phases() {
+            Some(phases) if phases.contains(&true) => phases,
+            _ if self.phases == 1 => [true, false, false],
+            _ => [true; 3],
+        }
+    }
+
+    /// The current the vehicle draws on the given phases
+    pub fn draw(&self, phases: [bool; 3]) -> f32 {
+        match self.currents {
+            Some(currents) => (0..3)
+                .filter(|&phase| phases[phase])
+                .map(|phase| currents[phase])
+                .fold(0.0, f32::max),
+            None => self.setpoint as f32,
+        }
+    }
+}
+
+/// Snapshot of an EV charger's state, independent of the wallbox brand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargerParams {
+    /// Time of the reading, in seconds since the UNIX epoch
+    pub update: u64,
+    /// Whether a vehicle is connected to the charger
+    pub connected: bool,
+    /// Energy charged in the current session, in Watthours
+    pub session_energy: u32,
+    /// Duration of the current session, in seconds
+    pub session_duration: u32,
+    /// The RFID tag that identified the user of the current session
+    pub user_id: Option<String>,
+    /// The maximum current currently signalled to the vehicle, in Amps
+    pub current_setpoint: u16,
+    /// The power the vehicle is actually drawing, in Watts
+    pub power: u32,
+    /// Current drawn per phase in Amps, if the charger measures it
+    #[serde(default)]
+    pub phase_current: Option<[f32; 3]>,
+}
+
+/// An EV charger whose charging current can be controlled
+pub trait Charger: Send + Sync {
+    /// Return the most recent reading, if any
+    fn get_charger_params(&self) -> Option<ChargerParams>;
+
+    /// Return the most recent device specific reading for the status socket
+    fn get_raw_params(&self) -> Option<serde_json::Value>;
+
+    /// Return the connection statistics of the device
+    fn stats(&self) -> PollerStats;
+
+    /// Set the maximum charging current. The message is logged if
+    /// the setpoint actually changes.
+    fn set_amps(&self, amps: u16, message_if_changed: String);
+
+    /// Write a coil of the charger, e.g. one driving a contactor.
+    /// The message is logged once the coil has been written.
+    fn write_coil(&self, address: u16, value: bool, message: String);
+
+    /// Write a holding register of the charger, see write_coil
+    fn write_register(&self, address: u16, value: u16, message: String);
+}
+
+impl From<&MennekesParams> for ChargerParams {
+    fn from(p: &MennekesParams) -> Self {
+        ChargerParams {
+            update: p.update,
+            connected: p.control_pilot != 0,
+            session_energy: p.current_energy,
+            session_duration: p.charging_duration,
+            user_id: p.user_id.clone(),
+            current_setpoint: p.hems_current,
+            power: p.power,
+            phase_current: Some([p.i_l1, p.i_l2, p.i_l3].map(|i| i as f32 / 1000.0)),
+        }
+    }
+}
+
+impl Charger for Mennekes {
+    fn get_charger_params(&self) -> Option<ChargerParams> {
+        self.get_current_params().as_ref().map(ChargerParams::from)
+    }
+
+    fn get_raw_params(&self) -> Option<serde_json::Value> {
+        self.get_current_params()
+            .and_then(|p| serde_json::to_value(p).ok())
+    }
+
+    fn stats(&self) -> PollerStats {
+        self.poller().stats()
+    }
+
+    fn set_amps(&self, amps: u16, message_if_changed: String) {
+        Mennekes::set_amps(self, amps, message_if_changed)
+    }
+
+    fn write_coil(&self, address: u16, value: bool, message: String) {
+        Mennekes::write_coil(self, address, value, message)
+    }
+
+    fn write_register(&self, address: u16, value: u16, message: String) {
+        Mennekes::write_register(self, address, value, message)
+    }
+}
+
+/// Create the chargers listed in the configuration
+pub fn chargers_from_config(config: &Config) -> Result<Vec<Arc<dyn Charger>>> {
+    let chargers = config
+        .wallboxes()
+        .map(charger_from_config)
+        .collect::<Result<Vec<_>>>()?;
+    if chargers.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No wallbox configured"));
+    }
+    Ok(chargers)
+}
+
+/// Create the given charger
+pub fn charger_from_config(wallbox: &WallboxConfig) -> Result<Arc<dyn Charger>> {
+    let connection = &wallbox.connection;
+    match wallbox.charger_type {
+        ChargerType::Mennekes => {
+            let mennekes = Mennekes::new(
+                connection.connector()?,
+                std::time::Duration::from_secs(2),
+                connection.load_register_map()?,
+            )?;
+            Ok(Arc::new(mennekes))
+        }
+    }
+}
+
+/// Switch the charger to the given number of phases via the configured
+/// output. Charging must have been paused before.
+pub fn switch_phases(charger: &dyn Charger, output: &PhaseSwitchOutput, phases: u16) {
+    let message = format!("Switched the charger to {} phases", phases);
+    let one_phase = phases == 1;
+    match output {
+        PhaseSwitchOutput::Coil { address, inverted } => {
+            charger.write_coil(*address, one_phase != *inverted, message)
+        }
+        PhaseSwitchOutput::Register {
+            address,
+            one_phase: value_one_phase,
+            three_phase: value_three_phase,
+        } => {
+            let value = if one_phase {
+                *value_one_phase
+            } else {
+                *value_three_phase
+            };
+            charger.write_register(*address, value, message)
+        }
+        PhaseSwitchOutput::Command {
+            one_phase: command_one_phase,
+            three_phase: command_three_phase,
+        } => {
+            let command = if one_phase {
+                command_one_phase
+            } else {
+                command_three_phase
+            };
+            let mut parts = command.split_whitespace();
+            let Some(program) = parts.next() else {
+                error!("The phase switch command is empty");
+                return;
+            };
+            match std::process::Command::new(program).args(parts).status() {
+                Ok(status) if status.success() => info!("{}", message),
+                Ok(status) => error!("Phase switch command {:?} failed: {}", command, status),
+                Err(e) => error!("Error running phase switch command {:?}: {}", command, e),
+            }
+        }
+    }
+}
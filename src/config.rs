@@ -1,10 +1,29 @@
+use crate::poller::{Connector, TcpConnector};
+use crate::register_map::RegisterMap;
+use crate::rtu::{Parity, RtuConnector};
+use crate::MODBUS_DEFAULT_PORT;
+use chrono::NaiveTime;
+use regex::Regex;
 use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+const MODBUS_DEFAULT_SLAVE_ID: u8 = 1;
+const RTU_DEFAULT_BAUD_RATE: u32 = 19200;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub pac2200: Option<ModbusConnection>,
-    pub e3dc: ModbusConnection,
-    pub wallbox: ModbusConnection,
+    #[serde(default)]
+    pub pv_source: PvSourceType,
+    pub e3dc: Option<ModbusConnection>,
+    /// A single charger, kept for existing configurations
+    pub wallbox: Option<WallboxConfig>,
+    #[serde(default)]
+    pub wallboxes: Vec<WallboxConfig>,
+    /// How to share the surplus and the fuse among several chargers
+    #[serde(default)]
+    pub sharing: SharingStrategy,
     pub initial_connection_timeout: u64,
     pub phases: PhasesConfig,
     pub phase_voltage: u16,
@@ -12,17 +31,300 @@ pub struct Config {
     pub initial_phase_duration: u32,
     pub hysteresis_watts: i32,
     pub rfid: HashMap<String, ConfigRfid>,
+    /// The profile of tags that match none of the above
+    pub default_rfid: Option<ConfigRfid>,
     pub bind_to: Option<String>,
+    /// Switch between one and three phases depending on the PV surplus
+    pub phase_switch: Option<PhaseSwitchConfig>,
+    #[serde(default)]
+    pub control_mode: ControlMode,
+    /// How to share the surplus with the home battery
+    pub battery: Option<BatteryConfig>,
+    /// Seconds after which readings of the grid exchange are too old
+    /// to act on
+    pub max_grid_data_age: Option<u64>,
+    /// Seconds to keep the charging current after changing it
+    pub setpoint_interval: Option<u64>,
+    /// The charging current while the PV system runs in backup mode
+    pub island_amps: Option<u16>,
+    /// Seconds after which readings of the PV source are too old to act
+    /// on
+    pub max_pv_data_age: Option<u64>,
+    /// The charging current while the PV readings are too old
+    pub failsafe_amps: Option<u16>,
+    /// Seconds after which readings of a charger are too old, raising
+    /// an alarm
+    pub max_charger_data_age: Option<u64>,
+    /// Smooth the readings the surplus is computed from
+    pub smoothing: Option<SmoothingConfig>,
+    /// Protect the main fuse of the house connection
+    pub fuse: Option<FuseConfig>,
+    /// Limit the unbalanced load between the phases
+    pub unbalanced_load: Option<UnbalancedLoadConfig>,
+    /// The prices of a dynamic electricity tariff
+    pub tariff: Option<TariffConfig>,
+    /// A forecast of the PV production to plan departures with
+    pub forecast: Option<ForecastConfig>,
+    /// The compiled keys of the tags matched by pattern
+    #[serde(skip)]
+    patterns: HashMap<String, Regex>,
+}
+
+/// How to smooth the readings the surplus is computed from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmoothingConfig {
+    #[serde(default)]
+    pub method: SmoothingMethod,
+    /// Seconds over which an increase of the surplus is smoothed
+    pub ramp_up_window: Option<u64>,
+    /// Seconds over which a decrease of the surplus is smoothed
+    pub ramp_down_window: Option<u64>,
+}
+
+/// How the readings of a window are averaged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SmoothingMethod {
+    #[default]
+    Mean,
+    Median,
+    /// An exponential moving average whose time constant is a third of
+    /// the window
+    Exponential,
+}
+
+/// Where to find a forecast of the PV production
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForecastConfig {
+    /// A CSV or JSON file, see Forecast
+    pub file: PathBuf,
+    /// The house consumption to expect while the sun shines, in Watts.
+    /// By default, the current consumption without the vehicle.
+    pub house_power: Option<i32>,
+}
+
+/// Where to find the prices of a dynamic electricity tariff
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TariffConfig {
+    /// A CSV or JSON file, see Tariff
+    pub file: PathBuf,
+}
+
+/// The current limit of the house connection. All currents are in Amps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FuseConfig {
+    pub max_phase_current: f32,
+    /// Reduce the charging current once a phase comes this close to
+    /// the limit
+    pub margin: Option<f32>,
+    /// Seconds to keep the charging current reduced after the limit
+    /// was approached
+    pub hold: Option<u64>,
+}
+
+/// How to limit the unbalanced load of single phase charging
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnbalancedLoadConfig {
+    /// The highest difference between two phases, in VA
+    pub max_power: Option<u32>,
+}
+
+/// How to share the PV surplus with the home battery. All states of
+/// charge are in percent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatteryConfig {
+    /// Charge the battery first up to this state of charge
+    pub priority_soc: Option<u16>,
+    /// From this state of charge on, let the battery discharge into
+    /// the vehicle by up to max_discharge_power
+    pub discharge_soc: Option<u16>,
+    pub max_discharge_power: Option<i32>,
+    /// Stop discharging into the vehicle at this state of charge
+    pub floor_soc: Option<u16>,
+}
+
+/// How the charging current follows the PV surplus
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlMode {
+    /// Increase the current by one Amp at a time if there is surplus
+    /// beyond the hysteresis, reduce it to what the surplus allows
+    #[default]
+    Step,
+    /// A PI controller on the power exchanged with the grid. The gains
+    /// relate the deviation from the target in Watts to the charging
+    /// power in Watts; ki is per second.
+    PI {
+        kp: f64,
+        ki: f64,
+        /// Watts to keep exporting to the grid
+        #[serde(default)]
+        export_offset: i32,
+    },
+    /// Like Step, but the surplus is what is exchanged with the grid
+    /// beyond the target, e.g. -100 to keep exporting 100 Watts or 500
+    /// to allow importing up to 500 Watts
+    Grid {
+        #[serde(default)]
+        target_grid_power: i32,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigRfid {
     pub name: String,
+    /// How the key of the section is matched against the tag
+    #[serde(default, rename = "match")]
+    pub matching: RfidMatch,
     pub pv_only: bool,
     pub min_amp: u16,
     pub max_amp: u16,
     pub max_charge: Option<u32>,
     pub minimum_charging_power: Option<i32>,
+    /// Vehicles with a higher priority are served first when sharing by
+    /// priority
+    #[serde(default)]
+    pub priority: u32,
+    /// Time windows overriding pv_only, the first matching one applies
+    #[serde(default)]
+    pub schedule: Vec<ScheduleWindow>,
+    /// Energy to have charged by a departure time
+    #[serde(default)]
+    pub departure: Vec<Departure>,
+    /// When to charge from the grid depending on the tariff's prices
+    pub price_policy: Option<PricePolicy>,
+    /// Seconds to keep charging once started
+    pub min_charge_duration: Option<u64>,
+    /// Seconds to keep charging paused once stopped
+    pub min_pause_duration: Option<u64>,
+    /// How often charging may be started per session
+    pub max_cycles: Option<u32>,
+}
+
+/// When to charge from the grid at max_amp, regardless of pv_only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PricePolicy {
+    /// Whenever the price in €/kWh is below max_price
+    Below { max_price: f64 },
+    /// During the cheapest hours before the next departure, or before
+    /// the last known price without one. By default, as many hours as
+    /// it takes to charge the departure's energy.
+    Cheapest { hours: Option<f64> },
+}
+
+/// A time window of the week. If to is before from, the window ends
+/// on the following day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleWindow {
+    /// The days the window starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+    pub pv_only: bool,
+}
+
+/// When a vehicle is to leave and how much energy the session has to
+/// have charged by then, in Watthours
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Departure {
+    /// The days the vehicle leaves on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub time: TimeOfDay,
+    pub energy: u32,
+}
+
+/// A time of day in the local time zone, written as "HH:MM"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        NaiveTime::parse_from_str(&s, "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|e| format!("Invalid time of day {:?}: {}", s, e))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        t.0.format("%H:%M").to_string()
+    }
+}
+
+/// A day of the week, written as e.g. "Mon" or "Monday"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Weekday(pub chrono::Weekday);
+
+impl TryFrom<String> for Weekday {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+            .map(Weekday)
+            .map_err(|_| format!("Invalid day of the week {:?}", s))
+    }
+}
+
+impl From<Weekday> for String {
+    fn from(d: Weekday) -> Self {
+        d.0.to_string()
+    }
+}
+
+/// How the key of an RFID section is matched against the upper case
+/// tag reported by the charger
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum RfidMatch {
+    #[default]
+    Exact,
+    /// The tag starts with the key
+    Prefix,
+    /// The key is a regular expression the tag matches
+    Regex,
+}
+
+/// How to share the PV surplus and the fuse's current among the
+/// sessions of several chargers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SharingStrategy {
+    /// Equally, as far as the vehicles can take it
+    #[default]
+    Equal,
+    /// By the priority of the vehicles' RFID tags
+    Priority,
+    /// By the time the vehicles were connected
+    FirstCome,
+}
+
+/// The EV charger and how to reach it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WallboxConfig {
+    /// The name of the charger on the status socket
+    pub name: Option<String>,
+    #[serde(rename = "type", default)]
+    pub charger_type: ChargerType,
+    #[serde(flatten)]
+    pub connection: ModbusConnection,
+}
+
+/// The brand of the EV charger
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum ChargerType {
+    #[default]
+    Mennekes,
+}
+
+/// The kind of PV system that provides the surplus readings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum PvSourceType {
+    #[default]
+    E3DC,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +333,106 @@ pub enum PhasesConfig {
     ThreePhase,
 }
 
+/// How and when to switch the charger between one and three phases
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseSwitchConfig {
+    pub output: PhaseSwitchOutput,
+    /// Seconds charging is paused before and after a switch
+    pub pause: Option<u64>,
+    /// Seconds to stay at a number of phases before switching again
+    pub min_dwell: Option<u64>,
+}
+
+/// The output that drives the phase switching contactor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PhaseSwitchOutput {
+    /// A coil of the charger that is set for one phase, or for three
+    /// phases if inverted
+    Coil {
+        address: u16,
+        #[serde(default)]
+        inverted: bool,
+    },
+    /// A holding register of the charger and the values to write
+    Register {
+        address: u16,
+        one_phase: u16,
+        three_phase: u16,
+    },
+    /// Commands to run, e.g. to drive an external contactor
+    Command {
+        one_phase: String,
+        three_phase: String,
+    },
+}
+
+impl Config {
+    /// The configured chargers, the single one first
+    pub fn wallboxes(&self) -> impl Iterator<Item = &WallboxConfig> {
+        self.wallbox.iter().chain(self.wallboxes.iter())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let config = std::fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&config)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))?;
+        config
+            .compile_patterns()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))?;
+        Ok(config)
+    }
+
+    /// Compile the keys of the tags matched by pattern
+    fn compile_patterns(&mut self) -> Result<()> {
+        self.patterns.clear();
+        for (key, rfid) in &self.rfid {
+            if rfid.matching == RfidMatch::Regex {
+                let pattern = Regex::new(key)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", key, e)))?;
+                self.patterns.insert(key.clone(), pattern);
+            }
+        }
+        Ok(())
+    }
+
+    /// The section of the given tag, if it is known. Exact matches take
+    /// precedence over prefixes and patterns, which are tried in the
+    /// order of their keys.
+    pub fn known_rfid(&self, user_id: &str) -> Option<&ConfigRfid> {
+        let user_id = user_id.to_uppercase();
+        if let Some(rfid) = self
+            .rfid
+            .get(&user_id)
+            .filter(|rfid| rfid.matching == RfidMatch::Exact)
+        {
+            return Some(rfid);
+        }
+        let mut keys: Vec<&String> = self.rfid.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .filter(|key| {
+                let key = key.as_str();
+                match self.rfid[key].matching {
+                    RfidMatch::Exact => false,
+                    RfidMatch::Prefix => user_id.starts_with(&key.to_uppercase()),
+                    RfidMatch::Regex => self
+                        .patterns
+                        .get(key)
+                        .is_some_and(|pattern| pattern.is_match(&user_id)),
+                }
+            })
+            .map(|key| &self.rfid[key])
+            .next()
+    }
+
+    /// The profile to charge the given tag with: its section or, for
+    /// unknown tags, the default one
+    pub fn rfid_for(&self, user_id: &str) -> Option<&ConfigRfid> {
+        self.known_rfid(user_id).or(self.default_rfid.as_ref())
+    }
+}
+
 impl PhasesConfig {
     pub fn number(&self) -> u16 {
         match self {
@@ -40,8 +442,177 @@ impl PhasesConfig {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How to reach a Modbus device: either via TCP (host and port) or
+/// via RTU on an RS-485 bus (serial port, baud rate and parity)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ModbusConnection {
-    pub host: String,
+    pub host: Option<String>,
     pub port: Option<u16>,
+    pub serial_port: Option<PathBuf>,
+    pub baud_rate: Option<u32>,
+    pub parity: Option<Parity>,
+    /// The Modbus unit identifier of the device
+    pub slave_id: Option<u8>,
+    /// A register map file that overrides the built-in register layout
+    pub register_map: Option<PathBuf>,
+}
+
+impl ModbusConnection {
+    pub fn connector(&self) -> Result<Box<dyn Connector>> {
+        match (&self.host, &self.serial_port) {
+            (Some(host), None) => Ok(Box::new(TcpConnector {
+                host: host.clone(),
+                port: self.port.unwrap_or(MODBUS_DEFAULT_PORT),
+                slave_id: self.slave_id.unwrap_or(MODBUS_DEFAULT_SLAVE_ID),
+            })),
+            (None, Some(serial_port)) => Ok(Box::new(RtuConnector {
+                serial_port: serial_port.clone(),
+                baud_rate: self.baud_rate.unwrap_or(RTU_DEFAULT_BAUD_RATE),
+                parity: self.parity.unwrap_or(Parity::Even),
+                slave_id: self.slave_id.unwrap_or(MODBUS_DEFAULT_SLAVE_ID),
+            })),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A Modbus connection needs either a host or a serial_port",
+            )),
+        }
+    }
+
+    pub fn load_register_map(&self) -> Result<Option<RegisterMap>> {
+        self.register_map
+            .as_ref()
+            .map(|path| RegisterMap::from_file(path))
+            .transpose()
+    }
+}
+
+/// Append a section for the given tag to the configuration file, so it
+/// is known from then on, unless the file knows the tag already
+pub fn adopt_rfid(path: &Path, user_id: &str, rfid: &ConfigRfid) -> Result<()> {
+    let user_id = user_id.to_uppercase();
+    let config = Config::from_file(path)?;
+    if config.rfid.contains_key(&user_id) || config.known_rfid(&user_id).is_some() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("The RFID tag {} is known already", user_id),
+        ));
+    }
+    // Nested under its table, so the tables within the section, e.g. of
+    // a schedule, are written below it rather than at the top level
+    let invalid = |e: toml::ser::Error| Error::new(ErrorKind::InvalidData, e);
+    let section =
+        toml::Table::from_iter([(user_id, toml::Value::try_from(rfid).map_err(invalid)?)]);
+    let document = toml::Table::from_iter([(String::from("rfid"), toml::Value::Table(section))]);
+    let document = toml::to_string(&document).map_err(invalid)?;
+    let mut file = std::fs::File::options().append(true).open(path)?;
+    write!(file, "\n{}", document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfid(name: &str, matching: RfidMatch) -> ConfigRfid {
+        ConfigRfid {
+            name: String::from(name),
+            matching,
+            pv_only: true,
+            min_amp: 6,
+            max_amp: 16,
+            max_charge: None,
+            minimum_charging_power: None,
+            priority: 0,
+            schedule: Vec::new(),
+            departure: Vec::new(),
+            price_policy: None,
+            min_charge_duration: None,
+            min_pause_duration: None,
+            max_cycles: None,
+        }
+    }
+
+    fn config() -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            wallbox = { host = "localhost" }
+            initial_connection_timeout = 60
+            phases = "ThreePhase"
+            phase_voltage = 230
+            default_amps = 8
+            initial_phase_duration = 180
+            hysteresis_watts = 200
+            [rfid]
+            "#,
+        )
+        .unwrap();
+        for (key, rfid) in [
+            ("04AABBCC", rfid("exact", RfidMatch::Exact)),
+            ("04AA", rfid("prefix", RfidMatch::Prefix)),
+            ("^05[0-9A-F]{6}$", rfid("regex", RfidMatch::Regex)),
+        ] {
+            config.rfid.insert(String::from(key), rfid);
+        }
+        config.compile_patterns().unwrap();
+        config
+    }
+
+    #[test]
+    fn tags_are_matched_by_key_prefix_and_pattern() {
+        let mut config = config();
+        let name = |config: &Config, user_id| config.rfid_for(user_id).map(|r| r.name.clone());
+        assert_eq!(name(&config, "04aabbcc").as_deref(), Some("exact"));
+        assert_eq!(name(&config, "04AABBCC  ").as_deref(), Some("prefix"));
+        assert_eq!(name(&config, "05aabbcc").as_deref(), Some("regex"));
+        assert_eq!(name(&config, "05AABBCCDD"), None);
+        assert!(config.known_rfid("06AABBCC").is_none());
+
+        config.default_rfid = Some(rfid("guest", RfidMatch::Exact));
+        assert_eq!(name(&config, "06AABBCC").as_deref(), Some("guest"));
+        assert!(config.known_rfid("06AABBCC").is_none());
+    }
+
+    #[test]
+    fn adopted_tags_are_appended() {
+        let path = std::env::temp_dir().join(format!("wallbox-{}.toml", std::process::id()));
+        // The file ends with a table the new section must not end up in
+        let mut file = toml::to_string(&config()).unwrap();
+        file.push_str("\n[tariff]\nfile = \"prices.csv\"\n");
+        std::fs::write(&path, file).unwrap();
+        let time = |t: &str| TimeOfDay::try_from(String::from(t)).unwrap();
+        let guest = ConfigRfid {
+            schedule: vec![ScheduleWindow {
+                days: vec![Weekday(chrono::Weekday::Sat)],
+                from: time("22:00"),
+                to: time("06:00"),
+                pv_only: false,
+            }],
+            departure: vec![Departure {
+                days: Vec::new(),
+                time: time("07:30"),
+                energy: 10000,
+            }],
+            price_policy: Some(PricePolicy::Below { max_price: 0.2 }),
+            max_cycles: Some(3),
+            ..rfid("adopted", RfidMatch::Exact)
+        };
+        adopt_rfid(&path, "06aabbcc", &guest).unwrap();
+        // Known tags aren't adopted again, by key or by prefix
+        assert!(adopt_rfid(&path, "06AABBCC", &guest).is_err());
+        assert!(adopt_rfid(&path, "04AA0000", &guest).is_err());
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let adopted = config.known_rfid("06AABBCC").unwrap();
+        assert_eq!(adopted.name, "adopted");
+        assert!(adopted.pv_only);
+        assert_eq!(adopted.schedule[0].from, time("22:00"));
+        assert_eq!(adopted.departure[0].energy, 10000);
+        assert!(matches!(
+            adopted.price_policy,
+            Some(PricePolicy::Below { max_price }) if max_price == 0.2
+        ));
+        assert_eq!(adopted.max_cycles, Some(3));
+        assert_eq!(config.known_rfid("05AABBCC").unwrap().name, "regex");
+        assert_eq!(config.tariff.unwrap().file, Path::new("prices.csv"));
+    }
 }
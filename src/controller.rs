@@ -0,0 +1,1634 @@
+use crate::charger::ChargerParams;
+use crate::config::{BatteryConfig, Config, ConfigRfid, ControlMode};
+use crate::forecast::Forecast;
+use crate::grid::GridParams;
+use crate::pv_source::PvParams;
+use crate::schedule::{self, Plan};
+use crate::tariff::Tariff;
+use chrono::NaiveDateTime;
+use log::{debug, info, warn};
+
+/// Seconds between two evaluations in normal operation
+const EVALUATION_INTERVAL: u64 = 20;
+/// Additional seconds to wait after signalling the initial current, so
+/// the vehicle can settle before the PV surplus is considered
+const INITIAL_PHASE_WAIT: u64 = 60;
+/// Seconds to wait after charging was halted for lack of PV power
+const PV_HALT_WAIT: u64 = 120;
+/// Additional seconds to wait after an unknown RFID tag was seen
+const UNKNOWN_TAG_WAIT: u64 = 60;
+/// Seconds charging is paused before and after switching the phases
+const DEFAULT_PHASE_SWITCH_PAUSE: u64 = 60;
+/// Seconds to stay at a number of phases before switching again
+const DEFAULT_PHASE_SWITCH_DWELL: u64 = 600;
+/// The PI controller integrates over at most this many seconds between
+/// two evaluations
+const MAX_PI_INTERVAL: u64 = 60;
+/// Seconds after which readings of the grid exchange are too old to
+/// act on
+const DEFAULT_MAX_GRID_DATA_AGE: u64 = 30;
+/// Seconds after which readings of the PV source are too old to act on
+pub const DEFAULT_MAX_PV_DATA_AGE: u64 = 60;
+
+/// Settings of the current charging session that can be changed at
+/// runtime via the status socket
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct CurrSettings {
+    pub max_session_energy: Option<u32>,
+}
+
+/// The settings of the charging policy, taken from the configuration
+#[derive(Debug, Clone)]
+pub struct ControllerSettings {
+    pub default_amps: u16,
+    pub initial_phase_duration: u32,
+    pub phase_voltage: u16,
+    /// The number of phases to charge with unless switched
+    pub phases: u16,
+    pub hysteresis_watts: i32,
+    pub phase_switch: Option<PhaseSwitchSettings>,
+    pub control_mode: ControlMode,
+    pub battery: Option<BatterySettings>,
+    /// Seconds after which grid readings are too old to act on
+    pub max_grid_data_age: u64,
+    /// Seconds to keep the charging current after changing it
+    pub setpoint_interval: u64,
+    /// The charging current while the PV system runs in backup mode
+    pub island_amps: u16,
+    /// Seconds after which PV readings are too old to act on
+    pub max_pv_data_age: u64,
+    /// The charging current while the PV readings are too old, if not
+    /// the default current, or 0 for PV only charging
+    pub failsafe_amps: Option<u16>,
+}
+
+/// How to share the surplus with the home battery, see BatteryConfig
+#[derive(Debug, Clone)]
+pub struct BatterySettings {
+    pub priority_soc: u16,
+    pub discharge_soc: Option<u16>,
+    pub max_discharge_power: i32,
+    pub floor_soc: u16,
+}
+
+impl From<&BatteryConfig> for BatterySettings {
+    fn from(battery: &BatteryConfig) -> Self {
+        BatterySettings {
+            priority_soc: battery.priority_soc.unwrap_or(0),
+            discharge_soc: battery.discharge_soc,
+            max_discharge_power: battery.max_discharge_power.unwrap_or(0),
+            floor_soc: battery.floor_soc.unwrap_or(0),
+        }
+    }
+}
+
+/// When to switch between one and three phases
+#[derive(Debug, Clone)]
+pub struct PhaseSwitchSettings {
+    /// Seconds charging is paused before and after a switch
+    pub pause: u64,
+    /// Seconds to stay at a number of phases before switching again
+    pub min_dwell: u64,
+}
+
+impl From<&Config> for ControllerSettings {
+    fn from(config: &Config) -> Self {
+        ControllerSettings {
+            default_amps: config.default_amps,
+            initial_phase_duration: config.initial_phase_duration,
+            phase_voltage: config.phase_voltage,
+            phases: config.phases.number(),
+            hysteresis_watts: config.hysteresis_watts,
+            phase_switch: config.phase_switch.as_ref().map(|ps| PhaseSwitchSettings {
+                pause: ps.pause.unwrap_or(DEFAULT_PHASE_SWITCH_PAUSE),
+                min_dwell: ps.min_dwell.unwrap_or(DEFAULT_PHASE_SWITCH_DWELL),
+            }),
+            control_mode: config.control_mode.clone(),
+            battery: config.battery.as_ref().map(BatterySettings::from),
+            max_grid_data_age: config
+                .max_grid_data_age
+                .unwrap_or(DEFAULT_MAX_GRID_DATA_AGE),
+            setpoint_interval: config.setpoint_interval.unwrap_or(0),
+            island_amps: config.island_amps.unwrap_or(0),
+            max_pv_data_age: config.max_pv_data_age.unwrap_or(DEFAULT_MAX_PV_DATA_AGE),
+            failsafe_amps: config.failsafe_amps,
+        }
+    }
+}
+
+/// Everything the controller bases a decision on
+pub struct Inputs<'a> {
+    pub pv: &'a PvParams,
+    pub charger: &'a ChargerParams,
+    /// The reading of the grid exchange point, if there is one
+    pub grid: Option<&'a GridParams>,
+    /// The settings of the connected vehicle's RFID tag, if it is known
+    pub rfid: Option<&'a ConfigRfid>,
+    pub session: &'a CurrSettings,
+    /// The current time in seconds since the UNIX epoch
+    pub now: u64,
+    /// The current local time, which schedules refer to
+    pub local_time: NaiveDateTime,
+    /// The prices of the electricity tariff, if one is configured
+    pub tariff: Option<&'a Tariff>,
+    /// The forecast of the PV production, if one is configured
+    pub forecast: Option<&'a Forecast>,
+    /// The power this vehicle may use if the surplus is shared with the
+    /// vehicles at other chargers, see Controller::surplus
+    pub budget: Option<i32>,
+}
+
+/// A change of the charging session the caller has to record
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionChange {
+    /// A known vehicle connected, its session energy limit applies
+    Connected {
+        name: String,
+        max_session_energy: Option<u32>,
+    },
+    /// The vehicle of the given name was disconnected
+    Disconnected(String),
+}
+
+/// What to do until the next evaluation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    /// The current to signal to the vehicle, or None to leave it as is
+    pub amps: Option<u16>,
+    pub reason: String,
+    /// When to evaluate again, in seconds since the UNIX epoch
+    pub next_evaluation: u64,
+    pub session: Option<SessionChange>,
+    /// The number of phases to switch the charger to, after the
+    /// current has been signalled
+    pub phases: Option<u16>,
+}
+
+/// The progress of a switch between one and three phases
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PhaseSwitching {
+    Idle,
+    /// Charging is paused, the phases are switched at the given time
+    Pausing {
+        phases: u16,
+        until: u64,
+    },
+    /// The phases were switched, charging resumes at the given time
+    Settling {
+        until: u64,
+    },
+}
+
+/// When charging was started and stopped during the session, to keep
+/// the vehicle and the contactor from switching too often
+#[derive(Debug, Clone, Default)]
+struct Cycling {
+    started: Option<u64>,
+    stopped: Option<u64>,
+    /// How often charging was started
+    starts: u32,
+    /// When the charging current was last changed
+    changed: Option<u64>,
+}
+
+/// Decides on the charging current and the number of phases. The
+/// controller has no side effects; it only remembers which vehicle is
+/// connected and how the phases are switched.
+pub struct Controller {
+    settings: ControllerSettings,
+    vehicle: Option<String>,
+    /// The number of phases the charger is switched to. This is
+    /// unknown after startup if phase switching is configured.
+    phases: Option<u16>,
+    last_phase_switch: Option<u64>,
+    phase_switching: PhaseSwitching,
+    /// The integral part of the PI controller in Watts, when it was
+    /// last updated and the current the controller set
+    pi_integral: Option<(f64, u64, u16)>,
+    /// Whether the home battery may currently discharge into the vehicle
+    battery_discharge: bool,
+    /// The plan of the connected vehicle's session
+    plan: Option<Plan>,
+    cycling: Cycling,
+}
+
+fn decision(now: u64, amps: Option<u16>, reason: String, wait: u64) -> Decision {
+    Decision {
+        amps,
+        reason,
+        next_evaluation: now + EVALUATION_INTERVAL + wait,
+        session: None,
+        phases: None,
+    }
+}
+
+/// Keep charging paused until the given time
+fn pause(reason: String, until: u64) -> Decision {
+    Decision {
+        amps: Some(0),
+        reason,
+        next_evaluation: until,
+        session: None,
+        phases: None,
+    }
+}
+
+impl Controller {
+    pub fn new(settings: ControllerSettings) -> Controller {
+        let phases = match settings.phase_switch {
+            Some(_) => None,
+            None => Some(settings.phases),
+        };
+        Controller {
+            settings,
+            vehicle: None,
+            phases,
+            last_phase_switch: None,
+            phase_switching: PhaseSwitching::Idle,
+            pi_integral: None,
+            battery_discharge: false,
+            plan: None,
+            cycling: Cycling::default(),
+        }
+    }
+
+    /// The number of phases the charger is switched to
+    pub fn phases(&self) -> u16 {
+        self.phases.unwrap_or(self.settings.phases)
+    }
+
+    /// The plan of the connected vehicle's session, if it is known
+    pub fn plan(&self) -> Option<&Plan> {
+        self.plan.as_ref()
+    }
+
+    pub fn decide(&mut self, inputs: &Inputs) -> Decision {
+        let settings = &self.settings;
+
+        // The home battery is all there is, whether a vehicle is
+        // connected or not
+        if inputs.pv.island {
+            self.pi_integral = None;
+            return decision(
+                inputs.now,
+                Some(settings.island_amps),
+                format!(
+                    "The PV system runs in backup mode without the grid, setting MAX_AMPS to {}A",
+                    settings.island_amps
+                ),
+                0,
+            );
+        }
+
+        if !inputs.charger.connected {
+            let mut decision = decision(
+                inputs.now,
+                Some(settings.default_amps),
+                format!(
+                    "No vehicle connected, setting MAX_AMPS to the configured default of {}A",
+                    settings.default_amps
+                ),
+                0,
+            );
+            decision.session = self.vehicle.take().map(SessionChange::Disconnected);
+            self.pi_integral = None;
+            self.plan = None;
+            self.cycling = Cycling::default();
+            // Nothing is charged, so the phases can be switched back
+            // to the configured number right away
+            self.phase_switching = PhaseSwitching::Idle;
+            if settings.phase_switch.is_some() && self.phases != Some(settings.phases) {
+                self.switch_phases(settings.phases, inputs.now, &mut decision);
+            }
+            return decision;
+        }
+
+        let Some(rfid) = inputs.rfid else {
+            self.plan = None;
+            let user_id = inputs.charger.user_id.as_deref().unwrap_or("");
+            return decision(
+                inputs.now,
+                Some(0),
+                format!(
+                    "Unknown RFID tag {}, setting MAX_AMPS to 0A!",
+                    user_id.to_uppercase()
+                ),
+                UNKNOWN_TAG_WAIT,
+            );
+        };
+
+        let mut session = None;
+        let mut max_session_energy = inputs.session.max_session_energy;
+        if self.vehicle.as_ref() != Some(&rfid.name) {
+            self.vehicle = Some(rfid.name.clone());
+            self.cycling = Cycling::default();
+            max_session_energy = rfid.max_charge;
+            session = Some(SessionChange::Connected {
+                name: rfid.name.clone(),
+                max_session_energy,
+            });
+        }
+
+        // The plan decides whether to charge from PV only
+        let max_power = rfid.max_amp as i32 * self.step_power(settings.phases);
+        let plan = schedule::plan(rfid, inputs, max_power);
+        let rfid = ConfigRfid {
+            pv_only: plan.pv_only,
+            ..rfid.clone()
+        };
+        self.plan = Some(plan);
+
+        // Signalling the initial current, stopping at the energy limit,
+        // pausing to switch the phases and falling back to the failsafe
+        // current are never held back, but count as starts and stops all
+        // the same
+        let charger = inputs.charger;
+        let exempt = charger.session_duration < self.settings.initial_phase_duration
+            || max_session_energy.is_some_and(|limit| limit < charger.session_energy)
+            || self.phase_switching != PhaseSwitching::Idle
+            || self.pv_data_age(inputs).is_some();
+        let mut decision = self.decide_amps(inputs, &rfid, max_session_energy);
+        if !exempt && self.phase_switching == PhaseSwitching::Idle {
+            decision = self.restrain(inputs, &rfid, decision);
+        } else {
+            self.record_change(inputs, &decision);
+        }
+        decision.session = session;
+        decision
+    }
+
+    /// Hold back a change of the charging current that comes too soon
+    /// after the last one, or a start or stop that comes too soon after
+    /// the last stop or start, and record the changes that are made
+    fn restrain(&mut self, inputs: &Inputs, rfid: &ConfigRfid, decided: Decision) -> Decision {
+        let now = inputs.now;
+        let current = inputs.charger.current_setpoint;
+        let Some(amps) = decided.amps.filter(|amps| *amps != current) else {
+            return decided;
+        };
+        let cycling = &self.cycling;
+        let since = |time: Option<u64>, duration: Option<u64>| {
+            time.zip(duration)
+                .map(|(time, duration)| (now.saturating_sub(time), duration))
+                .filter(|(elapsed, duration)| elapsed < duration)
+        };
+        let held_back = if current > 0 && amps == 0 {
+            since(cycling.started, rfid.min_charge_duration).map(|(elapsed, duration)| {
+                format!(
+                    "Not stopping vehicle {} yet, it has charged for {}s of at least {}s",
+                    rfid.name, elapsed, duration
+                )
+            })
+        } else if current == 0 && amps > 0 {
+            match rfid.max_cycles.filter(|max| cycling.starts >= *max) {
+                Some(max) => Some(format!(
+                    "Not starting vehicle {} again, it was started {} times this session already",
+                    rfid.name, max
+                )),
+                None => {
+                    since(cycling.stopped, rfid.min_pause_duration).map(|(elapsed, duration)| {
+                        format!(
+                            "Not starting vehicle {} yet, it has paused for {}s of at least {}s",
+                            rfid.name, elapsed, duration
+                        )
+                    })
+                }
+            }
+        } else {
+            None
+        };
+        let held_back = held_back.or_else(|| {
+            since(cycling.changed, Some(self.settings.setpoint_interval)).map(|(elapsed, _)| {
+                format!(
+                    "Not changing the charging current from {}A to {}A yet, it was changed {}s ago",
+                    current, amps, elapsed
+                )
+            })
+        });
+        if let Some(reason) = held_back {
+            info!("{} ({})", reason, decided.reason);
+            return decision(now, None, reason, 0);
+        }
+        self.record_change(inputs, &decided);
+        decided
+    }
+
+    /// Record when the charging current was last changed and the vehicle
+    /// last started or stopped
+    fn record_change(&mut self, inputs: &Inputs, decided: &Decision) {
+        let current = inputs.charger.current_setpoint;
+        let Some(amps) = decided.amps.filter(|amps| *amps != current) else {
+            return;
+        };
+        let cycling = &mut self.cycling;
+        cycling.changed = Some(inputs.now);
+        if amps == 0 {
+            cycling.stopped = Some(inputs.now);
+        } else if current == 0 {
+            cycling.started = Some(inputs.now);
+            cycling.starts += 1;
+        }
+    }
+
+    fn decide_amps(
+        &mut self,
+        inputs: &Inputs,
+        rfid: &ConfigRfid,
+        max_session_energy: Option<u32>,
+    ) -> Decision {
+        let pv = inputs.pv;
+        let charger = inputs.charger;
+        let now = inputs.now;
+
+        if let Some(decision) = self.continue_phase_switch(now) {
+            return decision;
+        }
+
+        let settings = &self.settings;
+        if charger.session_duration < settings.initial_phase_duration {
+            return decision(
+                now,
+                Some(settings.default_amps),
+                format!(
+                    "Vehicle {} connected for less than {} seconds, signalling {} amps",
+                    rfid.name, settings.initial_phase_duration, settings.default_amps
+                ),
+                INITIAL_PHASE_WAIT,
+            );
+        }
+
+        if let Some(limit) = max_session_energy.filter(|l| *l < charger.session_energy) {
+            return decision(
+                now,
+                Some(0),
+                format!(
+                    "Vehicle {} has charged {}Wh, the limit is {}Wh. Stopping the charging.",
+                    rfid.name, charger.session_energy, limit
+                ),
+                0,
+            );
+        }
+
+        if let Some(age) = self.pv_data_age(inputs) {
+            let amps = settings.failsafe_amps.unwrap_or(match rfid.pv_only {
+                true => 0,
+                false => settings.default_amps,
+            });
+            let reason = format!(
+                "The PV reading is {} seconds old, falling back to {}A",
+                age, amps
+            );
+            self.pi_integral = None;
+            return decision(now, Some(amps), reason, 0);
+        }
+
+        let grid_based = !matches!(settings.control_mode, ControlMode::Step);
+        let grid = inputs
+            .grid
+            .filter(|grid| now.saturating_sub(grid.update) <= settings.max_grid_data_age);
+        if grid_based && grid.is_none() {
+            let reason = match inputs.grid {
+                Some(grid) => format!(
+                    "The grid reading is {} seconds old, keeping the charging current",
+                    now.saturating_sub(grid.update)
+                ),
+                None => String::from("No grid reading available, keeping the charging current"),
+            };
+            warn!("{}", reason);
+            return decision(now, None, reason, 0);
+        }
+
+        self.update_battery_discharge(pv.battery_soc);
+        let phases = self.phases();
+        let charging_power = charger.power as i32;
+        let available_power = inputs
+            .budget
+            .unwrap_or_else(|| charging_power + self.surplus_of(pv, grid));
+        let step_power = self.step_power(phases);
+        let charging_power_computed = charger.current_setpoint as i32 * step_power;
+        let minimum_charging_power = self.minimum_charging_power(rfid, phases);
+        debug!(
+            "PV_Power {}W HausPower {}W GridPower {:?}W",
+            pv.pv_power,
+            pv.house_power,
+            grid.map(|grid| grid.power)
+        );
+        debug!(
+            "Charging power {}W Available power {}W Step power {}W ChargingPowerComputed {}W",
+            charging_power, available_power, step_power, charging_power_computed
+        );
+
+        if let Some(decision) = self.start_phase_switch(rfid, available_power, now) {
+            return decision;
+        }
+
+        if let Some(plan) = self.plan.as_ref().filter(|p| p.charge_from_grid(now)) {
+            let reason = if plan.departure_due(now) {
+                format!(
+                    "Vehicle {} needs another {}Wh by its departure, charging at {}A",
+                    rfid.name, plan.remaining_energy, rfid.max_amp
+                )
+            } else {
+                format!(
+                    "Electricity costs {:.4}€/kWh, charging vehicle {} at {}A",
+                    plan.price.unwrap_or_default(),
+                    rfid.name,
+                    rfid.max_amp
+                )
+            };
+            return decision(now, Some(rfid.max_amp), reason, 0);
+        }
+
+        let settings = &self.settings;
+        if available_power < minimum_charging_power {
+            if rfid.pv_only {
+                return decision(
+                    now,
+                    Some(0),
+                    format!(
+                        "Available PV power of {}Watts is less than minimum charging power of {}Watts. Halting charging.",
+                        available_power, minimum_charging_power
+                    ),
+                    PV_HALT_WAIT - EVALUATION_INTERVAL,
+                );
+            }
+            debug!(
+                "Available PV power of {}Watts is less than minimum charging power of {}Watts. Proceeding nevertheless.",
+                available_power, minimum_charging_power
+            );
+        }
+
+        if let (
+            ControlMode::PI {
+                kp,
+                ki,
+                export_offset,
+            },
+            Some(grid),
+        ) = (&settings.control_mode, grid)
+        {
+            let (kp, ki) = (*kp, *ki);
+            let deviation = match inputs.budget {
+                Some(budget) => budget - charging_power,
+                None => -export_offset - grid.power + self.battery_adjustment(pv, true),
+            };
+            return self.follow_grid_power(inputs, rfid, deviation, kp, ki);
+        }
+
+        let step_power_with_hysteresis = step_power + settings.hysteresis_watts;
+        if available_power < charging_power && charger.current_setpoint > rfid.min_amp {
+            let num_amps = std::cmp::max(
+                rfid.min_amp,
+                std::cmp::min(
+                    rfid.max_amp,
+                    ((available_power as f64) / (step_power as f64)).floor() as u16,
+                ),
+            );
+            decision(
+                now,
+                Some(num_amps),
+                format!("Reducing charging current to {}A", num_amps),
+                0,
+            )
+        } else if available_power > (charging_power_computed + step_power_with_hysteresis)
+            && charger.current_setpoint < rfid.max_amp
+        {
+            let set_to = std::cmp::max(charger.current_setpoint + 1, rfid.min_amp);
+            decision(
+                now,
+                Some(set_to),
+                format!(
+                    "Excessive power of {} Watts is available, increasing charging current to {}A",
+                    available_power, set_to
+                ),
+                0,
+            )
+        } else if charger.current_setpoint < rfid.min_amp {
+            let set_to = std::cmp::max(charger.current_setpoint + 1, settings.default_amps);
+            decision(
+                now,
+                Some(set_to),
+                format!(
+                    "HEMS current {}A < than min_amp of {}A, increasing power to {}A",
+                    charger.current_setpoint, rfid.min_amp, set_to
+                ),
+                0,
+            )
+        } else {
+            decision(
+                now,
+                None,
+                format!(
+                    "Keeping the charging current at {}A with {} Watts available",
+                    charger.current_setpoint, available_power
+                ),
+                0,
+            )
+        }
+    }
+
+    /// Set the current so the power exchanged with the grid approaches
+    /// the target, i.e. the deviation, which is the power to spare,
+    /// approaches zero. The integral is limited to the vehicle's current
+    /// range, so it doesn't wind up while the current is at a limit.
+    /// It restarts from the signalled current whenever that was set
+    /// otherwise, e.g. after charging was halted.
+    fn follow_grid_power(
+        &mut self,
+        inputs: &Inputs,
+        rfid: &ConfigRfid,
+        deviation: i32,
+        kp: f64,
+        ki: f64,
+    ) -> Decision {
+        let charger = inputs.charger;
+        let now = inputs.now;
+        let step_power = self.step_power(self.phases());
+        let min_power = (rfid.min_amp as i32 * step_power) as f64;
+        let max_power = (rfid.max_amp as i32 * step_power) as f64;
+        let error = deviation as f64;
+
+        let integral = match self.pi_integral {
+            Some((integral, updated, amps)) if amps == charger.current_setpoint => {
+                let seconds = now.saturating_sub(updated).min(MAX_PI_INTERVAL);
+                integral + ki * error * seconds as f64
+            }
+            _ => (charger.current_setpoint as i32 * step_power) as f64,
+        }
+        .clamp(min_power, max_power);
+        let power = (integral + kp * error).clamp(min_power, max_power);
+        let amps = (power / step_power as f64).round() as u16;
+        self.pi_integral = Some((integral, now, amps));
+        debug!(
+            "PI controller: deviation {}W, integral {:.0}W, output {:.0}W",
+            deviation, integral, power
+        );
+        if amps == charger.current_setpoint {
+            decision(
+                now,
+                None,
+                format!(
+                    "Keeping the charging current at {}A, {} Watts off the target",
+                    amps, deviation
+                ),
+                0,
+            )
+        } else {
+            decision(
+                now,
+                Some(amps),
+                format!(
+                    "{} Watts off the target, setting charging current to {}A",
+                    deviation, amps
+                ),
+                0,
+            )
+        }
+    }
+
+    /// Let the battery discharge into the vehicle from discharge_soc
+    /// on, until floor_soc is reached
+    fn update_battery_discharge(&mut self, soc: u16) {
+        let Some(battery) = &self.settings.battery else {
+            return;
+        };
+        let discharge = if soc <= battery.floor_soc {
+            false
+        } else {
+            self.battery_discharge || battery.discharge_soc.is_some_and(|s| soc >= s)
+        };
+        if discharge != self.battery_discharge {
+            debug!(
+                "Battery at {}%, {} discharging into the vehicle",
+                soc,
+                if discharge { "starting" } else { "stopping" }
+            );
+            self.battery_discharge = discharge;
+        }
+    }
+
+    /// The power the home battery leaves to the vehicle, or takes from
+    /// it if negative. Below priority_soc, the battery's charging power
+    /// is reserved for the battery; above, the vehicle may take it.
+    /// From discharge_soc on, the vehicle may take max_discharge_power
+    /// from the battery. The surplus of PV and house power doesn't
+    /// account for the battery, so a discharge already shows as a
+    /// deficit there; the grid power does, as the battery absorbs
+    /// surplus and covers deficits before the grid, so a discharge
+    /// beyond what is allowed is counted against the vehicle.
+    fn battery_adjustment(&self, pv: &PvParams, grid_based: bool) -> i32 {
+        let Some(battery) = &self.settings.battery else {
+            return 0;
+        };
+        let charging = pv.battery_power.max(0);
+        let reserved = match (pv.battery_soc < battery.priority_soc, grid_based) {
+            (true, false) => -charging,
+            (false, true) => charging,
+            _ => 0,
+        };
+        let discharging = pv.battery_power.min(0);
+        let discharge = match (self.battery_discharge, grid_based) {
+            (true, false) => battery.max_discharge_power,
+            (true, true) => discharging + battery.max_discharge_power,
+            (false, true) => discharging,
+            (false, false) => 0,
+        };
+        reserved + discharge
+    }
+
+    /// The age of the PV reading in seconds, if it is too old to act on
+    fn pv_data_age(&self, inputs: &Inputs) -> Option<u64> {
+        Some(inputs.now.saturating_sub(inputs.pv.update))
+            .filter(|age| *age > self.settings.max_pv_data_age)
+    }
+
+    /// The power available to all vehicles together if none of them
+    /// were charging, to share among several chargers
+    pub fn surplus(&self, inputs: &Inputs) -> i32 {
+        let grid = inputs.grid.filter(|grid| {
+            inputs.now.saturating_sub(grid.update) <= self.settings.max_grid_data_age
+        });
+        self.surplus_of(inputs.pv, grid)
+    }
+
+    /// The minimum and maximum charging power of the vehicle on the
+    /// active phases
+    pub fn power_range(&self, rfid: &ConfigRfid) -> (i32, i32) {
+        let phases = self.phases();
+        (
+            self.minimum_charging_power(rfid, phases),
+            rfid.max_amp as i32 * self.step_power(phases),
+        )
+    }
+
+    /// The surplus beyond the house consumption, including the vehicles,
+    /// or in Grid mode beyond the target grid power. A grid meter, which
+    /// unlike the PV system's own measurement reports the phases, also
+    /// sees the loads and generators the PV system doesn't, so the house
+    /// consumption is taken from its balance: PV production plus import
+    /// minus what the battery charges with.
+    fn surplus_of(&self, pv: &PvParams, grid: Option<&GridParams>) -> i32 {
+        match (&self.settings.control_mode, grid) {
+            (ControlMode::Grid { target_grid_power }, Some(grid)) => {
+                target_grid_power - grid.power + self.battery_adjustment(pv, true)
+            }
+            (_, Some(grid)) if grid.phase_power.is_some() => {
+                -grid.power + pv.battery_power + self.battery_adjustment(pv, false)
+            }
+            _ => pv.pv_power - pv.house_power + self.battery_adjustment(pv, false),
+        }
+    }
+
+    /// The power of one Amp on the given number of phases
+    fn step_power(&self, phases: u16) -> i32 {
+        self.settings.phase_voltage as i32 * phases as i32
+    }
+
+    /// The vehicle's minimum charging power on the given number of
+    /// phases. A configured minimum applies to the configured number
+    /// of phases and is scaled accordingly.
+    fn minimum_charging_power(&self, rfid: &ConfigRfid, phases: u16) -> i32 {
+        match rfid.minimum_charging_power {
+            Some(power) => power * phases as i32 / self.settings.phases as i32,
+            None => self.step_power(phases) * rfid.min_amp as i32,
+        }
+    }
+
+    /// Carry on with a switch of the phases in progress. Charging is
+    /// paused before and after the switch, so the phases never change
+    /// under load.
+    fn continue_phase_switch(&mut self, now: u64) -> Option<Decision> {
+        let pause_duration = self.settings.phase_switch.as_ref()?.pause;
+        if self.phases.is_none() && self.phase_switching == PhaseSwitching::Idle {
+            // After startup, the state of the switch is unknown
+            let until = now + pause_duration;
+            self.phase_switching = PhaseSwitching::Pausing {
+                phases: self.settings.phases,
+                until,
+            };
+            return Some(pause(
+                format!(
+                    "Pausing charging for {}s to switch to the configured {} phases",
+                    pause_duration, self.settings.phases
+                ),
+                until,
+            ));
+        }
+        match self.phase_switching {
+            PhaseSwitching::Idle => None,
+            PhaseSwitching::Pausing { phases, until } if now >= until => {
+                let until = now + pause_duration;
+                self.phase_switching = PhaseSwitching::Settling { until };
+                let mut decision = pause(
+                    format!(
+                        "Switching to {} phases, resuming charging in {}s",
+                        phases, pause_duration
+                    ),
+                    until,
+                );
+                self.switch_phases(phases, now, &mut decision);
+                Some(decision)
+            }
+            PhaseSwitching::Settling { until } if now >= until => {
+                self.phase_switching = PhaseSwitching::Idle;
+                None
+            }
+            PhaseSwitching::Pausing { until, .. } | PhaseSwitching::Settling { until } => {
+                Some(pause(
+                    String::from("Charging is paused to switch the phases"),
+                    until,
+                ))
+            }
+        }
+    }
+
+    /// Start switching to one phase if the available power suffices
+    /// for one phase but not for three phases, or back to three phases
+    /// once it suffices again. Vehicles that don't charge from PV only
+    /// always use three phases.
+    fn start_phase_switch(
+        &mut self,
+        rfid: &ConfigRfid,
+        available_power: i32,
+        now: u64,
+    ) -> Option<Decision> {
+        let phase_switch = self.settings.phase_switch.as_ref()?;
+        let phases = match self.phases() {
+            3 if rfid.pv_only
+                && available_power < self.minimum_charging_power(rfid, 3)
+                && available_power >= self.minimum_charging_power(rfid, 1) =>
+            {
+                1
+            }
+            1 if !rfid.pv_only
+                || available_power
+                    >= self.minimum_charging_power(rfid, 3) + self.settings.hysteresis_watts =>
+            {
+                3
+            }
+            _ => return None,
+        };
+        if let Some(last) = self
+            .last_phase_switch
+            .filter(|last| now < last + phase_switch.min_dwell)
+        {
+            debug!(
+                "Not switching to {} phases, the last switch was only {}s ago",
+                phases,
+                now - last
+            );
+            return None;
+        }
+        let until = now + phase_switch.pause;
+        self.phase_switching = PhaseSwitching::Pausing { phases, until };
+        Some(pause(
+            format!(
+                "{} Watts are available, pausing charging for {}s to switch to {} phases",
+                available_power, phase_switch.pause, phases
+            ),
+            until,
+        ))
+    }
+
+    fn switch_phases(&mut self, phases: u16, now: u64, decision: &mut Decision) {
+        self.phases = Some(phases);
+        self.last_phase_switch = Some(now);
+        decision.phases = Some(phases);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Departure, RfidMatch, TimeOfDay};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn settings() -> ControllerSettings {
+        ControllerSettings {
+            default_amps: 8,
+            initial_phase_duration: 180,
+            phase_voltage: 230,
+            phases: 3,
+            hysteresis_watts: 200,
+            phase_switch: None,
+            control_mode: ControlMode::Step,
+            battery: None,
+            max_grid_data_age: 30,
+            setpoint_interval: 0,
+            island_amps: 0,
+            max_pv_data_age: 60,
+            failsafe_amps: None,
+        }
+    }
+
+    fn rfid(pv_only: bool) -> ConfigRfid {
+        ConfigRfid {
+            name: String::from("Car"),
+            matching: RfidMatch::Exact,
+            pv_only,
+            min_amp: 6,
+            max_amp: 16,
+            max_charge: None,
+            minimum_charging_power: None,
+            priority: 0,
+            schedule: Vec::new(),
+            departure: Vec::new(),
+            price_policy: None,
+            min_charge_duration: None,
+            min_pause_duration: None,
+            max_cycles: None,
+        }
+    }
+
+    fn pv(pv_power: i32, house_power: i32) -> PvParams {
+        PvParams {
+            update: NOW,
+            pv_power,
+            house_power,
+            grid_power: 0,
+            battery_power: 0,
+            battery_soc: 50,
+            island: false,
+        }
+    }
+
+    /// A vehicle that has been charging for a while at the given current
+    fn charger(current_setpoint: u16) -> ChargerParams {
+        ChargerParams {
+            update: NOW,
+            connected: true,
+            session_energy: 5000,
+            session_duration: 3600,
+            user_id: Some(String::from("04aabbcc")),
+            current_setpoint,
+            power: current_setpoint as u32 * 690,
+            phase_current: None,
+        }
+    }
+
+    fn decide(
+        controller: &mut Controller,
+        pv: &PvParams,
+        charger: &ChargerParams,
+        rfid: Option<&ConfigRfid>,
+    ) -> Decision {
+        controller.decide(&Inputs {
+            pv,
+            charger,
+            grid: Some(&GridParams::from(pv)),
+            rfid,
+            session: &CurrSettings::default(),
+            now: NOW,
+            local_time: schedule::local_time(NOW),
+            tariff: None,
+            forecast: None,
+            budget: None,
+        })
+    }
+
+    /// A controller that already knows about the connected vehicle
+    fn connected_controller(rfid: &ConfigRfid) -> Controller {
+        let mut controller = Controller::new(settings());
+        decide(&mut controller, &pv(0, 0), &charger(8), Some(rfid));
+        controller
+    }
+
+    #[test]
+    fn no_vehicle_signals_default_amps() {
+        let mut controller = connected_controller(&rfid(false));
+        let charger = ChargerParams {
+            connected: false,
+            ..charger(0)
+        };
+        let decision = decide(&mut controller, &pv(0, 500), &charger, None);
+        assert_eq!(decision.amps, Some(8));
+        assert_eq!(
+            decision.session,
+            Some(SessionChange::Disconnected(String::from("Car")))
+        );
+        assert_eq!(decision.next_evaluation, NOW + EVALUATION_INTERVAL);
+    }
+
+    #[test]
+    fn unknown_tag_stops_charging() {
+        let mut controller = Controller::new(settings());
+        let decision = decide(&mut controller, &pv(8000, 500), &charger(8), None);
+        assert_eq!(decision.amps, Some(0));
+        assert!(decision.reason.contains("04AABBCC"));
+        assert_eq!(
+            decision.next_evaluation,
+            NOW + EVALUATION_INTERVAL + UNKNOWN_TAG_WAIT
+        );
+    }
+
+    #[test]
+    fn backup_mode_limits_charging() {
+        let rfid = rfid(false);
+        let mut controller = connected_controller(&rfid);
+        let island = PvParams {
+            island: true,
+            ..pv(8000, 500)
+        };
+        let decision = decide(&mut controller, &island, &charger(16), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+        assert!(decision.reason.contains("backup mode"));
+        assert_eq!(decision.next_evaluation, NOW + EVALUATION_INTERVAL);
+
+        let mut controller = Controller::new(ControllerSettings {
+            island_amps: 6,
+            ..settings()
+        });
+        let decision = decide(&mut controller, &island, &charger(16), Some(&rfid));
+        assert_eq!(decision.amps, Some(6));
+        // Normal control resumes once the grid is back
+        let decision = decide(&mut controller, &pv(8000, 500), &charger(6), Some(&rfid));
+        assert!(!decision.reason.contains("backup mode"));
+    }
+
+    #[test]
+    fn initial_phase_signals_default_amps() {
+        let rfid = rfid(true);
+        let mut controller = Controller::new(settings());
+        let charger = ChargerParams {
+            session_duration: 60,
+            ..charger(0)
+        };
+        let decision = decide(&mut controller, &pv(0, 500), &charger, Some(&rfid));
+        assert_eq!(decision.amps, Some(8));
+        assert_eq!(
+            decision.session,
+            Some(SessionChange::Connected {
+                name: String::from("Car"),
+                max_session_energy: None
+            })
+        );
+        assert_eq!(
+            decision.next_evaluation,
+            NOW + EVALUATION_INTERVAL + INITIAL_PHASE_WAIT
+        );
+    }
+
+    #[test]
+    fn energy_limit_stops_charging() {
+        let rfid = ConfigRfid {
+            max_charge: Some(4000),
+            ..rfid(false)
+        };
+        let mut controller = Controller::new(settings());
+        // The vehicle's limit applies as soon as it connects
+        let decision = decide(&mut controller, &pv(8000, 500), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+        // Later on, the session's limit applies, which may be changed
+        let decision = controller.decide(&Inputs {
+            pv: &pv(8000, 500),
+            charger: &charger(8),
+            grid: None,
+            rfid: Some(&rfid),
+            session: &CurrSettings {
+                max_session_energy: Some(6000),
+            },
+            now: NOW,
+            local_time: schedule::local_time(NOW),
+            tariff: None,
+            forecast: None,
+            budget: None,
+        });
+        assert_ne!(decision.amps, Some(0));
+    }
+
+    #[test]
+    fn pv_only_halts_without_surplus() {
+        let rfid = rfid(true);
+        let mut controller = connected_controller(&rfid);
+        // 6A * 690W = 4140W are needed; 5520W are charged, 2000W are consumed
+        let decision = decide(&mut controller, &pv(500, 7000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+        assert_eq!(decision.next_evaluation, NOW + PV_HALT_WAIT);
+    }
+
+    #[test]
+    fn departure_overrides_pv_only() {
+        // 5kWh charged, 25kWh to go at 11040W by an hour from now
+        let departure = schedule::local_time(NOW) + chrono::Duration::hours(1);
+        let rfid = ConfigRfid {
+            departure: vec![Departure {
+                days: Vec::new(),
+                time: TimeOfDay(departure.time()),
+                energy: 30000,
+            }],
+            ..rfid(true)
+        };
+        let mut controller = connected_controller(&rfid);
+        let decision = decide(&mut controller, &pv(500, 7000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(16));
+        let plan = controller.plan().unwrap();
+        assert!(!plan.pv_only);
+        assert_eq!(plan.remaining_energy, 25000);
+        assert!(plan.expected_completion.unwrap() > NOW + 3600);
+    }
+
+    #[test]
+    fn charging_proceeds_without_surplus_unless_pv_only() {
+        let rfid = rfid(false);
+        let mut controller = connected_controller(&rfid);
+        let decision = decide(&mut controller, &pv(500, 7000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(6));
+    }
+
+    #[test]
+    fn surplus_beyond_hysteresis_increases_current() {
+        let rfid = rfid(true);
+        let mut controller = connected_controller(&rfid);
+        // 8A = 5520W; one more Amp needs 690W plus 200W hysteresis
+        let decision = decide(&mut controller, &pv(7000, 6400), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, None);
+        let decision = decide(&mut controller, &pv(7000, 6000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(9));
+        // Never beyond max_amp
+        let decision = decide(&mut controller, &pv(20000, 0), &charger(16), Some(&rfid));
+        assert_eq!(decision.amps, None);
+    }
+
+    #[test]
+    fn deficit_reduces_current() {
+        let rfid = rfid(true);
+        let mut controller = connected_controller(&rfid);
+        // 10A = 6900W are charged, 5520W are available
+        let decision = decide(&mut controller, &pv(6000, 7380), &charger(10), Some(&rfid));
+        assert_eq!(decision.amps, Some(8));
+        let decision = decide(&mut controller, &pv(6000, 8000), &charger(10), Some(&rfid));
+        assert_eq!(decision.amps, Some(7));
+    }
+
+    fn phase_switch_settings() -> ControllerSettings {
+        ControllerSettings {
+            phase_switch: Some(PhaseSwitchSettings {
+                pause: 60,
+                min_dwell: 600,
+            }),
+            ..settings()
+        }
+    }
+
+    fn decide_at(
+        controller: &mut Controller,
+        now: u64,
+        pv: &PvParams,
+        charger: &ChargerParams,
+        rfid: &ConfigRfid,
+    ) -> Decision {
+        // The readings are always fresh
+        let pv = &PvParams {
+            update: now,
+            ..pv.clone()
+        };
+        let grid = GridParams {
+            update: now,
+            ..GridParams::from(pv)
+        };
+        controller.decide(&Inputs {
+            pv,
+            charger,
+            grid: Some(&grid),
+            rfid: Some(rfid),
+            session: &CurrSettings::default(),
+            now,
+            local_time: schedule::local_time(now),
+            tariff: None,
+            forecast: None,
+            budget: None,
+        })
+    }
+
+    #[test]
+    fn phases_are_established_after_startup() {
+        let rfid = rfid(true);
+        let mut controller = Controller::new(phase_switch_settings());
+        let decision = decide_at(&mut controller, NOW, &pv(9000, 6000), &charger(8), &rfid);
+        assert_eq!(decision.amps, Some(0));
+        assert_eq!(decision.phases, None);
+        assert_eq!(decision.next_evaluation, NOW + 60);
+        let decision = decide_at(
+            &mut controller,
+            NOW + 60,
+            &pv(9000, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.amps, Some(0));
+        assert_eq!(decision.phases, Some(3));
+        assert_eq!(decision.next_evaluation, NOW + 120);
+        let decision = decide_at(
+            &mut controller,
+            NOW + 120,
+            &pv(9000, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.amps, Some(6));
+    }
+
+    #[test]
+    fn low_surplus_switches_to_one_phase() {
+        let rfid = rfid(true);
+        let mut controller = Controller::new(phase_switch_settings());
+        controller.phases = Some(3);
+        // 2000W suffice for 8A on one phase, but not for 6A on three
+        let decision = decide_at(&mut controller, NOW, &pv(2500, 500), &charger(0), &rfid);
+        assert_eq!(decision.amps, Some(0));
+        assert_eq!(decision.phases, None);
+        let decision = decide_at(
+            &mut controller,
+            NOW + 60,
+            &pv(2500, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.phases, Some(1));
+        assert_eq!(controller.phases(), 1);
+        let decision = decide_at(
+            &mut controller,
+            NOW + 90,
+            &pv(2500, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.amps, Some(0));
+        let decision = decide_at(
+            &mut controller,
+            NOW + 120,
+            &pv(2500, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.amps, Some(6));
+
+        // Plenty of sun, but the minimum dwell time has not passed yet
+        let charging = ChargerParams {
+            power: 8 * 230,
+            ..charger(8)
+        };
+        let decision = decide_at(
+            &mut controller,
+            NOW + 300,
+            &pv(9000, 2340),
+            &charging,
+            &rfid,
+        );
+        assert_eq!(decision.phases, None);
+        assert_ne!(decision.amps, Some(0));
+        let decision = decide_at(
+            &mut controller,
+            NOW + 660,
+            &pv(9000, 2340),
+            &charging,
+            &rfid,
+        );
+        assert_eq!(decision.amps, Some(0));
+        let decision = decide_at(
+            &mut controller,
+            NOW + 720,
+            &pv(9000, 500),
+            &charger(0),
+            &rfid,
+        );
+        assert_eq!(decision.phases, Some(3));
+    }
+
+    #[test]
+    fn disconnecting_restores_the_configured_phases() {
+        let rfid = rfid(true);
+        let mut controller = Controller::new(phase_switch_settings());
+        controller.phases = Some(1);
+        let charger = ChargerParams {
+            connected: false,
+            ..charger(0)
+        };
+        let decision = decide_at(&mut controller, NOW, &pv(0, 500), &charger, &rfid);
+        assert_eq!(decision.amps, Some(8));
+        assert_eq!(decision.phases, Some(3));
+    }
+
+    #[test]
+    fn minimum_charging_power_scales_with_phases() {
+        let rfid = ConfigRfid {
+            minimum_charging_power: Some(3000),
+            ..rfid(true)
+        };
+        let controller = Controller::new(settings());
+        assert_eq!(controller.minimum_charging_power(&rfid, 3), 3000);
+        assert_eq!(controller.minimum_charging_power(&rfid, 1), 1000);
+        let rfid = ConfigRfid {
+            minimum_charging_power: None,
+            ..rfid
+        };
+        assert_eq!(controller.minimum_charging_power(&rfid, 1), 6 * 230);
+    }
+
+    fn pi_controller(rfid: &ConfigRfid) -> Controller {
+        let mut controller = Controller::new(ControllerSettings {
+            control_mode: ControlMode::PI {
+                kp: 0.5,
+                ki: 0.01,
+                export_offset: 100,
+            },
+            ..settings()
+        });
+        decide(&mut controller, &pv(0, 0), &charger(8), Some(rfid));
+        controller
+    }
+
+    fn grid(grid_power: i32) -> PvParams {
+        PvParams {
+            grid_power,
+            ..pv(9000, 500)
+        }
+    }
+
+    #[test]
+    fn pi_controller_follows_grid_power() {
+        let rfid = rfid(true);
+        let mut controller = pi_controller(&rfid);
+        // The integral grows by 0.01/s * 1900W * 20s = 380W to 5900W,
+        // plus 0.5 * 1900W = 6850W
+        let decision = decide_at(&mut controller, NOW + 20, &grid(-2000), &charger(8), &rfid);
+        assert_eq!(decision.amps, Some(10));
+        // At the target, only the integral of 5900W remains
+        let decision = decide_at(&mut controller, NOW + 40, &grid(-100), &charger(10), &rfid);
+        assert_eq!(decision.amps, Some(9));
+        // 5900W - 320W - 800W = 4780W
+        let decision = decide_at(&mut controller, NOW + 60, &grid(1500), &charger(9), &rfid);
+        assert_eq!(decision.amps, Some(7));
+    }
+
+    #[test]
+    fn pi_controller_does_not_wind_up() {
+        let rfid = rfid(true);
+        let mut controller = pi_controller(&rfid);
+        for i in 1..=20 {
+            let now = NOW + i * 20;
+            let decision = decide_at(&mut controller, now, &grid(-5000), &charger(16), &rfid);
+            assert_eq!(decision.amps, None);
+        }
+        // The integral stays at 16A * 690W, so importing reduces at once
+        let decision = decide_at(&mut controller, NOW + 420, &grid(1000), &charger(16), &rfid);
+        assert_eq!(decision.amps, Some(15));
+    }
+
+    fn battery_controller(rfid: &ConfigRfid, control_mode: ControlMode) -> Controller {
+        let mut controller = Controller::new(ControllerSettings {
+            battery: Some(BatterySettings {
+                priority_soc: 80,
+                discharge_soc: Some(90),
+                max_discharge_power: 2000,
+                floor_soc: 30,
+            }),
+            control_mode,
+            ..settings()
+        });
+        decide(&mut controller, &pv(0, 0), &charger(8), Some(rfid));
+        controller
+    }
+
+    fn battery(pv_power: i32, house_power: i32, battery_power: i32, battery_soc: u16) -> PvParams {
+        PvParams {
+            battery_power,
+            battery_soc,
+            ..pv(pv_power, house_power)
+        }
+    }
+
+    #[test]
+    fn battery_charges_first_up_to_priority_soc() {
+        let rfid = rfid(true);
+        let mut controller = battery_controller(&rfid, ControlMode::Step);
+        // 8500W surplus, of which the battery takes 3000W
+        let pv = battery(9000, 6020, 3000, 50);
+        let decision = decide(&mut controller, &pv, &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(7));
+        let pv = battery(9000, 6020, 3000, 80);
+        let decision = decide(&mut controller, &pv, &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(9));
+    }
+
+    #[test]
+    fn battery_discharges_into_the_vehicle_down_to_floor_soc() {
+        let rfid = rfid(true);
+        let mut controller = battery_controller(&rfid, ControlMode::Step);
+        // 2500W surplus; 6A need 4140W, the battery covers the rest
+        let pv_at = |soc| battery(3000, 4640, -1640, soc);
+        let decision = decide(&mut controller, &pv_at(85), &charger(6), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+        let decision = decide(&mut controller, &pv_at(90), &charger(6), Some(&rfid));
+        assert_eq!(decision.amps, None);
+        let decision = decide(&mut controller, &pv_at(31), &charger(6), Some(&rfid));
+        assert_eq!(decision.amps, None);
+        let decision = decide(&mut controller, &pv_at(30), &charger(6), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+        let decision = decide(&mut controller, &pv_at(31), &charger(6), Some(&rfid));
+        assert_eq!(decision.amps, Some(0));
+    }
+
+    #[test]
+    fn step_mode_counts_battery_discharge_once() {
+        let rfid = rfid(true);
+        let mut controller = battery_controller(&rfid, ControlMode::Step);
+        // The vehicle draws 5520W at 8A, the battery covers the deficit
+        // of 1020W, which leaves 4500W
+        let pv_at = |soc| battery(5000, 6020, -1020, soc);
+        let decision = decide(&mut controller, &pv_at(85), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(6));
+        // Plus the 2000W the vehicle may take from the battery
+        let decision = decide(&mut controller, &pv_at(95), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(9));
+    }
+
+    #[test]
+    fn pi_controller_takes_battery_charging_above_priority_soc() {
+        let rfid = rfid(true);
+        let control_mode = ControlMode::PI {
+            kp: 0.5,
+            ki: 0.0,
+            export_offset: 0,
+        };
+        let mut controller = battery_controller(&rfid, control_mode);
+        let pv_at = |soc| PvParams {
+            grid_power: 0,
+            ..battery(9000, 6020, 2000, soc)
+        };
+        let decision = decide(&mut controller, &pv_at(50), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, None);
+        // 5520W + 0.5 * 2000W
+        let decision = decide(&mut controller, &pv_at(80), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(9));
+    }
+
+    #[test]
+    fn grid_mode_regulates_on_grid_power() {
+        let rfid = rfid(true);
+        let mut controller = Controller::new(ControllerSettings {
+            control_mode: ControlMode::Grid {
+                target_grid_power: -100,
+            },
+            ..settings()
+        });
+        decide(&mut controller, &pv(0, 0), &charger(8), Some(&rfid));
+        // The PV system claims a large surplus, but the grid reading
+        // shows that the power is consumed elsewhere
+        let decision = decide(&mut controller, &grid(400), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(7));
+        let decision = decide(&mut controller, &grid(-1000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(9));
+    }
+
+    #[test]
+    fn step_mode_uses_grid_meter() {
+        let rfid = rfid(true);
+        let mut controller = connected_controller(&rfid);
+        // The PV system claims a surplus of 7500W, but the meter shows
+        // an import of 400W while charging at 8A
+        let meter = GridParams {
+            update: NOW,
+            power: 400,
+            phase_power: Some([2000, -800, -800]),
+            phase_current: Some([8.7, 3.5, 3.5]),
+        };
+        let decision = controller.decide(&Inputs {
+            pv: &pv(8000, 500),
+            charger: &charger(8),
+            grid: Some(&meter),
+            rfid: Some(&rfid),
+            session: &CurrSettings::default(),
+            now: NOW,
+            local_time: schedule::local_time(NOW),
+            tariff: None,
+            forecast: None,
+            budget: None,
+        });
+        assert_eq!(decision.amps, Some(7));
+    }
+
+    #[test]
+    fn stale_grid_readings_are_not_acted_on() {
+        let rfid = rfid(true);
+        let mut controller = pi_controller(&rfid);
+        let stale = GridParams {
+            update: NOW - 31,
+            ..GridParams::from(&grid(3000))
+        };
+        let decision = controller.decide(&Inputs {
+            pv: &grid(3000),
+            charger: &charger(8),
+            grid: Some(&stale),
+            rfid: Some(&rfid),
+            session: &CurrSettings::default(),
+            now: NOW,
+            local_time: schedule::local_time(NOW),
+            tariff: None,
+            forecast: None,
+            budget: None,
+        });
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("31 seconds old"));
+    }
+
+    #[test]
+    fn stale_pv_readings_fall_back_to_failsafe_current() {
+        let stale = PvParams {
+            update: NOW - 61,
+            ..pv(8000, 500)
+        };
+        // PV only charging stops, other vehicles get the default current
+        let pv_only = rfid(true);
+        let mut controller = connected_controller(&pv_only);
+        let decision = decide(&mut controller, &stale, &charger(16), Some(&pv_only));
+        assert_eq!(decision.amps, Some(0));
+        assert!(decision.reason.contains("61 seconds old"));
+        let rfid = rfid(false);
+        let mut controller = connected_controller(&rfid);
+        let decision = decide(&mut controller, &stale, &charger(16), Some(&rfid));
+        assert_eq!(decision.amps, Some(8));
+
+        let mut controller = Controller::new(ControllerSettings {
+            failsafe_amps: Some(6),
+            ..settings()
+        });
+        decide(&mut controller, &pv(0, 0), &charger(8), Some(&rfid));
+        let decision = decide(&mut controller, &stale, &charger(16), Some(&rfid));
+        assert_eq!(decision.amps, Some(6));
+    }
+
+    #[test]
+    fn starts_and_stops_are_held_back() {
+        let rfid = ConfigRfid {
+            min_charge_duration: Some(600),
+            min_pause_duration: Some(300),
+            max_cycles: Some(2),
+            ..rfid(true)
+        };
+        let mut controller = connected_controller(&rfid);
+        let (sunny, cloudy) = (pv(10000, 500), pv(500, 7000));
+        let mut decide_at =
+            |now, pv, amps| decide_at(&mut controller, NOW + now, pv, &charger(amps), &rfid);
+        // Stops charging for lack of PV power
+        assert_eq!(decide_at(0, &cloudy, 8).amps, Some(0));
+
+        let decision = decide_at(100, &sunny, 0);
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("paused for 100s"));
+        assert_eq!(decide_at(300, &sunny, 0).amps, Some(6));
+
+        let decision = decide_at(400, &cloudy, 6);
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("charged for 100s"));
+        assert_eq!(decide_at(900, &cloudy, 6).amps, Some(0));
+        assert_eq!(decide_at(1200, &sunny, 0).amps, Some(6));
+        assert_eq!(decide_at(1800, &cloudy, 6).amps, Some(0));
+
+        let decision = decide_at(2400, &sunny, 0);
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("started 2 times"));
+    }
+
+    #[test]
+    fn failsafe_stops_count_as_stops() {
+        let rfid = ConfigRfid {
+            min_pause_duration: Some(300),
+            ..rfid(true)
+        };
+        let stale = PvParams {
+            update: NOW - 61,
+            ..pv(10000, 500)
+        };
+        let mut controller = Controller::new(settings());
+        assert_eq!(
+            decide(&mut controller, &stale, &charger(8), Some(&rfid)).amps,
+            Some(0)
+        );
+        let sunny = pv(10000, 500);
+        let decision = decide_at(&mut controller, NOW + 100, &sunny, &charger(0), &rfid);
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("paused for 100s"));
+        let decision = decide_at(&mut controller, NOW + 300, &sunny, &charger(0), &rfid);
+        assert_eq!(decision.amps, Some(6));
+    }
+
+    #[test]
+    fn setpoint_changes_are_rate_limited() {
+        let rfid = rfid(false);
+        let mut controller = Controller::new(ControllerSettings {
+            setpoint_interval: 60,
+            ..settings()
+        });
+        // Reduces the current to the minimum
+        let decision = decide(&mut controller, &pv(500, 7000), &charger(8), Some(&rfid));
+        assert_eq!(decision.amps, Some(6));
+        let sunny = pv(10000, 500);
+        let decision = decide_at(&mut controller, NOW + 20, &sunny, &charger(6), &rfid);
+        assert_eq!(decision.amps, None);
+        assert!(decision.reason.contains("changed 20s ago"));
+        let decision = decide_at(&mut controller, NOW + 60, &sunny, &charger(6), &rfid);
+        assert_eq!(decision.amps, Some(7));
+    }
+}
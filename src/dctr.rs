@@ -1,25 +1,15 @@
+use crate::poller::{epoch_secs, read_holding_registers, Connector, Poller};
+use crate::register_map::RegisterMap;
 use byteorder::ReadBytesExt;
-use modbus::*;
+use modbus::Client;
 use std::io::{ErrorKind, Read, Result};
-use std::ops::Add;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread::spawn;
-
-const WAIT_AFTER_ERROR: u64 = 8;
+use std::sync::Arc;
 
+/// The poller is shared between all clones and stopped once the last
+/// clone is dropped
+#[derive(Clone)]
 pub struct Dctr {
-    do_run: Arc<AtomicBool>,
-    params: Arc<Mutex<Option<DctrParams>>>,
-}
-
-impl Clone for Dctr {
-    fn clone(&self) -> Self {
-        Dctr {
-            do_run: self.do_run.clone(),
-            params: self.params.clone(),
-        }
-    }
+    poller: Arc<Poller<DctrParams>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -168,104 +158,65 @@ fn read_currents<R: Read>(slice: &mut R) -> Result<Currents> {
     })
 }
 
-impl Dctr {
-    pub fn new(host_name: &str, port: u16, polling_interval: std::time::Duration) -> Result<Dctr> {
-        let host_name = String::from(host_name);
-        let do_run = Arc::new(AtomicBool::new(true));
-        let do_run_clone = do_run.clone();
-        let params = Arc::new(Mutex::new(None));
-        let params_clone = params.clone();
-        spawn(move || {
-            while do_run_clone.load(Ordering::Relaxed) {
-                let mut cfg = tcp::Config::default();
-                cfg.tcp_port = port;
-                match tcp::Transport::new_with_cfg(&host_name, cfg) {
-                    Ok(mut client) => {
-                        while do_run_clone.load(Ordering::Relaxed) {
-                            let mut fetch = || {
-                                let registers =
-                                    client.read_holding_registers(0, 36).map_err(|e| {
-                                        std::io::Error::new(std::io::ErrorKind::Other, e)
-                                    })?;
-                                let buf = binary::unpack_bytes(&registers);
-                                let mut slice = buf.as_slice();
-
-                                let validity = slice.read_u16::<byteorder::BE>()?;
-                                let f_i = read_currents(&mut slice)?;
-                                let alarm_a = slice.read_u16::<byteorder::BE>()?;
-                                let alarm_b = slice.read_u16::<byteorder::BE>()?;
-                                {
-                                    let mut skip_buf = vec![0u8; 10];
-                                    slice.read_exact(&mut skip_buf)?;
-                                }
-                                let thresholds_a = read_currents(&mut slice)?;
-                                let active_alarms_a = slice.read_u16::<byteorder::BE>()?;
-
-                                let thresholds_b = read_currents(&mut slice)?;
-                                let active_alarms_b = slice.read_u16::<byteorder::BE>()?;
-
-                                let alarm_delay = slice.read_u16::<byteorder::BE>()?;
-
-                                let update = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .map(|r| r.as_secs())
-                                    .map_err(|e| {
-                                        std::io::Error::new(std::io::ErrorKind::Other, e)
-                                    })?;
-                                Ok::<_, std::io::Error>(DctrParams {
-                                    update,
-                                    validity,
-                                    f_i,
-                                    raised_alarm_a: AlarmBitField::from_int(alarm_a),
-                                    raised_alarm_b: AlarmBitField::from_int(alarm_b),
-                                    thresholds_a,
-                                    activated_alarms_a: AlarmBitField::from_int(active_alarms_a),
-                                    thresholds_b,
-                                    activated_alarms_b: AlarmBitField::from_int(active_alarms_b),
-                                    alarm_delay,
-                                })
-                            };
-                            match fetch() {
-                                Ok(dctrparams) => {
-                                    if let Ok(mut p) = params_clone.lock() {
-                                        *p = Some(dctrparams);
-                                    } else {
-                                        eprintln!(
-                                            "Cannot update params, cannot acquire mutex lock"
-                                        );
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Error while reading from the RCM system: {:?}", e);
-                                    break;
-                                }
-                            }
-
-                            let wait_until = std::time::SystemTime::now().add(polling_interval);
-                            while std::time::SystemTime::now() < wait_until {
-                                std::thread::sleep(std::time::Duration::from_secs(1));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error while connecting to RCM system: {:?}", e);
-                    }
-                }
-                std::thread::sleep(std::time::Duration::from_secs(WAIT_AFTER_ERROR));
-            }
-            ()
-        });
+/// Decode the DCTR's measurement and configuration registers
+pub(crate) fn decode(client: &mut dyn Client) -> Result<DctrParams> {
+    let buf = read_holding_registers(client, 0, 36)?;
+    let mut slice = buf.as_slice();
+
+    let validity = slice.read_u16::<byteorder::BE>()?;
+    let f_i = read_currents(&mut slice)?;
+    let alarm_a = slice.read_u16::<byteorder::BE>()?;
+    let alarm_b = slice.read_u16::<byteorder::BE>()?;
+    {
+        let mut skip_buf = vec![0u8; 10];
+        slice.read_exact(&mut skip_buf)?;
+    }
+    let thresholds_a = read_currents(&mut slice)?;
+    let active_alarms_a = slice.read_u16::<byteorder::BE>()?;
+
+    let thresholds_b = read_currents(&mut slice)?;
+    let active_alarms_b = slice.read_u16::<byteorder::BE>()?;
+
+    let alarm_delay = slice.read_u16::<byteorder::BE>()?;
+
+    Ok(DctrParams {
+        update: epoch_secs()?,
+        validity,
+        f_i,
+        raised_alarm_a: AlarmBitField::from_int(alarm_a),
+        raised_alarm_b: AlarmBitField::from_int(alarm_b),
+        thresholds_a,
+        activated_alarms_a: AlarmBitField::from_int(active_alarms_a),
+        thresholds_b,
+        activated_alarms_b: AlarmBitField::from_int(active_alarms_b),
+        alarm_delay,
+    })
+}
 
-        Ok(Dctr { do_run, params })
+impl Dctr {
+    /// Start polling the device. Unless a register map is given, the
+    /// built-in register layout is used.
+    pub fn new<C: Connector + 'static>(
+        connector: C,
+        polling_interval: std::time::Duration,
+        register_map: Option<RegisterMap>,
+    ) -> Result<Dctr> {
+        let poller = match register_map {
+            Some(map) => Poller::new(
+                "RCM system",
+                connector,
+                polling_interval,
+                move |c: &mut dyn Client| map.read_as(c),
+            ),
+            None => Poller::new("RCM system", connector, polling_interval, decode),
+        };
+        Ok(Dctr {
+            poller: Arc::new(poller),
+        })
     }
 
     pub fn get_current_params(&self) -> Option<DctrParams> {
-        if let Ok(l) = self.params.lock() {
-            l.clone()
-        } else {
-            eprintln!("Unable to acquire mutex lock when fetching params!");
-            None
-        }
+        self.poller.get_current_params()
     }
 
     #[allow(unused)]
@@ -274,9 +225,3 @@ impl Dctr {
         Err(std::io::Error::new(ErrorKind::Other, "Not yet implemented"))
     }
 }
-
-impl Drop for Dctr {
-    fn drop(&mut self) {
-        self.do_run.store(false, Ordering::Relaxed);
-    }
-}
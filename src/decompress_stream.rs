@@ -1,9 +1,9 @@
 extern crate rusqlite;
 
-use crate::pac2200::Pac2200Params;
 use crate::*;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Result, Write};
+use wallbox::pac2200::Pac2200Params;
 
 pub fn decompress_stream(dsp: DecompressStreamParams) -> Result<()> {
     if let Some(output_to_sqlite) = dsp.output_to_sqlite.as_ref() {
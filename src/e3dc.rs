@@ -1,20 +1,14 @@
+use crate::poller::{epoch_secs, read_holding_registers, Connector, Poller};
+use crate::register_map::RegisterMap;
 use byteorder::ReadBytesExt;
-use modbus::*;
-use std::io::{Read, Result};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread::{spawn, JoinHandle};
-
-const WAIT_AFTER_ERROR: u64 = 8;
+use modbus::Client;
+use std::io::{Error, Read, Result};
 
 pub struct E3DC {
-    #[allow(unused)]
-    handler: JoinHandle<()>,
-    do_run: Arc<AtomicBool>,
-    params: Arc<Mutex<Option<E3DCParams>>>,
+    poller: Poller<E3DCParams>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct E3DCParams {
     pub update: u64,
     pub magic: u16,
@@ -50,154 +44,101 @@ fn read_big_little_i32<R: Read>(mut inbuf: R) -> Result<i32> {
     (&buf[..]).read_i32::<byteorder::BE>()
 }
 
-impl E3DC {
-    pub fn new(host_name: &str, port: u16, polling_interval: std::time::Duration) -> Result<E3DC> {
-        let host_name = String::from(host_name);
-        let do_run = Arc::new(AtomicBool::new(true));
-        let do_run_clone = do_run.clone();
-        let params = Arc::new(Mutex::new(None));
-        let params_clone = params.clone();
-        let handler = spawn(move || {
-            while do_run_clone.load(Ordering::Relaxed) {
-                let mut cfg = tcp::Config::default();
-                cfg.tcp_port = port;
-                match tcp::Transport::new_with_cfg(&host_name, cfg) {
-                    Ok(mut client) => {
-                        while do_run_clone.load(Ordering::Relaxed) {
-                            let registers = client.read_holding_registers(40000, 126);
-                            if registers.is_err() {
-                                eprintln!(
-                                    "Error while reading from e3dc system: {:?}",
-                                    registers.err()
-                                );
-                                break;
-                            } else {
-                                let registers = registers.unwrap();
-                                let fetch = || {
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-
-                                    let magic = slice.read_u16::<byteorder::BE>()?;
-                                    let v1 = slice.read_u8()?;
-                                    let v2 = slice.read_u8()?;
-                                    let v3 = slice.read_u16::<byteorder::BE>()?;
-                                    let mut version_strings = Vec::new();
-                                    let mut str_buf = [0; 32];
-                                    for _i in 0..4 {
-                                        slice.read_exact(&mut str_buf)?;
-                                        let version_string = String::from_utf8(str_buf.to_vec())
-                                            .map_err(|e| {
-                                                std::io::Error::new(std::io::ErrorKind::Other, e)
-                                            })?;
-                                        version_strings.push(version_string);
-                                    }
+/// Decode the E3DC's simple mode registers
+pub(crate) fn decode(client: &mut dyn Client) -> Result<E3DCParams> {
+    let buf = read_holding_registers(client, 40000, 126)?;
+    let mut slice = buf.as_slice();
 
-                                    let pv_power = read_big_little_i32(&mut slice)?;
-                                    let batt_power = read_big_little_i32(&mut slice)?;
-                                    let haus_power = read_big_little_i32(&mut slice)?;
-                                    let netz_power = read_big_little_i32(&mut slice)?;
-                                    let misc_1 = read_big_little_i32(&mut slice)?;
-                                    let misc_2 = read_big_little_i32(&mut slice)?;
-                                    let misc_3 = read_big_little_i32(&mut slice)?;
-                                    let autarky = slice.read_u8()?;
-                                    let self_utilisation = slice.read_u8()?;
-                                    let akku_charge_percentage =
-                                        slice.read_u16::<byteorder::BE>()?;
-                                    let emergency_power = slice.read_u16::<byteorder::BE>()?;
-                                    // skip 22 bytes
-                                    for _i in 0..11 {
-                                        slice.read_i16::<byteorder::BE>()?;
-                                    }
-                                    let s1v = slice.read_u16::<byteorder::BE>()?;
-                                    let s2v = slice.read_u16::<byteorder::BE>()?;
-                                    let _s3v = slice.read_u16::<byteorder::BE>()?;
-                                    let s1a = slice.read_u16::<byteorder::BE>()?;
-                                    let s2a = slice.read_u16::<byteorder::BE>()?;
-                                    let _s3a = slice.read_u16::<byteorder::BE>()?;
-                                    let s1p = slice.read_u16::<byteorder::BE>()?;
-                                    let s2p = slice.read_u16::<byteorder::BE>()?;
-                                    let _s3p = slice.read_u16::<byteorder::BE>()?;
+    let magic = slice.read_u16::<byteorder::BE>()?;
+    let v1 = slice.read_u8()?;
+    let v2 = slice.read_u8()?;
+    let v3 = slice.read_u16::<byteorder::BE>()?;
+    let mut version_strings = Vec::new();
+    let mut str_buf = [0; 32];
+    for _i in 0..4 {
+        slice.read_exact(&mut str_buf)?;
+        let version_string = String::from_utf8(str_buf.to_vec()).map_err(Error::other)?;
+        version_strings.push(version_string);
+    }
 
-                                    let update = std::time::SystemTime::now()
-                                        .duration_since(std::time::UNIX_EPOCH)
-                                        .map(|r| r.as_secs())
-                                        .map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    Ok::<_, std::io::Error>(E3DCParams {
-                                        update,
-                                        magic,
-                                        v1,
-                                        v2,
-                                        v3,
-                                        version_strings,
-                                        pv_power,
-                                        batt_power,
-                                        haus_power,
-                                        netz_power,
-                                        misc_1,
-                                        misc_2,
-                                        misc_3,
-                                        autarky,
-                                        self_utilisation,
-                                        akku_charge_percentage,
-                                        emergency_power,
-                                        s1v,
-                                        s2v,
-                                        s1a,
-                                        s2a,
-                                        s1p,
-                                        s2p,
-                                    })
-                                };
-                                match fetch() {
-                                    Ok(e3dcparams) => {
-                                        if let Ok(mut p) = params_clone.lock() {
-                                            *p = Some(e3dcparams);
-                                        } else {
-                                            eprintln!(
-                                                "Cannot update params, cannot acquire mutex lock"
-                                            );
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Error while reading from e3dc system: {:?}", e);
-                                        break;
-                                    }
-                                }
-                            }
+    let pv_power = read_big_little_i32(&mut slice)?;
+    let batt_power = read_big_little_i32(&mut slice)?;
+    let haus_power = read_big_little_i32(&mut slice)?;
+    let netz_power = read_big_little_i32(&mut slice)?;
+    let misc_1 = read_big_little_i32(&mut slice)?;
+    let misc_2 = read_big_little_i32(&mut slice)?;
+    let misc_3 = read_big_little_i32(&mut slice)?;
+    let autarky = slice.read_u8()?;
+    let self_utilisation = slice.read_u8()?;
+    let akku_charge_percentage = slice.read_u16::<byteorder::BE>()?;
+    let emergency_power = slice.read_u16::<byteorder::BE>()?;
+    // skip 22 bytes
+    for _i in 0..11 {
+        slice.read_i16::<byteorder::BE>()?;
+    }
+    let s1v = slice.read_u16::<byteorder::BE>()?;
+    let s2v = slice.read_u16::<byteorder::BE>()?;
+    let _s3v = slice.read_u16::<byteorder::BE>()?;
+    let s1a = slice.read_u16::<byteorder::BE>()?;
+    let s2a = slice.read_u16::<byteorder::BE>()?;
+    let _s3a = slice.read_u16::<byteorder::BE>()?;
+    let s1p = slice.read_u16::<byteorder::BE>()?;
+    let s2p = slice.read_u16::<byteorder::BE>()?;
+    let _s3p = slice.read_u16::<byteorder::BE>()?;
 
-                            std::thread::sleep(polling_interval);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error while connecting to e3dc system: {:?}", e);
-                    }
-                }
-                std::thread::sleep(std::time::Duration::from_secs(WAIT_AFTER_ERROR));
-            }
-            ()
-        });
+    Ok(E3DCParams {
+        update: epoch_secs()?,
+        magic,
+        v1,
+        v2,
+        v3,
+        version_strings,
+        pv_power,
+        batt_power,
+        haus_power,
+        netz_power,
+        misc_1,
+        misc_2,
+        misc_3,
+        autarky,
+        self_utilisation,
+        akku_charge_percentage,
+        emergency_power,
+        s1v,
+        s2v,
+        s1a,
+        s2a,
+        s1p,
+        s2p,
+    })
+}
 
-        Ok(E3DC {
-            handler,
-            do_run,
-            params,
-        })
+impl E3DC {
+    /// Start polling the device. Unless a register map is given, the
+    /// built-in register layout is used.
+    pub fn new<C: Connector + 'static>(
+        connector: C,
+        polling_interval: std::time::Duration,
+        register_map: Option<RegisterMap>,
+    ) -> Result<E3DC> {
+        let poller = match register_map {
+            Some(map) => Poller::new(
+                "e3dc system",
+                connector,
+                polling_interval,
+                move |c: &mut dyn Client| map.read_as(c),
+            ),
+            None => Poller::new("e3dc system", connector, polling_interval, decode),
+        };
+        Ok(E3DC { poller })
     }
 
     pub fn get_current_params(&self) -> Option<E3DCParams> {
-        if let Ok(l) = self.params.lock() {
-            l.clone()
-        } else {
-            eprintln!("Unable to acquire mutex lock when fetching params!");
-            None
-        }
+        self.poller.get_current_params()
     }
-}
 
-impl Drop for E3DC {
-    fn drop(&mut self) {
-        self.do_run.store(false, Ordering::Relaxed);
+    /// Access the poller, e.g. for staleness checks and statistics
+    pub fn poller(&self) -> &Poller<E3DCParams> {
+        &self.poller
     }
 }
@@ -96,12 +96,19 @@ pub fn energy_meter(emp: EnergyMeterParams) -> Result<()> {
             format!("A flushing interval smaller than ten seconds is too low"),
         ));
     }
+    wallbox::init_stderr_logging()?;
+
     let polling_interval = emp.polling_interval.unwrap_or(1000);
     let polling_interval = Duration::from_millis(polling_interval);
+    let connection = emp.serial.connection(
+        emp.meter_host.clone(),
+        emp.meter_port,
+        emp.register_map.clone(),
+    );
     let pac2200 = Pac2200::new(
-        &emp.meter_host,
-        emp.meter_port.unwrap_or(502),
+        connection.connector()?,
         polling_interval,
+        connection.load_register_map()?,
     )?;
 
     let bind_to = emp.bind_to.unwrap_or(String::from("localhost:1723"));
@@ -0,0 +1,85 @@
+use crate::config::ForecastConfig;
+use crate::time_series::TimeSeries;
+use std::io::Result;
+use std::path::Path;
+
+/// A forecast of the PV production in Watts, see TimeSeries for the
+/// file format. The key of the production in JSON files is "power".
+#[derive(Debug, Clone)]
+pub struct Forecast {
+    production: TimeSeries,
+    house_power: Option<i32>,
+}
+
+impl From<&ForecastConfig> for Forecast {
+    fn from(config: &ForecastConfig) -> Self {
+        Forecast {
+            production: TimeSeries::new(&config.file, "power"),
+            house_power: config.house_power,
+        }
+    }
+}
+
+impl Forecast {
+    pub fn path(&self) -> &Path {
+        self.production.path()
+    }
+
+    /// The number of known forecast values
+    pub fn len(&self) -> usize {
+        self.production.slots().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.production.slots().is_empty()
+    }
+
+    /// The configured estimate of the house consumption in Watts
+    pub fn house_power(&self) -> Option<i32> {
+        self.house_power
+    }
+
+    /// Reload the forecast if the file was modified, see TimeSeries
+    pub fn refresh(&mut self) -> Result<bool> {
+        self.production.refresh()
+    }
+
+    /// The energy in Watthours the production is expected to exceed the
+    /// given house consumption by between the given times. Times the
+    /// forecast doesn't cover count as no production; if it covers none
+    /// of them, there is no expectation.
+    pub fn surplus_energy(&self, from: u64, until: u64, house_power: i32) -> Option<f64> {
+        let mut covered = false;
+        let mut energy = 0.0;
+        for slot in self.production.slots() {
+            let (start, end) = (slot.start.max(from), slot.end.min(until));
+            if start < end {
+                covered = true;
+                let surplus = (slot.value - house_power as f64).max(0.0);
+                energy += surplus * (end - start) as f64 / 3600.0;
+            }
+        }
+        covered.then_some(energy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time_series;
+
+    #[test]
+    fn surplus_energy_is_integrated() {
+        let forecast = Forecast {
+            production: time_series::from_values(&[(0, 0.0), (3600, 3000.0), (7200, 5000.0)]),
+            house_power: None,
+        };
+        assert_eq!(forecast.surplus_energy(10800, 14400, 500), None);
+        assert_eq!(forecast.surplus_energy(0, 3600, 500), Some(0.0));
+        // Half of the second and all of the third hour
+        assert_eq!(
+            forecast.surplus_energy(5400, 14400, 1000),
+            Some(1000.0 + 4000.0)
+        );
+    }
+}
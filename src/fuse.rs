@@ -0,0 +1,198 @@
+use crate::charger::{reduced_current, Settling, VehicleLoad, MIN_CHARGING_CURRENT};
+use crate::config::{FuseConfig, SharingStrategy};
+use crate::sharing::{self, Demand};
+
+/// Amps to stay below the limit of the house connection by default
+const DEFAULT_FUSE_MARGIN: f32 = 2.0;
+/// Seconds to keep the charging current reduced by default
+const DEFAULT_FUSE_HOLD: u64 = 300;
+
+/// Guards the main fuse of the house connection. It is checked far
+/// more often than the controller decides and caps the charging current
+/// for a while once a phase comes close to the limit. The reduction is
+/// shared among the vehicles drawing on that phase like the surplus.
+#[derive(Debug, Clone)]
+pub struct FuseGuard {
+    max_phase_current: f32,
+    margin: f32,
+    hold: u64,
+    strategy: SharingStrategy,
+    /// The capped current of each charger and until when it applies
+    caps: Vec<Option<(u16, u64)>>,
+    settling: Settling,
+}
+
+impl FuseGuard {
+    pub fn new(fuse: &FuseConfig, strategy: SharingStrategy) -> FuseGuard {
+        FuseGuard {
+            max_phase_current: fuse.max_phase_current,
+            margin: fuse.margin.unwrap_or(DEFAULT_FUSE_MARGIN),
+            hold: fuse.hold.unwrap_or(DEFAULT_FUSE_HOLD),
+            strategy,
+            caps: Vec::new(),
+            settling: Settling::default(),
+        }
+    }
+
+    /// Check the currents of the house connection and return the
+    /// charging current to reduce each charger's setpoint to, if it is
+    /// too high. Vehicles whose own currents are unknown are assumed to
+    /// draw on all phases.
+    pub fn check(
+        &mut self,
+        currents: [f32; 3],
+        loads: &[VehicleLoad],
+        now: u64,
+    ) -> Vec<Option<u16>> {
+        let mut reductions = vec![None; loads.len()];
+        self.caps.resize(loads.len(), None);
+        if !self.settling.settled(now) {
+            return reductions;
+        }
+        let threshold = self.max_phase_current - self.margin;
+        let worst = (0..3)
+            .filter(|&phase| loads.iter().any(|load| load.draws_on(phase)))
+            .map(|phase| (phase, currents[phase] - threshold))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((phase, excess)) = worst.filter(|(_, excess)| *excess > 0.0) else {
+            return reductions;
+        };
+
+        let affected: Vec<usize> = (0..loads.len())
+            .filter(|&i| loads[i].draws_on(phase))
+            .collect();
+        let setpoints: u16 = affected.iter().map(|&i| loads[i].setpoint).sum();
+        let allowed = (setpoints as f32 - excess).floor() as i32;
+        let demands: Vec<Demand> = affected
+            .iter()
+            .map(|&i| Demand {
+                min: MIN_CHARGING_CURRENT as i32,
+                max: loads[i].setpoint as i32,
+                priority: loads[i].priority,
+                since: loads[i].since,
+            })
+            .collect();
+        let shares = sharing::share(self.strategy, allowed, &demands);
+        for (&i, share) in affected.iter().zip(shares) {
+            let amps = reduced_current(share);
+            self.caps[i] = Some((amps, now + self.hold));
+            reductions[i] = (amps < loads[i].setpoint).then_some(amps);
+        }
+        self.settling.reduced(now);
+        reductions
+    }
+
+    /// The highest charging current allowed for the given charger at the
+    /// moment, if capped
+    pub fn cap(&self, charger: usize, now: u64) -> Option<u16> {
+        self.caps
+            .get(charger)
+            .copied()
+            .flatten()
+            .filter(|(_, until)| now < *until)
+            .map(|(amps, _)| amps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn guard() -> FuseGuard {
+        let fuse = FuseConfig {
+            max_phase_current: 35.0,
+            margin: Some(2.0),
+            hold: Some(60),
+        };
+        FuseGuard::new(&fuse, SharingStrategy::Equal)
+    }
+
+    fn load(currents: Option<[f32; 3]>, setpoint: u16, since: u64) -> VehicleLoad {
+        VehicleLoad {
+            currents,
+            phases: 3,
+            setpoint,
+            priority: 0,
+            since,
+        }
+    }
+
+    /// Check a single vehicle
+    fn check(
+        guard: &mut FuseGuard,
+        currents: [f32; 3],
+        vehicle: Option<[f32; 3]>,
+        setpoint: u16,
+        now: u64,
+    ) -> Option<u16> {
+        guard.check(currents, &[load(vehicle, setpoint, 0)], now)[0]
+    }
+
+    #[test]
+    fn current_below_the_limit_is_left_alone() {
+        let mut guard = guard();
+        assert_eq!(check(&mut guard, [32.0, 20.0, 10.0], None, 16, NOW), None);
+        assert_eq!(guard.cap(0, NOW), None);
+    }
+
+    #[test]
+    fn overloaded_phase_reduces_and_caps_the_current() {
+        let mut guard = guard();
+        assert_eq!(
+            check(&mut guard, [36.5, 20.0, 20.0], None, 16, NOW),
+            Some(12)
+        );
+        assert_eq!(guard.cap(0, NOW + 59), Some(12));
+        assert_eq!(guard.cap(0, NOW + 60), None);
+
+        // Readings still showing the old setpoint don't reduce it further
+        assert_eq!(
+            check(&mut guard, [36.5, 20.0, 20.0], None, 12, NOW + 1),
+            None
+        );
+        assert_eq!(
+            check(&mut guard, [34.0, 20.0, 20.0], None, 12, NOW + 5),
+            Some(11)
+        );
+    }
+
+    #[test]
+    fn current_below_the_vehicles_minimum_stops_charging() {
+        let mut guard = guard();
+        assert_eq!(
+            check(&mut guard, [45.0, 20.0, 20.0], None, 16, NOW),
+            Some(0)
+        );
+        assert_eq!(guard.cap(0, NOW), Some(0));
+    }
+
+    #[test]
+    fn phases_the_vehicle_does_not_draw_on_are_ignored() {
+        let mut guard = guard();
+        let vehicle = Some([16.0, 0.0, 0.0]);
+        assert_eq!(
+            check(&mut guard, [20.0, 40.0, 20.0], vehicle, 16, NOW),
+            None
+        );
+        assert_eq!(
+            check(&mut guard, [35.0, 40.0, 20.0], vehicle, 16, NOW),
+            Some(14)
+        );
+    }
+
+    #[test]
+    fn reduction_is_shared_among_vehicles() {
+        let mut guard = guard();
+        // Two vehicles draw 16A each on the first phase, which is 7A over
+        let loads = [
+            load(Some([16.0, 16.0, 16.0]), 16, 10),
+            load(Some([16.0, 0.0, 0.0]), 16, 20),
+            load(Some([0.0, 16.0, 0.0]), 16, 30),
+        ];
+        let reductions = guard.check([40.0, 30.0, 20.0], &loads, NOW);
+        assert_eq!(reductions, vec![Some(12), Some(13), None]);
+        assert_eq!(guard.cap(2, NOW), None);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::config::Config;
+use crate::pac2200::{Pac2200, Pac2200Params};
+use crate::poller::PollerStats;
+use crate::pv_source::PvParams;
+use std::io::Result;
+use std::sync::Arc;
+
+/// Snapshot of the grid exchange point, independent of the meter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridParams {
+    /// Time of the reading, in seconds since the UNIX epoch
+    pub update: u64,
+    /// Power exchanged with the grid, positive values denote import
+    pub power: i32,
+    /// Power exchanged per phase, if the meter measures it
+    pub phase_power: Option<[i32; 3]>,
+    /// Current per phase in Amps, if the meter measures it
+    pub phase_current: Option<[f32; 3]>,
+}
+
+/// A meter at the grid exchange point
+pub trait GridMeter: Send + Sync {
+    /// Return the most recent reading, if any
+    fn get_grid_params(&self) -> Option<GridParams>;
+
+    /// Return the most recent device specific reading for the status socket
+    fn get_raw_params(&self) -> Option<serde_json::Value>;
+
+    /// Return the connection statistics of the device
+    fn stats(&self) -> PollerStats;
+}
+
+/// The PV system's own measurement of the grid exchange
+impl From<&PvParams> for GridParams {
+    fn from(p: &PvParams) -> Self {
+        GridParams {
+            update: p.update,
+            power: p.grid_power,
+            phase_power: None,
+            phase_current: None,
+        }
+    }
+}
+
+/// A PAC2200 installed at the grid connection, measuring import as
+/// positive power
+impl From<&Pac2200Params> for GridParams {
+    fn from(p: &Pac2200Params) -> Self {
+        let phase_power = [p.p_l1, p.p_l2, p.p_l3].map(|p| p.round() as i32);
+        GridParams {
+            update: p.update,
+            power: phase_power.iter().sum(),
+            phase_power: Some(phase_power),
+            phase_current: Some([p.i_l1, p.i_l2, p.i_l3]),
+        }
+    }
+}
+
+impl GridMeter for Pac2200 {
+    fn get_grid_params(&self) -> Option<GridParams> {
+        self.poller()
+            .get_current_params()
+            .as_ref()
+            .map(GridParams::from)
+    }
+
+    fn get_raw_params(&self) -> Option<serde_json::Value> {
+        self.poller()
+            .get_current_params()
+            .and_then(|p| serde_json::to_value(p).ok())
+    }
+
+    fn stats(&self) -> PollerStats {
+        self.poller().stats()
+    }
+}
+
+/// Create the grid meter if one is configured. Otherwise, the PV
+/// system's measurement of the grid exchange is used.
+pub fn grid_meter_from_config(config: &Config) -> Result<Option<Arc<dyn GridMeter>>> {
+    let Some(connection) = config.pac2200.as_ref() else {
+        return Ok(None);
+    };
+    let pac2200 = Pac2200::new(
+        connection.connector()?,
+        std::time::Duration::from_secs(1),
+        connection.load_register_map()?,
+    )?;
+    Ok(Some(Arc::new(pac2200)))
+}
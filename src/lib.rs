@@ -0,0 +1,72 @@
+//! Drivers and data types for the E3DC PV system, the Mennekes charger,
+//! the Siemens PAC2200 energy meter and the Doepke DCTR residual current
+//! monitor, plus the charging controller of the wallbox manager.
+//!
+//! The `wallbox` binary is a thin command line interface on top of this
+//! library; dashboards and scripts can use the same decoders, e.g.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use wallbox::e3dc::E3DC;
+//! use wallbox::poller::TcpConnector;
+//!
+//! let connector = TcpConnector {
+//!     host: String::from("192.168.34.10"),
+//!     port: wallbox::MODBUS_DEFAULT_PORT,
+//!     slave_id: 1,
+//! };
+//! let e3dc = E3DC::new(connector, Duration::from_secs(1), None)?;
+//! if let Some(params) = e3dc.get_current_params() {
+//!     println!("PV power: {}W", params.pv_power);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+
+pub mod charger;
+pub mod config;
+pub mod controller;
+pub mod dctr;
+pub mod e3dc;
+pub mod forecast;
+pub mod fuse;
+pub mod grid;
+pub mod mennekes;
+pub mod modbus_server;
+pub mod pac2200;
+pub mod poller;
+pub mod pv_source;
+pub mod register_map;
+pub mod rtu;
+pub mod schedule;
+pub mod sharing;
+pub mod smoothing;
+pub mod tariff;
+pub mod time_series;
+pub mod unbalance;
+
+pub const MODBUS_DEFAULT_PORT: u16 = 502;
+
+/// Log messages of level info and above to stderr, for the command line
+/// tools that run in the foreground
+pub fn init_stderr_logging() -> std::io::Result<()> {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{}[{}][{}] {}",
+                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                record.target(),
+                record.level(),
+                message
+            ))
+        })
+        .chain(std::io::stderr())
+        .level(log::LevelFilter::Info)
+        .apply()
+        .map_err(std::io::Error::other)
+}
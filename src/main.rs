@@ -7,35 +7,34 @@ extern crate fern;
 extern crate serde_json;
 extern crate toml;
 
-mod config;
-mod dctr;
 mod devnull;
-mod e3dc;
-mod mennekes;
-mod pac2200;
 mod timeouter;
 
 mod decompress_stream;
 mod energy_meter;
+mod read_registers;
+mod replay;
 mod residual_current_monitor;
+mod simulate_devices;
 mod wallbox_manager;
 
 use clap::{Args, Parser, Subcommand};
-use dctr::Dctr;
 use devnull::DevNullFile;
-use e3dc::E3DC;
-use mennekes::Mennekes;
-use pac2200::Pac2200;
 use std::path::PathBuf;
 use timeouter::Timeouter;
+use wallbox::config::ModbusConnection;
+use wallbox::dctr::Dctr;
+use wallbox::pac2200::Pac2200;
+use wallbox::rtu::Parity;
 
 use decompress_stream::decompress_stream;
 use energy_meter::energy_meter;
+use read_registers::read_registers;
+use replay::replay;
 use residual_current_monitor::residual_current_monitor;
+use simulate_devices::simulate_devices;
 use wallbox_manager::wallbox_manager;
 
-const MODBUS_DEFAULT_PORT: u16 = 502;
-
 /// Modbus manager
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(name = "mb")]
@@ -64,6 +63,21 @@ enum Commands {
     /// when defined thresholds are exceeded
     #[command(arg_required_else_help = true)]
     ResidualCurrentMonitor(ResidualCurrentMonitorParams),
+
+    /// Read a Modbus device as described by a register map file
+    /// and print its values as JSON lines
+    #[command(arg_required_else_help = true)]
+    ReadRegisters(ReadRegistersParams),
+
+    /// Emulate the E3DC, Mennekes, PAC2200 and DCTR devices via
+    /// Modbus TCP, following a scripted scenario
+    #[command(arg_required_else_help = true)]
+    SimulateDevices(SimulateDevicesParams),
+
+    /// Feed recordings of the wallbox manager's status socket
+    /// through the charging algorithm and report the outcome
+    #[command(arg_required_else_help = true)]
+    Replay(ReplayParams),
 }
 
 #[derive(Debug, Args)]
@@ -73,11 +87,53 @@ pub struct WallboxManagerParams {
     pub config_path: PathBuf,
 }
 
+/// Options to reach a device via Modbus RTU on an RS-485 bus
+/// instead of Modbus TCP
+#[derive(Debug, Args)]
+pub struct SerialParams {
+    /// The serial port the RS-485 bus is connected to, e.g.
+    /// /dev/ttyUSB0. Use this instead of a host name to talk
+    /// Modbus RTU to the device.
+    #[arg(long)]
+    pub serial_port: Option<PathBuf>,
+
+    /// The baud rate of the serial line. 19200 is the default.
+    #[arg(long)]
+    pub baud_rate: Option<u32>,
+
+    /// The parity of the serial line. Even is the default.
+    #[arg(long)]
+    pub parity: Option<Parity>,
+
+    /// The device's Modbus unit identifier. 1 is the default.
+    #[arg(long)]
+    pub slave_id: Option<u8>,
+}
+
+impl SerialParams {
+    pub fn connection(
+        &self,
+        host: Option<String>,
+        port: Option<u16>,
+        register_map: Option<PathBuf>,
+    ) -> ModbusConnection {
+        ModbusConnection {
+            host,
+            port,
+            serial_port: self.serial_port.clone(),
+            baud_rate: self.baud_rate,
+            parity: self.parity,
+            slave_id: self.slave_id,
+            register_map,
+        }
+    }
+}
+
 #[derive(Debug, Args)]
 #[command(args_conflicts_with_subcommands = true)]
 pub struct EnergyMeterParams {
-    #[arg(short = 'H', long)]
-    pub meter_host: String,
+    #[arg(short = 'H', long, required_unless_present = "serial_port")]
+    pub meter_host: Option<String>,
 
     #[arg(short = 'P', long)]
     pub meter_port: Option<u16>,
@@ -96,6 +152,14 @@ pub struct EnergyMeterParams {
 
     #[arg(short = 'c', long)]
     pub write_to_console: Option<bool>,
+
+    /// A register map file that overrides the built-in
+    /// register layout of the PAC2200
+    #[arg(short, long)]
+    pub register_map: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub serial: SerialParams,
 }
 
 #[derive(Debug, Args)]
@@ -118,8 +182,8 @@ pub struct ResidualCurrentMonitorParams {
     /// that not all versions of the software support enabling
     /// the modbus; if in doubt, contact the manufacturer for
     /// the right version.
-    #[arg(short = 'H', long)]
-    pub host_name: String,
+    #[arg(short = 'H', long, required_unless_present = "serial_port")]
+    pub host_name: Option<String>,
 
     /// The RCM's port to connect to
     #[arg(short = 'P', long)]
@@ -161,6 +225,88 @@ pub struct ResidualCurrentMonitorParams {
     /// the alarm of type B.
     #[arg(long)]
     pub alarm_b_command: Option<String>,
+
+    /// A register map file that overrides the built-in
+    /// register layout of the DCTR
+    #[arg(short, long)]
+    pub register_map: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub serial: SerialParams,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct ReadRegistersParams {
+    /// The device's hostname to connect to
+    #[arg(short = 'H', long, required_unless_present = "serial_port")]
+    pub host_name: Option<String>,
+
+    /// The device's port to connect to
+    #[arg(short = 'P', long)]
+    pub port: Option<u16>,
+
+    /// The register map file describing the device, see the
+    /// register_maps directory for examples
+    #[arg(short, long)]
+    pub register_map: PathBuf,
+
+    /// The polling interval in milliseconds
+    #[arg(short, long)]
+    pub polling_interval: Option<u64>,
+
+    /// Exit after the first record has been printed
+    #[arg(short, long)]
+    pub once: bool,
+
+    #[command(flatten)]
+    pub serial: SerialParams,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct SimulateDevicesParams {
+    /// The scenario file describing PV production, house
+    /// consumption, vehicles and residual currents over time
+    #[arg(short, long)]
+    pub scenario: PathBuf,
+
+    /// Simulated seconds per real second. 1 is the default.
+    #[arg(long)]
+    pub speed: Option<f64>,
+
+    /// The host+port to serve the E3DC on. 127.0.0.1:5020 is
+    /// the default.
+    #[arg(long)]
+    pub e3dc_bind_to: Option<String>,
+
+    /// The host+port to serve the Mennekes wallbox on.
+    /// 127.0.0.1:5021 is the default.
+    #[arg(long)]
+    pub mennekes_bind_to: Option<String>,
+
+    /// The host+port to serve the PAC2200 on. 127.0.0.1:5022
+    /// is the default.
+    #[arg(long)]
+    pub pac2200_bind_to: Option<String>,
+
+    /// The host+port to serve the DCTR residual current
+    /// monitor on. 127.0.0.1:5023 is the default.
+    #[arg(long)]
+    pub rcm_bind_to: Option<String>,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct ReplayParams {
+    /// The configuration to evaluate
+    #[arg(short, long)]
+    pub config_path: PathBuf,
+
+    /// Recordings of the status socket, one JSON object per line.
+    /// Files ending in .gz are decompressed.
+    #[arg(short, long)]
+    pub file_name: Vec<PathBuf>,
 }
 
 fn main() {
@@ -171,6 +317,9 @@ fn main() {
         Commands::EnergyMeter(emp) => energy_meter(emp),
         Commands::WallboxManager(cmp) => wallbox_manager(cmp),
         Commands::ResidualCurrentMonitor(rcm) => residual_current_monitor(rcm),
+        Commands::ReadRegisters(rrp) => read_registers(rrp),
+        Commands::SimulateDevices(sdp) => simulate_devices(sdp),
+        Commands::Replay(rp) => replay(rp),
     };
     if let Err(e) = result {
         eprintln!("Error: {:?}", e);
@@ -1,42 +1,16 @@
+use crate::poller::{epoch_secs, modbus_error, read_holding_registers, Connector, Poller};
+use crate::register_map::RegisterMap;
 use byteorder::ReadBytesExt;
-use modbus::*;
-use std::io::Result;
-use std::ops::{Add, Deref};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread::{spawn, JoinHandle};
+use modbus::Client;
+use std::io::{Error, Result};
 
-use log::{debug, error, info, warn};
-
-const WAIT_AFTER_ERROR: u64 = 8;
+use log::{debug, info, warn};
 
 pub struct Mennekes {
-    #[allow(unused)]
-    handler: JoinHandle<()>,
-    do_run: Arc<AtomicBool>,
-    params: Arc<Mutex<Option<MennekesParams>>>,
-    action: Mutex<Sender<MennekesAction>>,
+    poller: Poller<MennekesParams>,
 }
 
-#[derive(Clone)]
-struct SetAmpsAction {
-    pub max_hems_current: u16,
-    pub message_if_changed: String,
-}
-
-#[derive(Clone)]
-struct AuthorizeUserAction {
-    pub user_id: String,
-}
-
-#[derive(Clone)]
-enum MennekesAction {
-    SetAmps(SetAmpsAction),
-    AuthorizeUser(AuthorizeUserAction),
-}
-
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MennekesParams {
     pub update: u64,
     pub control_pilot: u16,
@@ -60,290 +34,188 @@ pub struct MennekesParams {
     pub hems_current: u16,
 }
 
+/// Decode the Mennekes' registers, which are spread over several blocks
+pub(crate) fn decode(client: &mut dyn Client) -> Result<MennekesParams> {
+    let control_pilot = {
+        let buf = read_holding_registers(client, 104, 1)?;
+        let mut slice = buf.as_slice();
+        slice.read_u16::<byteorder::BE>()?
+    };
+
+    let (f_i_ac, f_i_dc) = {
+        let buf = read_holding_registers(client, 136, 2)?;
+        let mut slice = buf.as_slice();
+        (
+            slice.read_u16::<byteorder::BE>()?,
+            slice.read_u16::<byteorder::BE>()?,
+        )
+    };
+
+    let (i_l1, i_l2, i_l3, energy, power, u_l1, u_l2, u_l3) = {
+        let buf = read_holding_registers(client, 212, 16)?;
+        let mut slice = buf.as_slice();
+        (
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+        )
+    };
+
+    let (max_allowed_current_signalled, start_time) = {
+        let buf = read_holding_registers(client, 706, 3)?;
+        let mut slice = buf.as_slice();
+        (
+            slice.read_u16::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+        )
+    };
+
+    let (ev_required_energy, max_allowed_ev_current, current_energy, charging_duration) = {
+        let buf = read_holding_registers(client, 713, 7)?;
+        let mut slice = buf.as_slice();
+        (
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u16::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+            slice.read_u32::<byteorder::BE>()?,
+        )
+    };
+
+    let user_id = {
+        let buf = read_holding_registers(client, 720, 10)?;
+        let user_id = String::from_utf8(buf)
+            .map_err(Error::other)?
+            .trim()
+            .to_string();
+        if user_id.is_empty() {
+            None
+        } else {
+            Some(user_id)
+        }
+    };
+
+    let hems_current = read_hems_current(client)?;
+
+    Ok(MennekesParams {
+        update: epoch_secs()?,
+        control_pilot,
+        f_i_ac,
+        f_i_dc,
+        i_l1,
+        i_l2,
+        i_l3,
+        energy,
+        power,
+        u_l1,
+        u_l2,
+        u_l3,
+        max_allowed_current_signalled,
+        start_time,
+        ev_required_energy,
+        max_allowed_ev_current,
+        current_energy,
+        charging_duration,
+        user_id,
+        hems_current,
+    })
+}
+
+fn read_hems_current(client: &mut dyn Client) -> Result<u16> {
+    let buf = read_holding_registers(client, 1000, 1)?;
+    let mut slice = buf.as_slice();
+    slice.read_u16::<byteorder::BE>()
+}
+
 impl Mennekes {
-    pub fn new(
-        host_name: &str,
-        port: u16,
+    /// Start polling the device. Unless a register map is given, the
+    /// built-in register layout is used.
+    pub fn new<C: Connector + 'static>(
+        connector: C,
         polling_interval: std::time::Duration,
+        register_map: Option<RegisterMap>,
     ) -> Result<Mennekes> {
-        let host_name = String::from(host_name);
-        let do_run = Arc::new(AtomicBool::new(true));
-        let do_run_clone = do_run.clone();
-        let params = Arc::new(Mutex::new(None));
-        let params_clone = params.clone();
-        let (action, receive_action) = channel();
-        let handler = spawn(move || {
-            while do_run_clone.load(Ordering::Relaxed) {
-                let mut cfg = tcp::Config::default();
-                cfg.tcp_port = port;
-                match tcp::Transport::new_with_cfg(&host_name, cfg) {
-                    Ok(mut client) => {
-                        while do_run_clone.load(Ordering::Relaxed) {
-                            let mut fetch = || {
-                                let control_pilot = {
-                                    let registers =
-                                        client.read_holding_registers(104, 1).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    slice.read_u16::<byteorder::BE>()?
-                                };
-
-                                let (f_i_ac, f_i_dc) = {
-                                    let registers =
-                                        client.read_holding_registers(136, 2).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    (
-                                        slice.read_u16::<byteorder::BE>()?,
-                                        slice.read_u16::<byteorder::BE>()?,
-                                    )
-                                };
-
-                                let (i_l1, i_l2, i_l3, energy, power, u_l1, u_l2, u_l3) = {
-                                    let registers =
-                                        client.read_holding_registers(212, 16).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    (
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                    )
-                                };
-
-                                let (max_allowed_current_signalled, start_time) = {
-                                    let registers =
-                                        client.read_holding_registers(706, 3).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    (
-                                        slice.read_u16::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                    )
-                                };
-
-                                let (
-                                    ev_required_energy,
-                                    max_allowed_ev_current,
-                                    current_energy,
-                                    charging_duration,
-                                ) = {
-                                    let registers =
-                                        client.read_holding_registers(713, 7).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    (
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u16::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                        slice.read_u32::<byteorder::BE>()?,
-                                    )
-                                };
-
-                                let user_id = {
-                                    let registers =
-                                        client.read_holding_registers(720, 10).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let user_id = String::from_utf8(buf)
-                                        .map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?
-                                        .trim()
-                                        .to_string();
-                                    if user_id.is_empty() {
-                                        None
-                                    } else {
-                                        Some(user_id)
-                                    }
-                                };
+        let poller = match register_map {
+            Some(map) => Poller::new(
+                "mennekes wallbox",
+                connector,
+                polling_interval,
+                move |c: &mut dyn Client| map.read_as(c),
+            ),
+            None => Poller::new("mennekes wallbox", connector, polling_interval, decode),
+        };
+        Ok(Mennekes { poller })
+    }
 
-                                let hems_current = {
-                                    let registers =
-                                        client.read_holding_registers(1000, 1).map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?;
-                                    let buf = binary::unpack_bytes(&registers);
-                                    let mut slice = buf.as_slice();
-                                    slice.read_u16::<byteorder::BE>()?
-                                };
+    pub fn get_current_params(&self) -> Option<MennekesParams> {
+        self.poller.get_current_params()
+    }
 
-                                let update = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .map(|r| r.as_secs())
-                                    .map_err(|e| {
-                                        std::io::Error::new(std::io::ErrorKind::Other, e)
-                                    })?;
-                                Ok::<_, std::io::Error>(MennekesParams {
-                                    update,
-                                    control_pilot,
-                                    f_i_ac,
-                                    f_i_dc,
-                                    i_l1,
-                                    i_l2,
-                                    i_l3,
-                                    energy,
-                                    power,
-                                    u_l1,
-                                    u_l2,
-                                    u_l3,
-                                    max_allowed_current_signalled,
-                                    start_time,
-                                    ev_required_energy,
-                                    max_allowed_ev_current,
-                                    current_energy,
-                                    charging_duration,
-                                    user_id,
-                                    hems_current,
-                                })
-                            };
-                            match fetch() {
-                                Ok(mennekesparams) => {
-                                    if let Ok(mut p) = params_clone.lock() {
-                                        *p = Some(mennekesparams);
-                                    } else {
-                                        warn!("Cannot update params, cannot acquire mutex lock");
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Error while reading from mennekes wallbox: {:?}", e);
-                                    break;
-                                }
-                            }
+    /// Access the poller, e.g. for staleness checks and statistics
+    pub fn poller(&self) -> &Poller<MennekesParams> {
+        &self.poller
+    }
 
-                            let wait_until = std::time::SystemTime::now().add(polling_interval);
-                            while std::time::SystemTime::now() < wait_until {
-                                if let Ok(action) = receive_action.try_recv() {
-                                    match action {
-                                        MennekesAction::SetAmps(amps) => {
-                                            if amps.max_hems_current > 16 {
-                                                warn!(
-                                                    "Illegal HEMS current of {} Amps",
-                                                    amps.max_hems_current
-                                                );
-                                            } else {
-                                                let hems_current = (|| {
-                                                    let registers = client
-                                                        .read_holding_registers(1000, 1)
-                                                        .map_err(|e| {
-                                                            std::io::Error::new(
-                                                                std::io::ErrorKind::Other,
-                                                                e,
-                                                            )
-                                                        })?;
-                                                    let buf = binary::unpack_bytes(&registers);
-                                                    let mut slice = buf.as_slice();
-                                                    slice.read_u16::<byteorder::BE>()
-                                                })(
-                                                );
-                                                if hems_current.is_err() {
-                                                    error!(
-                                                        "Unable to read HEMS current: {:?}",
-                                                        hems_current.err()
-                                                    );
-                                                } else {
-                                                    let hems_current = hems_current.unwrap();
-                                                    if hems_current == amps.max_hems_current {
-                                                        debug!(
-                                                            "{} == {}, not changing value",
-                                                            hems_current, amps.max_hems_current
-                                                        );
-                                                    } else {
-                                                        match client.write_single_register(1000, amps.max_hems_current) {
-                                                            Ok(_) => {
-                                                                info!("{}", amps.message_if_changed);
-                                                            },
-                                                            Err(e) => error!("Unable to change HEMS current! {:?}", e)
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        MennekesAction::AuthorizeUser(user) => {
-                                            let regs =
-                                                modbus::binary::pack_bytes(user.user_id.as_bytes());
-                                            if regs.is_err() {
-                                                error!(
-                                                    "Unable to pack bytes to authorize user: {:?}",
-                                                    regs.err()
-                                                );
-                                            } else {
-                                                let regs = regs.unwrap();
-                                                match client.write_multiple_registers(1110, &regs) {
-                                                    Ok(_) => info!("User authorized."),
-                                                    Err(e) => {
-                                                        warn!("Unable to authorize user! {:?}", e)
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                std::thread::sleep(std::time::Duration::from_secs(1));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Error while connecting to mennekes wallbox: {:?}", e);
-                    }
-                }
-                std::thread::sleep(std::time::Duration::from_secs(WAIT_AFTER_ERROR));
+    pub fn set_amps(&self, max_hems_current: u16, message_if_changed: String) {
+        if max_hems_current > 16 {
+            warn!("Illegal HEMS current of {} Amps", max_hems_current);
+            return;
+        }
+        self.poller.execute(Box::new(move |client| {
+            let hems_current = read_hems_current(client)?;
+            if hems_current == max_hems_current {
+                debug!(
+                    "{} == {}, not changing value",
+                    hems_current, max_hems_current
+                );
+            } else {
+                client
+                    .write_single_register(1000, max_hems_current)
+                    .map_err(modbus_error)?;
+                info!("{}", message_if_changed);
             }
-            ()
-        });
-
-        Ok(Mennekes {
-            handler,
-            do_run,
-            params,
-            action: Mutex::new(action),
-        })
+            Ok(())
+        }));
     }
 
-    pub fn get_current_params(&self) -> Option<MennekesParams> {
-        if let Ok(l) = self.params.lock() {
-            (l.deref()).clone()
-        } else {
-            warn!("Unable to acquire mutex lock when fetching params!");
-            None
-        }
+    pub fn write_coil(&self, address: u16, value: bool, message: String) {
+        self.poller.execute(Box::new(move |client| {
+            let coil = if value {
+                modbus::Coil::On
+            } else {
+                modbus::Coil::Off
+            };
+            client
+                .write_single_coil(address, coil)
+                .map_err(modbus_error)?;
+            info!("{}", message);
+            Ok(())
+        }));
     }
 
-    pub fn set_amps(&self, max_hems_current: u16, message_if_changed: String) {
-        let action = MennekesAction::SetAmps(SetAmpsAction {
-            max_hems_current,
-            message_if_changed,
-        });
-        let action_sender = self.action.lock().unwrap();
-        action_sender.send(action).expect("set_amps");
+    pub fn write_register(&self, address: u16, value: u16, message: String) {
+        self.poller.execute(Box::new(move |client| {
+            client
+                .write_single_register(address, value)
+                .map_err(modbus_error)?;
+            info!("{}", message);
+            Ok(())
+        }));
     }
 
     #[allow(unused)]
     pub fn authorize_user(&self, user_id: String) {
-        let action = MennekesAction::AuthorizeUser(AuthorizeUserAction { user_id });
-        let action_sender = self.action.lock().unwrap();
-        action_sender.send(action).expect("authorize_user");
-    }
-}
-
-impl Drop for Mennekes {
-    fn drop(&mut self) {
-        self.do_run.store(false, Ordering::Relaxed);
+        self.poller.execute(Box::new(move |client| {
+            let regs = modbus::binary::pack_bytes(user_id.as_bytes()).map_err(modbus_error)?;
+            client
+                .write_multiple_registers(1110, &regs)
+                .map_err(modbus_error)?;
+            info!("User authorized.");
+            Ok(())
+        }));
     }
 }
@@ -0,0 +1,209 @@
+use byteorder::{ByteOrder, BE};
+use log::{debug, info, warn};
+use modbus::ExceptionCode;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::spawn;
+
+/// The largest number of registers served in one read. The Modbus
+/// specification allows 125, but the E3DC is read in one block of 126
+/// registers, so allow as many as fit into the byte count.
+const MAX_READ_REGISTERS: u16 = 127;
+/// The largest number of registers written in one request, as the
+/// Modbus specification allows
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// The registers of a device served via Modbus TCP. Holding and input
+/// registers share the same address space.
+pub trait RegisterBank: Send + Sync {
+    fn read(&self, address: u16, count: u16) -> std::result::Result<Vec<u16>, ExceptionCode>;
+    fn write(&self, address: u16, values: &[u16]) -> std::result::Result<(), ExceptionCode>;
+}
+
+/// Serve the register bank via Modbus TCP in a background thread and
+/// return the address bound to. Every connection is handled in a thread
+/// of its own. The unit identifier of requests is ignored.
+pub fn serve(name: &str, bind_to: &str, bank: Arc<dyn RegisterBank>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(bind_to)?;
+    let address = listener.local_addr()?;
+    info!("Serving {} on {}", name, address);
+    let name = String::from(name);
+    spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let name = name.clone();
+                    let bank = bank.clone();
+                    spawn(move || {
+                        let peer = stream.peer_addr();
+                        debug!("{}: connection from {:?}", name, peer);
+                        if let Err(e) = handle_connection(stream, bank.as_ref()) {
+                            warn!("{}: connection from {:?} failed: {}", name, peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("{}: unable to accept connection: {}", name, e),
+            }
+        }
+    });
+    Ok(address)
+}
+
+fn handle_connection(mut stream: TcpStream, bank: &dyn RegisterBank) -> Result<()> {
+    loop {
+        // Transaction id, protocol id, length and unit id
+        let mut header = [0u8; 7];
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let length = BE::read_u16(&header[4..6]);
+        if !(2..=254).contains(&length) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid frame length {}", length),
+            ));
+        }
+        let mut pdu = vec![0u8; length as usize - 1];
+        stream.read_exact(&mut pdu)?;
+        let response = handle_pdu(bank, &pdu);
+        let mut frame = header.to_vec();
+        BE::write_u16(&mut frame[4..6], response.len() as u16 + 1);
+        frame.extend_from_slice(&response);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// Execute a request and return the response PDU
+fn handle_pdu(bank: &dyn RegisterBank, pdu: &[u8]) -> Vec<u8> {
+    let function = pdu[0];
+    let response = match function {
+        0x03 | 0x04 => read_registers(bank, function, &pdu[1..]),
+        0x06 if pdu.len() == 5 => bank
+            .write(BE::read_u16(&pdu[1..3]), &[BE::read_u16(&pdu[3..5])])
+            .map(|_| pdu.to_vec()),
+        0x10 => write_registers(bank, &pdu[1..]),
+        0x06 => Err(ExceptionCode::IllegalDataValue),
+        _ => Err(ExceptionCode::IllegalFunction),
+    };
+    response.unwrap_or_else(|code| vec![function | 0x80, code as u8])
+}
+
+fn read_registers(
+    bank: &dyn RegisterBank,
+    function: u8,
+    data: &[u8],
+) -> std::result::Result<Vec<u8>, ExceptionCode> {
+    if data.len() != 4 {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let address = BE::read_u16(&data[0..2]);
+    let count = BE::read_u16(&data[2..4]);
+    if count == 0 || count > MAX_READ_REGISTERS {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    if address as u32 + count as u32 > 0x10000 {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    let registers = bank.read(address, count)?;
+    let mut response = vec![function, (registers.len() * 2) as u8];
+    for register in registers {
+        response.extend_from_slice(&register.to_be_bytes());
+    }
+    Ok(response)
+}
+
+fn write_registers(
+    bank: &dyn RegisterBank,
+    data: &[u8],
+) -> std::result::Result<Vec<u8>, ExceptionCode> {
+    if data.len() < 5 || data.len() != 5 + data[4] as usize {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let address = BE::read_u16(&data[0..2]);
+    let count = BE::read_u16(&data[2..4]);
+    if count == 0 || count > MAX_WRITE_REGISTERS || data[4] as u16 != count * 2 {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    if address as u32 + count as u32 > 0x10000 {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    let values: Vec<u16> = data[5..].chunks(2).map(BE::read_u16).collect();
+    bank.write(address, &values)?;
+    let mut response = vec![0x10];
+    response.extend_from_slice(&data[0..4]);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modbus::{tcp, Client};
+    use std::sync::Mutex;
+
+    /// Registers 0 to 199, of which only 100 and above are writable
+    struct TestBank(Mutex<Vec<u16>>);
+
+    impl RegisterBank for TestBank {
+        fn read(&self, address: u16, count: u16) -> std::result::Result<Vec<u16>, ExceptionCode> {
+            let registers = self.0.lock().unwrap();
+            registers
+                .get(address as usize..address as usize + count as usize)
+                .map(|r| r.to_vec())
+                .ok_or(ExceptionCode::IllegalDataAddress)
+        }
+
+        fn write(&self, address: u16, values: &[u16]) -> std::result::Result<(), ExceptionCode> {
+            if address < 100 || address as usize + values.len() > 200 {
+                return Err(ExceptionCode::IllegalDataAddress);
+            }
+            let mut registers = self.0.lock().unwrap();
+            registers[address as usize..address as usize + values.len()].copy_from_slice(values);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_and_write_registers_via_tcp() {
+        let bank = Arc::new(TestBank(Mutex::new((0..200).collect())));
+        let port = serve("test device", "127.0.0.1:0", bank.clone())
+            .unwrap()
+            .port();
+
+        let cfg = tcp::Config {
+            tcp_port: port,
+            ..Default::default()
+        };
+        let mut client = tcp::Transport::new_with_cfg("127.0.0.1", cfg).unwrap();
+        assert_eq!(client.read_holding_registers(3, 2).unwrap(), vec![3, 4]);
+        assert_eq!(client.read_holding_registers(0, 126).unwrap().len(), 126);
+        assert_eq!(client.read_input_registers(199, 1).unwrap(), vec![199]);
+        client.write_single_register(100, 1000).unwrap();
+        client.write_multiple_registers(150, &[1, 2, 3]).unwrap();
+
+        let registers = bank.0.lock().unwrap();
+        assert_eq!(registers[100], 1000);
+        assert_eq!(&registers[150..153], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn exception_responses() {
+        // The modbus client waits for a full-length reply, so exceptions
+        // are checked on the PDU level
+        let bank = TestBank(Mutex::new((0..200).collect()));
+        assert_eq!(handle_pdu(&bank, &[0x06, 0, 10, 0, 1]), vec![0x86, 0x02]);
+        assert_eq!(handle_pdu(&bank, &[0x03, 0, 199, 0, 2]), vec![0x83, 0x02]);
+        assert_eq!(handle_pdu(&bank, &[0x03, 0, 0, 0, 128]), vec![0x83, 0x03]);
+        assert_eq!(
+            handle_pdu(&bank, &[0x10, 0, 100, 0, 2, 2, 0, 1]),
+            vec![0x90, 0x03]
+        );
+        assert_eq!(
+            handle_pdu(&bank, &[0x10, 0, 100, 0x80, 0, 0]),
+            vec![0x90, 0x03]
+        );
+        assert_eq!(handle_pdu(&bank, &[0x01, 0, 0, 0, 1]), vec![0x81, 0x01]);
+        assert_eq!(bank.0.lock().unwrap()[10], 10);
+    }
+}
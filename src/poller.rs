@@ -0,0 +1,401 @@
+use log::{debug, info, warn};
+use modbus::{tcp, Client};
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The first reconnection attempt is made after this many seconds
+const MIN_BACKOFF: u64 = 1;
+/// The delay between reconnection attempts doubles up to this many seconds
+const WAIT_AFTER_ERROR: u64 = 8;
+/// Timeout for connecting to, reading from and writing to a device
+const MODBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Modbus connection that can be shared with the poller thread
+pub type ModbusClient = Box<dyn Client + Send>;
+
+/// A write or other one-off operation to execute on the device's connection
+pub type Command = Box<dyn FnOnce(&mut dyn Client) -> Result<()> + Send>;
+
+/// Establishes connections to a Modbus device
+pub trait Connector: Send {
+    fn connect(&self) -> Result<ModbusClient>;
+}
+
+impl Connector for Box<dyn Connector> {
+    fn connect(&self) -> Result<ModbusClient> {
+        self.as_ref().connect()
+    }
+}
+
+/// Connects to a device via Modbus TCP
+pub struct TcpConnector {
+    pub host: String,
+    pub port: u16,
+    pub slave_id: u8,
+}
+
+impl Connector for TcpConnector {
+    fn connect(&self) -> Result<ModbusClient> {
+        let cfg = tcp::Config {
+            tcp_port: self.port,
+            modbus_uid: self.slave_id,
+            tcp_connect_timeout: Some(MODBUS_TIMEOUT),
+            tcp_read_timeout: Some(MODBUS_TIMEOUT),
+            tcp_write_timeout: Some(MODBUS_TIMEOUT),
+        };
+        Ok(Box::new(tcp::Transport::new_with_cfg(&self.host, cfg)?))
+    }
+}
+
+/// Connection and polling statistics of a device
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PollerStats {
+    pub connects: u64,
+    pub connect_errors: u64,
+    pub polls: u64,
+    pub poll_errors: u64,
+    pub commands: u64,
+    pub command_errors: u64,
+    pub last_error: Option<String>,
+    /// Time of the last successful poll, in seconds since the UNIX epoch
+    pub last_update: Option<u64>,
+}
+
+struct Shared<T> {
+    do_run: AtomicBool,
+    params: Mutex<Option<T>>,
+    stats: Mutex<PollerStats>,
+}
+
+impl<T> Shared<T> {
+    fn update_stats<F: FnOnce(&mut PollerStats)>(&self, f: F) {
+        if let Ok(mut stats) = self.stats.lock() {
+            f(&mut stats);
+        }
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let wait_until = Instant::now() + duration;
+        while self.do_run.load(Ordering::Relaxed) && Instant::now() < wait_until {
+            std::thread::sleep(std::cmp::min(
+                Duration::from_secs(1),
+                wait_until.saturating_duration_since(Instant::now()),
+            ));
+        }
+    }
+}
+
+/// Polls a Modbus device in a background thread. The thread connects
+/// to the device, decodes its registers once per polling interval,
+/// executes queued commands in between and reconnects with an
+/// increasing delay whenever an error occurs. The thread is stopped
+/// when the poller is dropped.
+pub struct Poller<T> {
+    name: String,
+    #[allow(unused)]
+    handler: JoinHandle<()>,
+    shared: Arc<Shared<T>>,
+    commands: Mutex<Sender<Command>>,
+}
+
+impl<T: Clone + Send + 'static> Poller<T> {
+    /// Start polling. The decoder reads the device's registers and
+    /// turns them into a reading.
+    pub fn new<C, D>(name: &str, connector: C, polling_interval: Duration, decoder: D) -> Poller<T>
+    where
+        C: Connector + 'static,
+        D: FnMut(&mut dyn Client) -> Result<T> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            do_run: AtomicBool::new(true),
+            params: Mutex::new(None),
+            stats: Mutex::new(PollerStats::default()),
+        });
+        let (commands, receive_commands) = channel();
+        let handler = {
+            let name = String::from(name);
+            let shared = shared.clone();
+            spawn(move || {
+                poll(
+                    &name,
+                    &shared,
+                    connector,
+                    polling_interval,
+                    decoder,
+                    receive_commands,
+                )
+            })
+        };
+        Poller {
+            name: String::from(name),
+            handler,
+            shared,
+            commands: Mutex::new(commands),
+        }
+    }
+
+    /// Return the most recent reading, if any
+    pub fn get_current_params(&self) -> Option<T> {
+        if let Ok(l) = self.shared.params.lock() {
+            l.clone()
+        } else {
+            warn!("Unable to acquire mutex lock when fetching params!");
+            None
+        }
+    }
+
+    /// Return the most recent reading, if any, and forget about it so
+    /// the next call only returns a reading once a new one arrived
+    pub fn take_current_params(&self) -> Option<T> {
+        if let Ok(mut l) = self.shared.params.lock() {
+            l.take()
+        } else {
+            warn!("Unable to acquire mutex lock when fetching params!");
+            None
+        }
+    }
+
+    pub fn stats(&self) -> PollerStats {
+        self.shared
+            .stats
+            .lock()
+            .map(|s| s.clone())
+            .unwrap_or_default()
+    }
+
+    /// Queue a command for execution on the device's connection
+    pub fn execute(&self, command: Command) {
+        if let Ok(sender) = self.commands.lock() {
+            if sender.send(command).is_err() {
+                warn!("{}: poller is not running, dropping command", self.name);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Poller<T> {
+    fn drop(&mut self) {
+        self.shared.do_run.store(false, Ordering::Relaxed);
+    }
+}
+
+fn poll<T, C, D>(
+    name: &str,
+    shared: &Shared<T>,
+    connector: C,
+    polling_interval: Duration,
+    mut decoder: D,
+    receive_commands: Receiver<Command>,
+) where
+    C: Connector,
+    D: FnMut(&mut dyn Client) -> Result<T>,
+{
+    let mut backoff = MIN_BACKOFF;
+    while shared.do_run.load(Ordering::Relaxed) {
+        match connector.connect() {
+            Ok(mut client) => {
+                info!("Connected to {}", name);
+                shared.update_stats(|s| s.connects += 1);
+                while shared.do_run.load(Ordering::Relaxed) {
+                    match decoder(client.as_mut()) {
+                        Ok(params) => {
+                            backoff = MIN_BACKOFF;
+                            if let Ok(mut p) = shared.params.lock() {
+                                *p = Some(params);
+                            } else {
+                                warn!("Cannot update params, cannot acquire mutex lock");
+                            }
+                            shared.update_stats(|s| {
+                                s.polls += 1;
+                                s.last_update = epoch_secs().ok();
+                            });
+                        }
+                        Err(e) => {
+                            warn!("Error while reading from {}: {:?}", name, e);
+                            shared.update_stats(|s| {
+                                s.poll_errors += 1;
+                                s.last_error = Some(e.to_string());
+                            });
+                            break;
+                        }
+                    }
+
+                    let wait_until = Instant::now() + polling_interval;
+                    while shared.do_run.load(Ordering::Relaxed) {
+                        let remaining = wait_until.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            break;
+                        }
+                        match receive_commands
+                            .recv_timeout(std::cmp::min(remaining, Duration::from_secs(1)))
+                        {
+                            Ok(command) => {
+                                let result = command(client.as_mut());
+                                shared.update_stats(|s| {
+                                    s.commands += 1;
+                                    if let Err(e) = &result {
+                                        s.command_errors += 1;
+                                        s.last_error = Some(e.to_string());
+                                    }
+                                });
+                                if let Err(e) = result {
+                                    warn!("Error while executing command on {}: {:?}", name, e);
+                                }
+                            }
+                            Err(RecvTimeoutError::Timeout) => (),
+                            Err(RecvTimeoutError::Disconnected) => {
+                                debug!("{}: command channel closed", name);
+                                shared.sleep(remaining);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Error while connecting to {}: {:?}", name, e);
+                shared.update_stats(|s| {
+                    s.connect_errors += 1;
+                    s.last_error = Some(e.to_string());
+                });
+            }
+        }
+        shared.sleep(Duration::from_secs(backoff));
+        backoff = std::cmp::min(backoff * 2, WAIT_AFTER_ERROR);
+    }
+}
+
+/// Read `count` holding registers starting at `address` as bytes
+pub fn read_holding_registers(
+    client: &mut dyn Client,
+    address: u16,
+    count: u16,
+) -> Result<Vec<u8>> {
+    let registers = client
+        .read_holding_registers(address, count)
+        .map_err(modbus_error)?;
+    Ok(modbus::binary::unpack_bytes(&registers))
+}
+
+/// Convert a Modbus error into an I/O error
+pub fn modbus_error(e: modbus::Error) -> Error {
+    match e {
+        modbus::Error::Io(e) => e,
+        e => Error::other(e),
+    }
+}
+
+/// The current time in seconds since the UNIX epoch
+pub fn epoch_secs() -> Result<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|r| r.as_secs())
+        .map_err(Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_server::{serve, RegisterBank};
+    use modbus::ExceptionCode;
+
+    struct TestBank(Mutex<Vec<u16>>);
+
+    impl RegisterBank for TestBank {
+        fn read(&self, address: u16, count: u16) -> std::result::Result<Vec<u16>, ExceptionCode> {
+            let registers = self.0.lock().unwrap();
+            registers
+                .get(address as usize..address as usize + count as usize)
+                .map(|r| r.to_vec())
+                .ok_or(ExceptionCode::IllegalDataAddress)
+        }
+
+        fn write(&self, address: u16, values: &[u16]) -> std::result::Result<(), ExceptionCode> {
+            let mut registers = self.0.lock().unwrap();
+            registers[address as usize..address as usize + values.len()].copy_from_slice(values);
+            Ok(())
+        }
+    }
+
+    /// Fails the first connection attempt and records when each one
+    /// was made
+    struct FlakyConnector {
+        port: u16,
+        attempts: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl Connector for FlakyConnector {
+        fn connect(&self) -> Result<ModbusClient> {
+            let mut attempts = self.attempts.lock().unwrap();
+            attempts.push(Instant::now());
+            if attempts.len() == 1 {
+                return Err(Error::other("Device unreachable"));
+            }
+            TcpConnector {
+                host: String::from("127.0.0.1"),
+                port: self.port,
+                slave_id: 1,
+            }
+            .connect()
+        }
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let until = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < until, "Timed out");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn reconnects_with_backoff_and_executes_commands() {
+        let bank = Arc::new(TestBank(Mutex::new(vec![0; 10])));
+        bank.0.lock().unwrap()[0] = 42;
+        let port = serve("test device", "127.0.0.1:0", bank.clone())
+            .unwrap()
+            .port();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let connector = FlakyConnector {
+            port,
+            attempts: attempts.clone(),
+        };
+        // The first read after connecting fails as well
+        let mut reads = 0;
+        let poller = Poller::new(
+            "test device",
+            connector,
+            Duration::from_millis(100),
+            move |client: &mut dyn Client| {
+                reads += 1;
+                if reads == 1 {
+                    return Err(Error::other("Garbled reply"));
+                }
+                let registers = client.read_holding_registers(0, 1).map_err(modbus_error)?;
+                Ok(registers[0])
+            },
+        );
+
+        wait_for(|| poller.get_current_params() == Some(42));
+        {
+            let attempts = attempts.lock().unwrap();
+            assert_eq!(attempts.len(), 3);
+            // The delay doubles after every error
+            assert!(attempts[1] - attempts[0] >= Duration::from_secs(MIN_BACKOFF));
+            assert!(attempts[2] - attempts[1] >= Duration::from_secs(2 * MIN_BACKOFF));
+        }
+
+        poller.execute(Box::new(|client: &mut dyn Client| {
+            client.write_single_register(5, 7).map_err(modbus_error)
+        }));
+        wait_for(|| poller.stats().commands == 1);
+        assert_eq!(bank.0.lock().unwrap()[5], 7);
+        let stats = poller.stats();
+        assert_eq!((stats.connects, stats.connect_errors), (2, 1));
+        assert_eq!(stats.poll_errors, 1);
+        assert_eq!((stats.commands, stats.command_errors), (1, 0));
+    }
+}
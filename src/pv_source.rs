@@ -0,0 +1,92 @@
+use crate::config::{Config, PvSourceType};
+use crate::e3dc::{E3DCParams, E3DC};
+use crate::poller::PollerStats;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// The E3DC's emergency power state while it runs in backup mode
+const EMERGENCY_POWER_ACTIVE: u16 = 1;
+
+/// Snapshot of the energy flows of the PV system and the house,
+/// independent of the inverter brand. All powers are in Watts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PvParams {
+    /// Time of the reading, in seconds since the UNIX epoch
+    pub update: u64,
+    /// Power produced by the PV modules
+    pub pv_power: i32,
+    /// Power consumed in the house, including the EV charger
+    pub house_power: i32,
+    /// Power exchanged with the grid, positive values denote import
+    pub grid_power: i32,
+    /// Power flowing into the home battery, negative values denote discharge
+    pub battery_power: i32,
+    /// State of charge of the home battery in percent
+    pub battery_soc: u16,
+    /// Whether the PV system supplies the house in backup mode, cut off
+    /// from the grid
+    #[serde(default)]
+    pub island: bool,
+}
+
+/// A source of PV and house energy readings the wallbox manager
+/// bases its charging decisions on
+pub trait PvSource: Send + Sync {
+    /// Return the most recent reading, if any
+    fn get_pv_params(&self) -> Option<PvParams>;
+
+    /// Return the most recent device specific reading for the status socket
+    fn get_raw_params(&self) -> Option<serde_json::Value>;
+
+    /// Return the connection statistics of the device
+    fn stats(&self) -> PollerStats;
+}
+
+impl From<&E3DCParams> for PvParams {
+    fn from(p: &E3DCParams) -> Self {
+        PvParams {
+            update: p.update,
+            pv_power: p.pv_power,
+            house_power: p.haus_power,
+            grid_power: p.netz_power,
+            battery_power: p.batt_power,
+            battery_soc: p.akku_charge_percentage,
+            island: p.emergency_power == EMERGENCY_POWER_ACTIVE,
+        }
+    }
+}
+
+impl PvSource for E3DC {
+    fn get_pv_params(&self) -> Option<PvParams> {
+        self.get_current_params().as_ref().map(PvParams::from)
+    }
+
+    fn get_raw_params(&self) -> Option<serde_json::Value> {
+        self.get_current_params()
+            .and_then(|p| serde_json::to_value(p).ok())
+    }
+
+    fn stats(&self) -> PollerStats {
+        self.poller().stats()
+    }
+}
+
+/// Create the PV source selected in the configuration
+pub fn pv_source_from_config(config: &Config) -> Result<Arc<dyn PvSource>> {
+    match config.pv_source {
+        PvSourceType::E3DC => {
+            let connection = config.e3dc.as_ref().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "pv_source is E3DC, but no e3dc connection is configured",
+                )
+            })?;
+            let e3dc = E3DC::new(
+                connection.connector()?,
+                std::time::Duration::from_secs(2),
+                connection.load_register_map()?,
+            )?;
+            Ok(Arc::new(e3dc))
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use crate::*;
+use modbus::Client;
+use std::io::{Error, Result};
+use std::time::Duration;
+use wallbox::poller::Poller;
+use wallbox::register_map::RegisterMap;
+
+pub fn read_registers(rrp: ReadRegistersParams) -> Result<()> {
+    let polling_interval = Duration::from_millis(rrp.polling_interval.unwrap_or(1000));
+    if polling_interval < Duration::from_millis(100) {
+        return Err(Error::other(
+            "A polling interval smaller than 100 milliseconds is too low",
+        ));
+    }
+    let map = RegisterMap::from_file(&rrp.register_map)?;
+    let connection = rrp.serial.connection(rrp.host_name, rrp.port, None);
+    let name = map.name.clone().unwrap_or(String::from("device"));
+    let poller = Poller::new(
+        &name,
+        connection.connector()?,
+        polling_interval,
+        move |c: &mut dyn Client| map.read(c),
+    );
+    loop {
+        if let Some(record) = poller.take_current_params() {
+            println!("{}", serde_json::to_string(&record).expect("serde_json"));
+            if rrp.once {
+                return Ok(());
+            }
+        }
+        std::thread::sleep(polling_interval / 4);
+    }
+}
@@ -0,0 +1,672 @@
+use crate::poller::{epoch_secs, modbus_error};
+use byteorder::{ByteOrder, BE};
+use modbus::Client;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// The maximum number of registers to merge adjacent fields into. The
+/// Modbus specification allows no more than this per read, though some
+/// devices support larger explicitly configured blocks.
+const MAX_REGISTERS_PER_READ: u16 = 125;
+/// Number of addressable registers
+const ADDRESS_SPACE: u32 = 0x10000;
+
+/// Describes where a device keeps its values and how to decode them.
+/// Register maps are read from TOML files, so support for new firmware
+/// versions or additional registers doesn't require recompiling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterMap {
+    pub name: Option<String>,
+    /// Whether to read holding or input registers
+    #[serde(default)]
+    pub registers: RegisterKind,
+    /// Ranges of registers to read in one go. Fields that are not
+    /// covered by any block are read in blocks of adjacent fields.
+    #[serde(default, rename = "block")]
+    pub blocks: Vec<Block>,
+    #[serde(rename = "field")]
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterKind {
+    #[default]
+    Holding,
+    Input,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub address: u16,
+    pub count: u16,
+}
+
+/// A single value of a device. The name may contain dots to create
+/// nested objects and `[n]` to create arrays, e.g. `f_i.dc` or
+/// `version_strings[0]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub address: u16,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// Number of registers, only used for strings
+    pub count: Option<u16>,
+    /// The decoded number is multiplied by this factor
+    pub scale: Option<f64>,
+    /// Which byte of the register to decode a `u8` from, 0 being the
+    /// most significant one
+    #[serde(default)]
+    pub byte: u8,
+    /// Names of the bits of a `bits` field, least significant bit first
+    #[serde(default)]
+    pub bits: Vec<String>,
+    /// Decode empty strings as null
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    U8,
+    U16,
+    I16,
+    U32,
+    I32,
+    /// A 32 bit integer with the low word first, as used by E3DC
+    I32BigLittle,
+    F32,
+    String,
+    Bits,
+}
+
+impl Field {
+    /// Number of registers the field occupies
+    pub fn register_count(&self) -> u16 {
+        match self.field_type {
+            FieldType::U8 | FieldType::U16 | FieldType::I16 | FieldType::Bits => 1,
+            FieldType::U32 | FieldType::I32 | FieldType::I32BigLittle | FieldType::F32 => 2,
+            FieldType::String => self.count.unwrap_or(1),
+        }
+    }
+
+    fn end(&self) -> u32 {
+        self.address as u32 + self.register_count() as u32
+    }
+
+    /// Decode the field from the registers it occupies
+    fn decode(&self, regs: &[u16]) -> Result<Value> {
+        let bytes = modbus::binary::unpack_bytes(regs);
+        let number = match self.field_type {
+            FieldType::U8 => bytes[(self.byte as usize).min(1)] as f64,
+            FieldType::U16 => regs[0] as f64,
+            FieldType::I16 => regs[0] as i16 as f64,
+            FieldType::U32 => BE::read_u32(&bytes) as f64,
+            FieldType::I32 => BE::read_i32(&bytes) as f64,
+            FieldType::I32BigLittle => {
+                BE::read_i32(&[bytes[2], bytes[3], bytes[0], bytes[1]]) as f64
+            }
+            FieldType::F32 => BE::read_f32(&bytes) as f64,
+            FieldType::String => {
+                let s = String::from_utf8(bytes).map_err(Error::other)?;
+                let s = s.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+                return Ok(if s.is_empty() && self.optional {
+                    Value::Null
+                } else {
+                    Value::String(String::from(s))
+                });
+            }
+            FieldType::Bits => {
+                let bits = self
+                    .bits
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| (name.clone(), Value::Bool(regs[0] & (1 << i) != 0)))
+                    .collect();
+                return Ok(Value::Object(bits));
+            }
+        };
+        Ok(match self.scale {
+            Some(scale) => Number::from_f64(number * scale)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            None => match self.field_type {
+                FieldType::F32 => Number::from_f64(number)
+                    .map(Value::Number)
+                    .unwrap_or(Value::Null),
+                _ => Value::from(number as i64),
+            },
+        })
+    }
+
+    /// Encode a value into the registers the field occupies. Registers
+    /// are updated in place, as `u8` fields only replace one byte.
+    fn encode(&self, value: &Value, regs: &mut [u16]) -> Result<()> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid value {} for field {}", value, self.name),
+            )
+        };
+        match self.field_type {
+            FieldType::String => {
+                let s = value.as_str().unwrap_or("");
+                let mut bytes = s.as_bytes().to_vec();
+                // The built-in decoders only trim whitespace
+                bytes.resize(regs.len() * 2, b' ');
+                for (reg, chunk) in regs.iter_mut().zip(bytes.chunks(2)) {
+                    *reg = BE::read_u16(chunk);
+                }
+                return Ok(());
+            }
+            FieldType::Bits => {
+                regs[0] = self
+                    .bits
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, name)| value[name.as_str()].as_bool().unwrap_or(false))
+                    .fold(0, |acc, (i, _)| acc | (1 << i));
+                return Ok(());
+            }
+            _ => (),
+        }
+        let number = value.as_f64().ok_or_else(invalid)? / self.scale.unwrap_or(1.0);
+        let mut bytes = [0u8; 4];
+        match self.field_type {
+            FieldType::U8 => {
+                let [high, low] = regs[0].to_be_bytes();
+                regs[0] = if self.byte == 0 {
+                    u16::from_be_bytes([number.round() as u8, low])
+                } else {
+                    u16::from_be_bytes([high, number.round() as u8])
+                };
+            }
+            FieldType::U16 => regs[0] = number.round() as u16,
+            FieldType::I16 => regs[0] = number.round() as i16 as u16,
+            FieldType::U32 => BE::write_u32(&mut bytes, number.round() as u32),
+            FieldType::I32 => BE::write_i32(&mut bytes, number.round() as i32),
+            FieldType::I32BigLittle => {
+                BE::write_i32(&mut bytes, number.round() as i32);
+                bytes = [bytes[2], bytes[3], bytes[0], bytes[1]];
+            }
+            FieldType::F32 => BE::write_f32(&mut bytes, number as f32),
+            FieldType::String | FieldType::Bits => unreachable!(),
+        }
+        if regs.len() == 2 {
+            regs[0] = BE::read_u16(&bytes[0..2]);
+            regs[1] = BE::read_u16(&bytes[2..4]);
+        }
+        Ok(())
+    }
+}
+
+impl RegisterMap {
+    pub fn from_file(path: &Path) -> Result<RegisterMap> {
+        let map = std::fs::read_to_string(path)?;
+        RegisterMap::parse(&map, &format!("{:?}", path))
+    }
+
+    /// The register map of a supported device, as read by its built-in
+    /// decoder. Known devices are `e3dc`, `pac2200`, `dctr` and `mennekes`.
+    pub fn builtin(device: &str) -> Result<RegisterMap> {
+        let map = match device {
+            "e3dc" => include_str!("../register_maps/e3dc.toml"),
+            "pac2200" => include_str!("../register_maps/pac2200.toml"),
+            "dctr" => include_str!("../register_maps/dctr.toml"),
+            "mennekes" => include_str!("../register_maps/mennekes.toml"),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("No built-in register map for {}", device),
+                ))
+            }
+        };
+        RegisterMap::parse(map, device)
+    }
+
+    fn parse(map: &str, origin: &str) -> Result<RegisterMap> {
+        let map: RegisterMap = toml::from_str(map)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", origin, e)))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for block in &self.blocks {
+            if block.count == 0 || block.address as u32 + block.count as u32 > ADDRESS_SPACE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Block at {} has an invalid count", block.address),
+                ));
+            }
+        }
+        for field in &self.fields {
+            if field.register_count() == 0 || field.register_count() > MAX_REGISTERS_PER_READ {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Field {} has an invalid count", field.name),
+                ));
+            }
+            if field.end() > ADDRESS_SPACE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Field {} ends beyond the last register", field.name),
+                ));
+            }
+            if field.bits.len() > 16 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Field {} names more than 16 bits", field.name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The ranges of registers to read, consisting of the configured
+    /// blocks plus adjacent fields that aren't covered by them
+    pub fn read_plan(&self) -> Vec<Block> {
+        let mut plan = self.blocks.clone();
+        let mut uncovered: Vec<&Field> =
+            self.fields
+                .iter()
+                .filter(|f| {
+                    !self.blocks.iter().any(|b| {
+                        f.address >= b.address && f.end() <= b.address as u32 + b.count as u32
+                    })
+                })
+                .collect();
+        uncovered.sort_by_key(|f| f.address);
+        let mut current: Option<(u16, u32)> = None;
+        for field in uncovered {
+            current = match current {
+                Some((start, end))
+                    if field.address as u32 <= end
+                        && field.end().max(end) - start as u32 <= MAX_REGISTERS_PER_READ as u32 =>
+                {
+                    Some((start, field.end().max(end)))
+                }
+                Some((start, end)) => {
+                    plan.push(Block {
+                        address: start,
+                        count: (end - start as u32) as u16,
+                    });
+                    Some((field.address, field.end()))
+                }
+                None => Some((field.address, field.end())),
+            };
+        }
+        if let Some((start, end)) = current {
+            plan.push(Block {
+                address: start,
+                count: (end - start as u32) as u16,
+            });
+        }
+        plan
+    }
+
+    /// Read all blocks from the device and decode the fields into a
+    /// JSON object. An `update` timestamp is added unless the map
+    /// defines a field of that name.
+    pub fn read(&self, client: &mut dyn Client) -> Result<Value> {
+        let mut blocks = Vec::new();
+        for block in self.read_plan() {
+            let regs = match self.registers {
+                RegisterKind::Holding => client.read_holding_registers(block.address, block.count),
+                RegisterKind::Input => client.read_input_registers(block.address, block.count),
+            }
+            .map_err(modbus_error)?;
+            if regs.len() < block.count as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Short read at register {}", block.address),
+                ));
+            }
+            blocks.push((block, regs));
+        }
+        let mut record = Value::Object(Map::new());
+        record["update"] = Value::from(epoch_secs()?);
+        for field in &self.fields {
+            let (block, regs) = blocks
+                .iter()
+                .find(|(b, _)| {
+                    field.address >= b.address && field.end() <= b.address as u32 + b.count as u32
+                })
+                .expect("Every field is covered by the read plan");
+            let offset = (field.address - block.address) as usize;
+            let value = field.decode(&regs[offset..offset + field.register_count() as usize])?;
+            insert(&mut record, &field.name, value)?;
+        }
+        Ok(record)
+    }
+
+    /// Read the device and convert the record into a typed reading
+    pub fn read_as<T: DeserializeOwned>(&self, client: &mut dyn Client) -> Result<T> {
+        serde_json::from_value(self.read(client)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// The inverse of `read`: encode a record into the registers a
+    /// device would hold. Fields missing from the record are skipped.
+    pub fn encode(&self, record: &Value, registers: &mut BTreeMap<u16, u16>) -> Result<()> {
+        for field in &self.fields {
+            let Some(value) = lookup(record, &field.name) else {
+                continue;
+            };
+            let mut regs: Vec<u16> = (field.address..)
+                .take(field.register_count() as usize)
+                .map(|a| registers.get(&a).copied().unwrap_or(0))
+                .collect();
+            field.encode(value, &mut regs)?;
+            for (address, reg) in (field.address..).zip(regs) {
+                registers.insert(address, reg);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Look up a value in a JSON object at a path like `a.b[2].c`
+fn lookup<'a>(record: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut node = record;
+    for segment in path.split('.') {
+        node = match segment.find('[') {
+            Some(pos) => {
+                let index = segment[pos + 1..]
+                    .strip_suffix(']')?
+                    .parse::<usize>()
+                    .ok()?;
+                node.get(&segment[..pos])?.get(index)?
+            }
+            None => node.get(segment)?,
+        };
+    }
+    Some(node)
+}
+
+/// Insert a value into a JSON object at a path like `a.b[2].c`
+fn insert(record: &mut Value, path: &str, value: Value) -> Result<()> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid field name {}", path),
+        )
+    };
+    let mut node = record;
+    let segments: Vec<&str> = path.split('.').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let (key, index) = match segment.find('[') {
+            Some(pos) => {
+                let index = segment[pos + 1..]
+                    .strip_suffix(']')
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .ok_or_else(invalid)?;
+                (&segment[..pos], Some(index))
+            }
+            None => (*segment, None),
+        };
+        let last = i + 1 == segments.len();
+        let object = node.as_object_mut().ok_or_else(invalid)?;
+        node = match index {
+            None if last => {
+                object.insert(String::from(key), value);
+                return Ok(());
+            }
+            None => object
+                .entry(key)
+                .or_insert_with(|| Value::Object(Map::new())),
+            Some(index) => {
+                let array = object
+                    .entry(key)
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                    .as_array_mut()
+                    .ok_or_else(invalid)?;
+                if array.len() <= index {
+                    array.resize(index + 1, Value::Null);
+                }
+                if last {
+                    array[index] = value;
+                    return Ok(());
+                }
+                if array[index].is_null() {
+                    array[index] = Value::Object(Map::new());
+                }
+                &mut array[index]
+            }
+        };
+    }
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus_server::{serve, RegisterBank};
+    use modbus::{tcp, ExceptionCode};
+    use serde::Serialize;
+    use std::sync::Arc;
+
+    /// A register dump, unset registers read as 0
+    struct Dump(BTreeMap<u16, u16>);
+
+    impl RegisterBank for Dump {
+        fn read(&self, address: u16, count: u16) -> std::result::Result<Vec<u16>, ExceptionCode> {
+            Ok((address..address + count)
+                .map(|a| self.0.get(&a).copied().unwrap_or(0))
+                .collect())
+        }
+
+        fn write(&self, _: u16, _: &[u16]) -> std::result::Result<(), ExceptionCode> {
+            Err(ExceptionCode::IllegalFunction)
+        }
+    }
+
+    fn field(field_type: FieldType, scale: Option<f64>) -> Field {
+        Field {
+            name: String::from("value"),
+            address: 0,
+            field_type,
+            count: None,
+            scale,
+            byte: 0,
+            bits: Vec::new(),
+            optional: false,
+        }
+    }
+
+    /// A dump of the registers the map reads: letters for strings and
+    /// values with the sign bit set for numbers, so that a mixed up word
+    /// order or sign shows
+    fn dump(map: &RegisterMap) -> BTreeMap<u16, u16> {
+        let mut registers = BTreeMap::new();
+        for block in map.read_plan() {
+            for address in block.address..block.address + block.count {
+                registers.insert(address, 0xc000 | address.wrapping_mul(0x9e37) >> 4);
+            }
+        }
+        for field in &map.fields {
+            match field.field_type {
+                FieldType::String => {
+                    for address in field.address as u32..field.end() {
+                        let letter = |i: u32| b'A' as u16 + (i % 26) as u16;
+                        registers
+                            .insert(address as u16, letter(address) << 8 | letter(address + 7));
+                    }
+                }
+                // Only the named bits
+                FieldType::Bits => {
+                    let mask = (1 << field.bits.len()) - 1;
+                    registers.entry(field.address).and_modify(|r| *r &= mask);
+                }
+                _ => (),
+            }
+        }
+        registers
+    }
+
+    /// Decode a dump with the built-in decoder and the bundled register
+    /// map, and check that the map encodes the reading into the dump
+    fn compare<T: Serialize + DeserializeOwned>(
+        device: &str,
+        decode: fn(&mut dyn Client) -> Result<T>,
+    ) {
+        let map = RegisterMap::builtin(device).unwrap();
+        let registers = dump(&map);
+        let bank = Arc::new(Dump(registers.clone()));
+        let port = serve(device, "127.0.0.1:0", bank).unwrap().port();
+        let cfg = tcp::Config {
+            tcp_port: port,
+            ..Default::default()
+        };
+        let mut client = tcp::Transport::new_with_cfg("127.0.0.1", cfg).unwrap();
+
+        let mut builtin = serde_json::to_value(decode(&mut client).unwrap()).unwrap();
+        let mut mapped = serde_json::to_value(map.read_as::<T>(&mut client).unwrap()).unwrap();
+        builtin["update"] = Value::Null;
+        mapped["update"] = Value::Null;
+        assert_eq!(mapped, builtin, "{}", device);
+
+        let mut encoded = BTreeMap::new();
+        map.encode(&map.read(&mut client).unwrap(), &mut encoded)
+            .unwrap();
+        for (address, register) in encoded {
+            assert_eq!(register, registers[&address], "{} at {}", device, address);
+        }
+    }
+
+    #[test]
+    fn bundled_maps_match_builtin_decoders() {
+        compare("e3dc", crate::e3dc::decode);
+        compare("pac2200", crate::pac2200::decode);
+        compare("dctr", crate::dctr::decode);
+        compare("mennekes", crate::mennekes::decode);
+    }
+
+    #[test]
+    fn read_plan_merges_adjacent_fields() {
+        let map = RegisterMap::parse(
+            r#"
+            [[block]]
+            address = 100
+            count = 4
+
+            [[field]]
+            name = "covered"
+            address = 102
+            type = "u32"
+
+            [[field]]
+            name = "b"
+            address = 12
+            type = "u16"
+
+            [[field]]
+            name = "a"
+            address = 10
+            type = "u32"
+
+            [[field]]
+            name = "gap"
+            address = 15
+            type = "u16"
+
+            [[field]]
+            name = "long"
+            address = 20
+            type = "string"
+            count = 125
+
+            [[field]]
+            name = "beyond_limit"
+            address = 145
+            type = "u16"
+            "#,
+            "test",
+        )
+        .unwrap();
+        let plan: Vec<(u16, u16)> = map
+            .read_plan()
+            .iter()
+            .map(|b| (b.address, b.count))
+            .collect();
+        // Gaps and the read limit start a new block
+        assert_eq!(plan, vec![(100, 4), (10, 3), (15, 1), (20, 125), (145, 1)]);
+    }
+
+    #[test]
+    fn rejects_fields_beyond_their_registers() {
+        let parse = |field: &str| {
+            RegisterMap::parse(&format!("[[field]]\nname = \"f\"\n{}", field), "test")
+                .map(|_| ())
+                .map_err(|e| e.kind())
+        };
+        assert_eq!(parse("address = 65535\ntype = \"u16\""), Ok(()));
+        assert_eq!(
+            parse("address = 65535\ntype = \"u32\""),
+            Err(ErrorKind::InvalidData)
+        );
+        let bits = |n: usize| {
+            let names: Vec<String> = (0..n).map(|i| format!("\"b{}\"", i)).collect();
+            parse(&format!(
+                "address = 0\ntype = \"bits\"\nbits = [{}]",
+                names.join(", ")
+            ))
+        };
+        assert_eq!(bits(16), Ok(()));
+        assert_eq!(bits(17), Err(ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn decodes_scale_sign_and_word_order() {
+        let decode = |field: Field, regs: &[u16]| field.decode(regs).unwrap();
+        assert_eq!(decode(field(FieldType::I16, None), &[0xfffe]), -2);
+        assert_eq!(decode(field(FieldType::U16, None), &[0xfffe]), 65534);
+        assert_eq!(decode(field(FieldType::I32, None), &[0xffff, 0xfff6]), -10);
+        // The low word comes first
+        assert_eq!(
+            decode(field(FieldType::I32BigLittle, None), &[0xfff6, 0xffff]),
+            -10
+        );
+        assert_eq!(
+            decode(field(FieldType::U32, None), &[0x0001, 0x0002]),
+            65538
+        );
+        assert_eq!(decode(field(FieldType::I16, Some(0.1)), &[0xfff6]), -1.0);
+        assert_eq!(
+            decode(field(FieldType::F32, None), &[0x4049, 0x0fdb]),
+            std::f32::consts::PI as f64
+        );
+        let low_byte = Field {
+            byte: 1,
+            ..field(FieldType::U8, None)
+        };
+        assert_eq!(decode(low_byte, &[0x1234]), 0x34);
+    }
+
+    #[test]
+    fn encodes_the_inverse_of_decoding() {
+        let cases = [
+            (field(FieldType::I16, Some(0.1)), vec![0xfff6]),
+            (field(FieldType::I32BigLittle, None), vec![0xfff6, 0xffff]),
+            (field(FieldType::U32, Some(10.0)), vec![0x0001, 0x0002]),
+            (field(FieldType::F32, None), vec![0x4049, 0x0fdb]),
+        ];
+        for (field, regs) in cases {
+            let mut encoded = vec![0; regs.len()];
+            field
+                .encode(&field.decode(&regs).unwrap(), &mut encoded)
+                .unwrap();
+            assert_eq!(encoded, regs, "{:?}", field.field_type);
+        }
+        // A u8 field only replaces its byte
+        let high_byte = field(FieldType::U8, None);
+        let mut regs = [0x1234];
+        high_byte.encode(&Value::from(0xab), &mut regs).unwrap();
+        assert_eq!(regs, [0xab34]);
+    }
+}
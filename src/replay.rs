@@ -0,0 +1,334 @@
+use crate::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::path::Path;
+use wallbox::charger::ChargerParams;
+use wallbox::config::Config;
+use wallbox::controller::{Controller, ControllerSettings, CurrSettings, Inputs, SessionChange};
+use wallbox::e3dc::E3DCParams;
+use wallbox::forecast::Forecast;
+use wallbox::grid::GridParams;
+use wallbox::mennekes::MennekesParams;
+use wallbox::pv_source::PvParams;
+use wallbox::schedule::local_time;
+use wallbox::smoothing::Smoothing;
+use wallbox::tariff::Tariff;
+
+/// Records further apart than this many seconds are considered a gap
+/// in the recording; no energy is accounted for in between
+const MAX_RECORD_GAP: u64 = 60;
+
+/// One line of a recording of the wallbox manager's status socket.
+/// Older recordings only contain the raw E3DC and Mennekes readings.
+#[derive(Deserialize)]
+struct Record {
+    e3dc: Option<E3DCParams>,
+    pv: Option<PvParams>,
+    mennekes: Option<MennekesParams>,
+    charger: Option<ChargerParams>,
+    grid: Option<GridParams>,
+}
+
+impl Record {
+    fn params(&self) -> Option<(PvParams, ChargerParams, Option<GridParams>)> {
+        let pv = self
+            .pv
+            .clone()
+            .or_else(|| self.e3dc.as_ref().map(PvParams::from))?;
+        let charger = self
+            .charger
+            .clone()
+            .or_else(|| self.mennekes.as_ref().map(ChargerParams::from))?;
+        Some((pv, charger, self.grid.clone()))
+    }
+}
+
+/// A setpoint the controller would have signalled
+#[derive(Serialize)]
+struct Setpoint<'a> {
+    time: u64,
+    amps: u16,
+    phases: u16,
+    reason: &'a str,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    records: u64,
+    /// Seconds covered by the recording, without gaps
+    duration: u64,
+    setpoint_changes: u64,
+    phase_switches: u64,
+    /// Energy the vehicle would have charged, in Wh
+    charged_energy: f64,
+    /// Part of the charged energy covered by PV production, in Wh
+    pv_energy: f64,
+    /// Part of the charged energy drawn from the grid, in Wh
+    grid_energy: f64,
+}
+
+/// Feeds recorded readings through the controller. The vehicle is
+/// assumed to draw the signalled current, unless it didn't draw any
+/// power in the recording although current was signalled to it, e.g.
+/// because its battery was full. The home battery is assumed to behave
+/// as recorded, so the vehicle's additional or reduced consumption is
+/// exchanged with the grid.
+struct Replay {
+    controller: Controller,
+    config: Config,
+    tariff: Option<Tariff>,
+    forecast: Option<Forecast>,
+    smoothing: Option<Smoothing>,
+    session: CurrSettings,
+    amps: Option<u16>,
+    next_evaluation: u64,
+    /// Time, charging power and PV surplus of the previous record
+    previous: Option<(u64, f64, f64)>,
+    session_energy: f64,
+    summary: Summary,
+}
+
+impl Replay {
+    fn new(config: Config) -> Replay {
+        Replay {
+            controller: Controller::new(ControllerSettings::from(&config)),
+            tariff: config.tariff.as_ref().map(|t| Tariff::new(&t.file)),
+            forecast: config.forecast.as_ref().map(Forecast::from),
+            smoothing: config.smoothing.as_ref().map(Smoothing::from),
+            config,
+            session: CurrSettings::default(),
+            amps: None,
+            next_evaluation: 0,
+            previous: None,
+            session_energy: 0.0,
+            summary: Summary::default(),
+        }
+    }
+
+    /// Feed one record. A recorded grid reading is used instead of the
+    /// PV system's, as the wallbox manager would, but only its total
+    /// power is adjusted to the simulated charging.
+    fn feed(
+        &mut self,
+        mut pv: PvParams,
+        mut charger: ChargerParams,
+        grid: Option<GridParams>,
+    ) -> Result<()> {
+        let time = pv.update;
+        self.summary.records += 1;
+        if !charger.connected {
+            self.session_energy = 0.0;
+        }
+
+        // Replace the recorded charging by the one the controller
+        // would have caused
+        let amps = *self.amps.get_or_insert(charger.current_setpoint);
+        let vehicle_refuses = charger.current_setpoint > 0 && charger.power == 0;
+        let step_power = self.config.phase_voltage as f64 * self.controller.phases() as f64;
+        let power = if charger.connected && !vehicle_refuses {
+            amps as f64 * step_power
+        } else {
+            0.0
+        };
+        let house_without_charger = pv.house_power as f64 - charger.power as f64;
+        let surplus = (pv.pv_power as f64 - house_without_charger).max(0.0);
+        pv.house_power = (house_without_charger + power) as i32;
+        let additional_power = (power - charger.power as f64) as i32;
+        pv.grid_power += additional_power;
+        let grid = match grid {
+            Some(grid) => GridParams {
+                power: grid.power + additional_power,
+                phase_power: None,
+                phase_current: None,
+                ..grid
+            },
+            None => GridParams::from(&pv),
+        };
+        charger.current_setpoint = amps;
+        charger.power = power as u32;
+        charger.phase_current = None;
+        charger.session_energy = self.session_energy as u32;
+
+        if let Some((previous_time, previous_power, previous_surplus)) = self.previous {
+            let seconds = time.saturating_sub(previous_time);
+            if seconds <= MAX_RECORD_GAP {
+                let hours = seconds as f64 / 3600.0;
+                let from_pv = previous_power.min(previous_surplus);
+                self.summary.duration += seconds;
+                self.summary.charged_energy += previous_power * hours;
+                self.summary.pv_energy += from_pv * hours;
+                self.summary.grid_energy += (previous_power - from_pv) * hours;
+                self.session_energy += previous_power * hours;
+            }
+        }
+        self.previous = Some((time, power, surplus));
+
+        // Every record is a sample, as the readings are recorded about
+        // as often as the wallbox manager samples them
+        let (pv, grid) = match self.smoothing.as_mut() {
+            Some(smoothing) => {
+                let charging = charger.power as i32;
+                smoothing.add_pv(&pv, charging);
+                smoothing.add_grid(&grid, charging);
+                (smoothing.pv(&pv, charging), smoothing.grid(&grid, charging))
+            }
+            None => (pv, grid),
+        };
+        if time < self.next_evaluation {
+            return Ok(());
+        }
+        let user_id = charger.user_id.as_deref().unwrap_or("");
+        let decision = self.controller.decide(&Inputs {
+            pv: &pv,
+            charger: &charger,
+            grid: Some(&grid),
+            rfid: self.config.rfid_for(user_id),
+            session: &self.session,
+            now: time,
+            local_time: local_time(time),
+            tariff: self.tariff.as_ref(),
+            forecast: self.forecast.as_ref(),
+            budget: None,
+        });
+        match decision.session {
+            Some(SessionChange::Connected {
+                max_session_energy, ..
+            }) => self.session.max_session_energy = max_session_energy,
+            Some(SessionChange::Disconnected(_)) => self.session.max_session_energy = None,
+            None => (),
+        }
+        let amps = decision.amps.filter(|a| Some(*a) != self.amps);
+        if let Some(amps) = amps {
+            self.amps = Some(amps);
+            self.summary.setpoint_changes += 1;
+        }
+        if decision.phases.is_some() {
+            self.summary.phase_switches += 1;
+        }
+        if amps.is_some() || decision.phases.is_some() {
+            let setpoint = Setpoint {
+                time,
+                amps: self.amps.unwrap_or_default(),
+                phases: self.controller.phases(),
+                reason: &decision.reason,
+            };
+            println!("{}", serde_json::to_string(&setpoint).expect("serde_json"));
+        }
+        self.next_evaluation = decision.next_evaluation;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = if path.extension().is_some_and(|e| e == "gz") {
+        Box::new(flate2::read::MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+pub fn replay(rp: ReplayParams) -> Result<()> {
+    let config = Config::from_file(&rp.config_path)?;
+    let mut replay = Replay::new(config);
+    if let Some(tariff) = replay.tariff.as_mut() {
+        tariff.refresh()?;
+    }
+    if let Some(forecast) = replay.forecast.as_mut() {
+        forecast.refresh()?;
+    }
+    for path in &rp.file_name {
+        let mut line = String::new();
+        let mut reader = open(path)?;
+        loop {
+            line.truncate(0);
+            match reader.read_line(&mut line) {
+                // Recordings may end in an incomplete gzip stream
+                Err(e) => {
+                    eprintln!("{:?}: stopping at read error: {}", path, e);
+                    break;
+                }
+                Ok(0) => break,
+                Ok(_) => (),
+            }
+            match serde_json::from_str::<Record>(&line).map(|r| r.params()) {
+                Ok(Some((pv, charger, grid))) => replay.feed(pv, charger, grid)?,
+                Ok(None) => eprintln!("{:?}: skipping incomplete record", path),
+                Err(e) => eprintln!("{:?}: skipping invalid record: {}", path, e),
+            }
+        }
+    }
+    if replay.summary.records == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "No records to replay"));
+    }
+    println!(
+        "{}",
+        serde_json::to_string(&replay.summary).expect("serde_json")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        initial_connection_timeout = 60
+        wallbox = { host = "localhost" }
+        phases = "ThreePhase"
+        phase_voltage = 230
+        default_amps = 8
+        initial_phase_duration = 180
+        hysteresis_watts = 200
+
+        [rfid.CAR]
+        name = "Car"
+        pv_only = true
+        min_amp = 6
+        max_amp = 16
+    "#;
+
+    fn record(time: u64, pv_power: i32) -> (PvParams, ChargerParams) {
+        let charging = 8 * 690;
+        let pv = PvParams {
+            update: time,
+            pv_power,
+            house_power: 500 + charging,
+            grid_power: 500 + charging - pv_power,
+            battery_power: 0,
+            battery_soc: 100,
+            island: false,
+        };
+        let charger = ChargerParams {
+            update: time,
+            connected: true,
+            session_energy: 0,
+            session_duration: time as u32,
+            user_id: Some(String::from("car")),
+            current_setpoint: 8,
+            power: charging as u32,
+            phase_current: None,
+        };
+        (pv, charger)
+    }
+
+    #[test]
+    fn simulated_charging_replaces_the_recorded_one() {
+        let mut replay = Replay::new(toml::from_str(CONFIG).unwrap());
+        // Plenty of sun for the initial phase and a bit beyond, then clouds
+        for time in (0..600).step_by(10) {
+            let (pv, charger) = record(time, if time < 300 { 9000 } else { 1000 });
+            replay.feed(pv, charger, None).unwrap();
+        }
+
+        let summary = &replay.summary;
+        assert_eq!(summary.records, 60);
+        assert_eq!(summary.duration, 590);
+        assert!(summary.setpoint_changes >= 2);
+        assert_eq!(replay.amps, Some(0));
+        assert!(summary.pv_energy > 0.0);
+        assert!(summary.grid_energy > 0.0);
+        assert!((summary.charged_energy - summary.pv_energy - summary.grid_energy).abs() < 0.001);
+    }
+}
@@ -129,9 +129,18 @@ pub fn residual_current_monitor(rcm: ResidualCurrentMonitorParams) -> Result<()>
             format!("A flushing interval smaller than ten seconds is too low"),
         ));
     }
+    wallbox::init_stderr_logging()?;
+
     let polling_interval = rcm.polling_interval.unwrap_or(1000);
     let polling_interval = Duration::from_millis(polling_interval);
-    let dctr = Dctr::new(&rcm.host_name, rcm.port.unwrap_or(502), polling_interval)?;
+    let connection =
+        rcm.serial
+            .connection(rcm.host_name.clone(), rcm.port, rcm.register_map.clone());
+    let dctr = Dctr::new(
+        connection.connector()?,
+        polling_interval,
+        connection.load_register_map()?,
+    )?;
 
     let bind_to = rcm.bind_to.unwrap_or(String::from("localhost:2317"));
     let listener = std::net::TcpListener::bind(bind_to)?;
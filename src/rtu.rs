@@ -0,0 +1,367 @@
+use crate::poller::{Connector, ModbusClient};
+use log::debug;
+use modbus::{binary, Client, Coil, ExceptionCode, Reason};
+use serialport::{ClearBuffer, SerialPort};
+use std::io::{Error, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Timeout for a device's response to arrive
+const RTU_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Parity setting of a serial line
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl From<Parity> for serialport::Parity {
+    fn from(p: Parity) -> Self {
+        match p {
+            Parity::None => serialport::Parity::None,
+            Parity::Even => serialport::Parity::Even,
+            Parity::Odd => serialport::Parity::Odd,
+        }
+    }
+}
+
+/// Connects to a device on an RS-485 bus via Modbus RTU
+pub struct RtuConnector {
+    pub serial_port: PathBuf,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub slave_id: u8,
+}
+
+impl Connector for RtuConnector {
+    fn connect(&self) -> std::io::Result<ModbusClient> {
+        let port = serialport::new(self.serial_port.to_string_lossy(), self.baud_rate)
+            .parity(self.parity.into())
+            .data_bits(serialport::DataBits::Eight)
+            .stop_bits(match self.parity {
+                // Modbus RTU requires 11 bits per character
+                Parity::None => serialport::StopBits::Two,
+                _ => serialport::StopBits::One,
+            })
+            .timeout(RTU_TIMEOUT)
+            .open()?;
+        Ok(Box::new(RtuTransport::new(
+            port,
+            self.baud_rate,
+            self.slave_id,
+        )))
+    }
+}
+
+/// A Modbus RTU client talking to one slave on a serial line
+pub struct RtuTransport {
+    port: Box<dyn SerialPort>,
+    slave_id: u8,
+    /// Silent interval that separates two frames
+    frame_gap: Duration,
+}
+
+impl RtuTransport {
+    pub fn new(port: Box<dyn SerialPort>, baud_rate: u32, slave_id: u8) -> RtuTransport {
+        // 3.5 characters of 11 bits each, but at least 1.75ms as
+        // recommended for baud rates above 19200
+        let frame_gap = Duration::from_micros(std::cmp::max(
+            38_500_000 / std::cmp::max(baud_rate, 1) as u64,
+            1750,
+        ));
+        RtuTransport {
+            port,
+            slave_id,
+            frame_gap,
+        }
+    }
+
+    /// Send a request PDU and return the response PDU
+    fn transact(&mut self, pdu: &[u8]) -> modbus::Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(pdu.len() + 3);
+        frame.push(self.slave_id);
+        frame.extend_from_slice(pdu);
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_le_bytes());
+
+        std::thread::sleep(self.frame_gap);
+        self.port.clear(ClearBuffer::Input).map_err(Error::from)?;
+        self.port.write_all(&frame)?;
+        self.port.flush()?;
+
+        let mut response = vec![0u8; 2];
+        self.port.read_exact(&mut response)?;
+        let function = response[1];
+        let remaining = if function & 0x80 != 0 {
+            // exception code plus CRC
+            3
+        } else {
+            match function {
+                0x01..=0x04 | 0x17 => {
+                    let mut byte_count = [0u8; 1];
+                    self.port.read_exact(&mut byte_count)?;
+                    response.push(byte_count[0]);
+                    byte_count[0] as usize + 2
+                }
+                0x05 | 0x06 | 0x0f | 0x10 => 6,
+                _ => return Err(modbus::Error::InvalidFunction),
+            }
+        };
+        let start = response.len();
+        response.resize(start + remaining, 0);
+        self.port.read_exact(&mut response[start..])?;
+
+        let (body, crc) = response.split_at(response.len() - 2);
+        if crc16(body).to_le_bytes() != crc {
+            debug!("CRC mismatch in response {:02x?}", response);
+            return Err(modbus::Error::InvalidResponse);
+        }
+        if body[0] != self.slave_id || body[1] & 0x7f != pdu[0] {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        if body[1] & 0x80 != 0 {
+            return Err(modbus::Error::Exception(exception_code(body[2])));
+        }
+        Ok(body[1..].to_vec())
+    }
+
+    fn read_bits(
+        &mut self,
+        function: u8,
+        address: u16,
+        quantity: u16,
+    ) -> modbus::Result<Vec<Coil>> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&quantity.to_be_bytes());
+        let response = self.transact(&pdu)?;
+        if response.len() < 2 || (response[1] as usize) < (quantity as usize).div_ceil(8) {
+            return Err(modbus::Error::InvalidData(Reason::UnexpectedReplySize));
+        }
+        Ok(binary::unpack_bits(&response[2..], quantity))
+    }
+
+    fn read_registers(
+        &mut self,
+        function: u8,
+        address: u16,
+        quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&quantity.to_be_bytes());
+        registers_from_response(&self.transact(&pdu)?, quantity)
+    }
+}
+
+fn registers_from_response(response: &[u8], quantity: u16) -> modbus::Result<Vec<u16>> {
+    if response.len() < 2 || response[1] as usize != quantity as usize * 2 {
+        return Err(modbus::Error::InvalidData(Reason::UnexpectedReplySize));
+    }
+    Ok(response[2..]
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect())
+}
+
+impl Client for RtuTransport {
+    fn read_discrete_inputs(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<Coil>> {
+        self.read_bits(0x02, address, quantity)
+    }
+
+    fn read_coils(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<Coil>> {
+        self.read_bits(0x01, address, quantity)
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: Coil) -> modbus::Result<()> {
+        let mut pdu = vec![0x05];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(match value {
+            Coil::On => &[0xff, 0x00],
+            Coil::Off => &[0x00, 0x00],
+        });
+        self.transact(&pdu).map(|_| ())
+    }
+
+    fn write_multiple_coils(&mut self, address: u16, coils: &[Coil]) -> modbus::Result<()> {
+        let bytes = binary::pack_bits(coils);
+        let mut pdu = vec![0x0f];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&(coils.len() as u16).to_be_bytes());
+        pdu.push(bytes.len() as u8);
+        pdu.extend_from_slice(&bytes);
+        self.transact(&pdu).map(|_| ())
+    }
+
+    fn read_input_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        self.read_registers(0x04, address, quantity)
+    }
+
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        self.read_registers(0x03, address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        let mut pdu = vec![0x06];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+        self.transact(&pdu).map(|_| ())
+    }
+
+    fn write_multiple_registers(&mut self, address: u16, values: &[u16]) -> modbus::Result<()> {
+        let mut pdu = vec![0x10];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push((values.len() * 2) as u8);
+        pdu.extend_from_slice(&binary::unpack_bytes(values));
+        self.transact(&pdu).map(|_| ())
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        write_quantity: u16,
+        write_values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        let mut pdu = vec![0x17];
+        pdu.extend_from_slice(&read_address.to_be_bytes());
+        pdu.extend_from_slice(&read_quantity.to_be_bytes());
+        pdu.extend_from_slice(&write_address.to_be_bytes());
+        pdu.extend_from_slice(&write_quantity.to_be_bytes());
+        pdu.push((write_values.len() * 2) as u8);
+        pdu.extend_from_slice(&binary::unpack_bytes(write_values));
+        registers_from_response(&self.transact(&pdu)?, read_quantity)
+    }
+
+    fn set_uid(&mut self, uid: u8) {
+        self.slave_id = uid;
+    }
+}
+
+fn exception_code(code: u8) -> ExceptionCode {
+    match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::SlaveOrServerFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::SlaveOrServerBusy,
+        0x07 => ExceptionCode::NegativeAcknowledge,
+        0x08 => ExceptionCode::MemoryParity,
+        0x0a => ExceptionCode::GatewayPath,
+        0x0b => ExceptionCode::GatewayTarget,
+        _ => ExceptionCode::NotDefined,
+    }
+}
+
+/// The Modbus CRC-16 of a frame
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::TTYPort;
+    use std::thread::JoinHandle;
+
+    /// Emulate a slave with id 7 on the master side of a pty pair,
+    /// answering the given number of requests. The port is handed
+    /// back so it isn't hung up before the last response was read.
+    fn serve(
+        mut port: TTYPort,
+        mut registers: Vec<u16>,
+        requests: usize,
+    ) -> JoinHandle<(Vec<u16>, TTYPort)> {
+        port.set_timeout(Duration::from_secs(5)).unwrap();
+        std::thread::spawn(move || {
+            for _ in 0..requests {
+                let mut request = vec![0u8; 8];
+                port.read_exact(&mut request).unwrap();
+                if request[1] == 0x10 {
+                    let mut rest = vec![0u8; request[6] as usize + 1];
+                    port.read_exact(&mut rest).unwrap();
+                    request.extend_from_slice(&rest);
+                }
+                let (body, crc) = request.split_at(request.len() - 2);
+                assert_eq!(crc16(body).to_le_bytes(), crc);
+                let address = u16::from_be_bytes([body[2], body[3]]) as usize;
+                let value = u16::from_be_bytes([body[4], body[5]]);
+                let mut response = vec![body[0], body[1]];
+                match body[1] {
+                    0x03 if address + value as usize > registers.len() => {
+                        response[1] |= 0x80;
+                        response.push(0x02);
+                    }
+                    0x03 => {
+                        response.push((value * 2) as u8);
+                        for r in &registers[address..address + value as usize] {
+                            response.extend_from_slice(&r.to_be_bytes());
+                        }
+                    }
+                    0x06 => {
+                        registers[address] = value;
+                        response.extend_from_slice(&body[2..6]);
+                    }
+                    0x10 => {
+                        for (i, r) in body[7..].chunks(2).enumerate() {
+                            registers[address + i] = u16::from_be_bytes([r[0], r[1]]);
+                        }
+                        response.extend_from_slice(&body[2..6]);
+                    }
+                    _ => panic!("Unexpected function {}", body[1]),
+                }
+                let crc = crc16(&response);
+                response.extend_from_slice(&crc.to_le_bytes());
+                port.write_all(&response).unwrap();
+            }
+            (registers, port)
+        })
+    }
+
+    #[test]
+    fn crc_of_known_frame() {
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0a]), 0xcdc5);
+    }
+
+    #[test]
+    fn read_and_write_registers_via_pty() {
+        let (master, slave) = TTYPort::pair().expect("pty pair");
+        let connector = RtuConnector {
+            serial_port: PathBuf::from(slave.name().expect("pty name")),
+            baud_rate: 19200,
+            parity: Parity::Even,
+            slave_id: 7,
+        };
+        let emulator = serve(master, (0..16).collect(), 4);
+        let mut client = connector.connect().expect("open pty");
+        assert_eq!(
+            client.read_holding_registers(2, 3).unwrap(),
+            vec![2u16, 3, 4]
+        );
+        client.write_single_register(5, 500).unwrap();
+        client.write_multiple_registers(10, &[1, 2]).unwrap();
+        assert!(matches!(
+            client.read_holding_registers(15, 2),
+            Err(modbus::Error::Exception(ExceptionCode::IllegalDataAddress))
+        ));
+        let (registers, _port) = emulator.join().unwrap();
+        assert_eq!(registers[5], 500);
+        assert_eq!(&registers[10..12], &[1, 2]);
+    }
+}
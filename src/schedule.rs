@@ -0,0 +1,344 @@
+use crate::config::{ConfigRfid, Departure, PricePolicy, ScheduleWindow};
+use crate::controller::Inputs;
+use chrono::{Datelike, Days, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+/// Seconds to be done charging ahead of a departure, as vehicles
+/// usually charge slower than at full power towards the end
+const DEPARTURE_RESERVE: u64 = 1800;
+
+/// How a vehicle is charged during its session, as derived from its
+/// schedule and departure times
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    /// Whether the vehicle charges from PV only at the moment, as set
+    /// by its schedule unless it has to charge from the grid
+    pub pv_only: bool,
+    /// The next departure, in seconds since the UNIX epoch
+    pub departure: Option<u64>,
+    /// Energy still to charge by the departure, in Watthours
+    pub remaining_energy: u32,
+    /// When charging at full power from the grid starts, unless the
+    /// PV surplus covered the remaining energy by then
+    pub grid_start: Option<u64>,
+    /// When the remaining energy is expected to have been charged
+    pub expected_completion: Option<u64>,
+    /// Energy the PV surplus is expected to charge by the departure
+    /// according to the forecast, in Watthours
+    pub expected_pv_energy: Option<u32>,
+    /// The current price of the tariff in €/kWh, if known
+    pub price: Option<f64>,
+    /// Whether the vehicle charges from the grid for the price
+    pub cheap: bool,
+}
+
+impl Plan {
+    /// Whether the vehicle has to charge at full power to make it
+    pub fn departure_due(&self, now: u64) -> bool {
+        self.grid_start.is_some_and(|start| start <= now)
+    }
+
+    /// Whether the vehicle charges at full power, from the grid if need be
+    pub fn charge_from_grid(&self, now: u64) -> bool {
+        self.departure_due(now) || self.cheap
+    }
+}
+
+/// The local time at the given number of seconds since the UNIX epoch
+pub fn local_time(now: u64) -> NaiveDateTime {
+    Local
+        .timestamp_opt(now as i64, 0)
+        .single()
+        .map(|t| t.naive_local())
+        .unwrap_or_default()
+}
+
+fn on_day(days: &[crate::config::Weekday], date: NaiveDate) -> bool {
+    days.is_empty() || days.iter().any(|d| d.0 == date.weekday())
+}
+
+impl ScheduleWindow {
+    pub fn contains(&self, local: NaiveDateTime) -> bool {
+        let (from, to, time) = (self.from.0, self.to.0, local.time());
+        let date = local.date();
+        if from <= to {
+            on_day(&self.days, date) && from <= time && time < to
+        } else {
+            // The window spans midnight
+            let started_yesterday = date
+                .pred_opt()
+                .is_some_and(|yesterday| on_day(&self.days, yesterday));
+            (from <= time && on_day(&self.days, date)) || (time < to && started_yesterday)
+        }
+    }
+}
+
+impl Departure {
+    /// The next departure after the given local time, within a week
+    pub fn next(&self, local: NaiveDateTime) -> Option<NaiveDateTime> {
+        (0..=7)
+            .filter_map(|days| local.date().checked_add_days(Days::new(days)))
+            .filter(|date| on_day(&self.days, *date))
+            .map(|date| date.and_time(self.time.0))
+            .find(|departure| *departure > local)
+    }
+}
+
+/// Plan the session of a vehicle. Until the next departure, it charges
+/// from PV as configured; charging at max_power from the grid starts
+/// just late enough to have the departure's energy charged in time.
+/// With a forecast, only what the PV surplus is not expected to cover
+/// is charged from the grid. The vehicle also charges from the grid
+/// whenever its price policy deems the tariff's price cheap enough.
+pub fn plan(rfid: &ConfigRfid, inputs: &Inputs, max_power: i32) -> Plan {
+    let (now, local, tariff) = (inputs.now, inputs.local_time, inputs.tariff);
+    let next_departure = rfid
+        .departure
+        .iter()
+        .filter_map(|d| d.next(local).map(|time| (time, d.energy)))
+        .min_by_key(|(time, _)| *time);
+    let departure =
+        next_departure.map(|(time, _)| now + (time - local).num_seconds().max(0) as u64);
+    let remaining_energy = next_departure.map_or(0, |(_, energy)| {
+        energy.saturating_sub(inputs.charger.session_energy)
+    });
+    let expected_pv_energy = departure.and_then(|departure| {
+        let forecast = inputs.forecast?;
+        let house_power = forecast
+            .house_power()
+            .unwrap_or(inputs.pv.house_power - inputs.charger.power as i32);
+        let energy = forecast.surplus_energy(now, departure, house_power.max(0))?;
+        Some(energy as u32)
+    });
+    let shortfall = remaining_energy.saturating_sub(expected_pv_energy.unwrap_or(0));
+    // Seconds it takes to charge the shortfall at full power
+    let duration = (shortfall > 0 && max_power > 0)
+        .then(|| (shortfall as u64 * 3600).div_ceil(max_power as u64));
+    let grid_start = departure
+        .zip(duration)
+        .map(|(departure, duration)| departure.saturating_sub(duration + DEPARTURE_RESERVE));
+    let expected_completion = grid_start
+        .zip(duration)
+        .map(|(grid_start, duration)| grid_start.max(now) + duration);
+
+    let price = tariff.and_then(|t| t.price_at(now));
+    let cheap = match (&rfid.price_policy, tariff, price) {
+        (Some(PricePolicy::Below { max_price }), _, Some(price)) => price < *max_price,
+        (Some(PricePolicy::Cheapest { hours }), Some(tariff), Some(_)) => {
+            let seconds = hours.map(|hours| (hours * 3600.0) as u64).or(duration);
+            let until = departure.unwrap_or(u64::MAX);
+            seconds.is_some_and(|seconds| tariff.is_cheapest(now, until, seconds))
+        }
+        _ => false,
+    };
+
+    let pv_only = rfid
+        .schedule
+        .iter()
+        .find(|window| window.contains(local))
+        .map_or(rfid.pv_only, |window| window.pv_only);
+    let mut plan = Plan {
+        pv_only,
+        departure,
+        remaining_energy,
+        grid_start,
+        expected_completion,
+        expected_pv_energy,
+        price,
+        cheap,
+    };
+    plan.pv_only = pv_only && !plan.charge_from_grid(now);
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charger::ChargerParams;
+    use crate::config::ForecastConfig;
+    use crate::controller::CurrSettings;
+    use crate::forecast::Forecast;
+    use crate::pv_source::PvParams;
+    use crate::tariff::Tariff;
+
+    /// Monday, 2024-01-01 00:00 UTC
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn local(days: u64, hour: u64, minute: u64) -> (u64, NaiveDateTime) {
+        let now = MONDAY + days * 86400 + hour * 3600 + minute * 60;
+        let local = chrono::DateTime::from_timestamp(now as i64, 0)
+            .unwrap()
+            .naive_utc();
+        (now, local)
+    }
+
+    /// Plan with the house consuming 500 Watts
+    fn plan_at(
+        rfid: &ConfigRfid,
+        (now, local_time): (u64, NaiveDateTime),
+        session_energy: u32,
+        max_power: i32,
+        tariff: Option<&Tariff>,
+        forecast: Option<&Forecast>,
+    ) -> Plan {
+        let pv = PvParams {
+            update: now,
+            pv_power: 0,
+            house_power: 500,
+            grid_power: 500,
+            battery_power: 0,
+            battery_soc: 0,
+            island: false,
+        };
+        let charger = ChargerParams {
+            update: now,
+            connected: true,
+            session_energy,
+            session_duration: 3600,
+            user_id: None,
+            current_setpoint: 0,
+            power: 0,
+            phase_current: None,
+        };
+        let inputs = Inputs {
+            pv: &pv,
+            charger: &charger,
+            grid: None,
+            rfid: Some(rfid),
+            session: &CurrSettings::default(),
+            now,
+            local_time,
+            tariff,
+            forecast,
+            budget: None,
+        };
+        plan(rfid, &inputs, max_power)
+    }
+
+    fn rfid() -> ConfigRfid {
+        toml::from_str(
+            r#"
+            name = "Car"
+            pv_only = false
+            min_amp = 6
+            max_amp = 16
+
+            [[schedule]]
+            days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+            from = "08:00"
+            to = "16:00"
+            pv_only = true
+
+            [[schedule]]
+            days = ["Sun"]
+            from = "22:00"
+            to = "06:00"
+            pv_only = true
+
+            [[departure]]
+            days = ["Mon"]
+            time = "07:00"
+            energy = 30000
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn schedule_windows_override_pv_only() {
+        let rfid = rfid();
+        // The departure's energy has been charged already
+        let pv_only = |days, hour, minute| {
+            plan_at(&rfid, local(days, hour, minute), 30000, 11040, None, None).pv_only
+        };
+        assert!(pv_only(0, 8, 0));
+        assert!(pv_only(4, 15, 59));
+        assert!(!pv_only(4, 16, 0));
+        assert!(!pv_only(5, 12, 0));
+        // Sunday night into Monday morning
+        assert!(pv_only(6, 23, 0));
+        assert!(pv_only(0, 5, 59));
+        assert!(!pv_only(1, 5, 0));
+    }
+
+    #[test]
+    fn grid_charging_starts_in_time_for_the_departure() {
+        let rfid = rfid();
+        // Sunday noon, 10kWh charged so far, 20kWh to go at 10kW
+        let now = local(6, 12, 0);
+        let plan = plan_at(&rfid, now, 10000, 10000, None, None);
+        let departure = MONDAY + 7 * 86400 + 7 * 3600;
+        assert_eq!(plan.departure, Some(departure));
+        assert_eq!(plan.remaining_energy, 20000);
+        assert_eq!(plan.grid_start, Some(departure - 2 * 3600 - 1800));
+        assert_eq!(plan.expected_completion, Some(departure - 1800));
+        assert!(!plan.charge_from_grid(now.0));
+        assert!(!plan.charge_from_grid(departure - 3 * 3600));
+        assert!(plan.charge_from_grid(departure - 2 * 3600));
+    }
+
+    #[test]
+    fn price_policies_allow_charging_from_the_grid() {
+        let path = std::env::temp_dir().join(format!("plan-prices-{}.csv", std::process::id()));
+        let (now, local_time) = local(0, 12, 0);
+        let prices = format!("{},0.30\n{},0.10\n", now, now + 3600);
+        std::fs::write(&path, prices).unwrap();
+        let mut tariff = Tariff::new(&path);
+        tariff.refresh().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let below = ConfigRfid {
+            pv_only: true,
+            price_policy: Some(PricePolicy::Below { max_price: 0.2 }),
+            ..rfid()
+        };
+        let plan_at = |rfid: &ConfigRfid, now| {
+            plan_at(rfid, (now, local_time), 0, 11040, Some(&tariff), None)
+        };
+        let expensive = plan_at(&below, now);
+        assert_eq!(expensive.price, Some(0.30));
+        assert!(!expensive.cheap);
+        let cheap = plan_at(&below, now + 3600);
+        assert!(cheap.cheap);
+        assert!(!cheap.pv_only);
+
+        let cheapest = ConfigRfid {
+            price_policy: Some(PricePolicy::Cheapest { hours: Some(1.0) }),
+            ..below
+        };
+        assert!(!plan_at(&cheapest, now).cheap);
+        assert!(plan_at(&cheapest, now + 3600).cheap);
+    }
+
+    #[test]
+    fn reached_target_needs_no_grid_charging() {
+        let plan = plan_at(&rfid(), local(6, 12, 0), 30000, 10000, None, None);
+        assert_eq!(plan.remaining_energy, 0);
+        assert_eq!(plan.grid_start, None);
+        assert_eq!(plan.expected_completion, None);
+    }
+
+    #[test]
+    fn forecast_reduces_grid_charging_to_the_shortfall() {
+        let path = std::env::temp_dir().join(format!("plan-forecast-{}.csv", std::process::id()));
+        // Sunday noon, departure on Monday at 07:00; 4500W are expected
+        // for the next two hours, 4000W of which are surplus
+        let now = local(6, 12, 0);
+        std::fs::write(&path, format!("{},4500\n{},0\n", now.0, now.0 + 7200)).unwrap();
+        let mut forecast = Forecast::from(&ForecastConfig {
+            file: path.clone(),
+            house_power: None,
+        });
+        forecast.refresh().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let plan = plan_at(&rfid(), now, 10000, 10000, None, Some(&forecast));
+        let departure = MONDAY + 7 * 86400 + 7 * 3600;
+        assert_eq!(plan.remaining_energy, 20000);
+        assert_eq!(plan.expected_pv_energy, Some(8000));
+        assert_eq!(plan.grid_start, Some(departure - 4320 - 1800));
+
+        // Enough sun for all of it
+        let plan = plan_at(&rfid(), now, 22000, 10000, None, Some(&forecast));
+        assert_eq!(plan.grid_start, None);
+    }
+}
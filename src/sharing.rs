@@ -0,0 +1,145 @@
+use crate::config::SharingStrategy;
+
+/// What a session can take of a shared budget, in Watts or Amps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Demand {
+    /// Less than this is of no use to the session
+    pub min: i32,
+    pub max: i32,
+    pub priority: u32,
+    /// When the session started, in seconds since the UNIX epoch
+    pub since: u64,
+}
+
+/// Share the total among the sessions by the given strategy. Sessions
+/// that can't get their minimum get nothing, unless only one is left.
+/// What is left once every session got its maximum is split among
+/// them, so a single session gets the total as if there were no
+/// sharing.
+pub fn share(strategy: SharingStrategy, total: i32, demands: &[Demand]) -> Vec<i32> {
+    // The order in which sessions are served, or in which they give up
+    // their share when sharing equally
+    let mut order: Vec<usize> = (0..demands.len()).collect();
+    match strategy {
+        SharingStrategy::Priority => {
+            order.sort_by_key(|&i| (std::cmp::Reverse(demands[i].priority), demands[i].since))
+        }
+        SharingStrategy::Equal | SharingStrategy::FirstCome => {
+            order.sort_by_key(|&i| demands[i].since)
+        }
+    }
+
+    let mut shares = vec![0; demands.len()];
+    let mut left = total;
+    match strategy {
+        SharingStrategy::Equal => {
+            let mut active = order;
+            loop {
+                shares = fill_equally(total, &active, demands);
+                let short = active.iter().any(|&i| shares[i] < demands[i].min);
+                if !short || active.len() <= 1 {
+                    break;
+                }
+                active.pop();
+            }
+            left = total - shares.iter().sum::<i32>();
+        }
+        SharingStrategy::Priority | SharingStrategy::FirstCome => {
+            for &i in &order {
+                let demand = &demands[i];
+                if left >= demand.min || order.len() == 1 {
+                    shares[i] = left.min(demand.max);
+                    left -= shares[i];
+                }
+            }
+        }
+    }
+    if left > 0 {
+        let full: Vec<usize> = (0..demands.len())
+            .filter(|&i| shares[i] >= demands[i].max)
+            .collect();
+        for (served, &i) in full.iter().enumerate() {
+            let extra = left / (full.len() - served) as i32;
+            shares[i] += extra;
+            left -= extra;
+        }
+    }
+    shares
+}
+
+/// Share the total equally among the given sessions, those that can
+/// take the least first, so what they can't take goes to the others
+fn fill_equally(total: i32, active: &[usize], demands: &[Demand]) -> Vec<i32> {
+    let mut shares = vec![0; demands.len()];
+    let mut by_max = active.to_vec();
+    by_max.sort_by_key(|&i| demands[i].max);
+    let mut left = total;
+    for (served, &i) in by_max.iter().enumerate() {
+        let share = left / (by_max.len() - served) as i32;
+        shares[i] = share.min(demands[i].max);
+        left -= shares[i];
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demand(min: i32, max: i32, priority: u32, since: u64) -> Demand {
+        Demand {
+            min,
+            max,
+            priority,
+            since,
+        }
+    }
+
+    #[test]
+    fn single_session_gets_the_total() {
+        let demands = [demand(4140, 11040, 0, 0)];
+        for strategy in [
+            SharingStrategy::Equal,
+            SharingStrategy::Priority,
+            SharingStrategy::FirstCome,
+        ] {
+            assert_eq!(share(strategy, 15000, &demands), vec![15000]);
+            assert_eq!(share(strategy, 2000, &demands), vec![2000]);
+            assert_eq!(share(strategy, -500, &demands), vec![-500]);
+        }
+    }
+
+    #[test]
+    fn equal_sharing_gives_what_one_cant_take_to_the_other() {
+        let demands = [demand(1380, 3680, 0, 10), demand(4140, 11040, 0, 20)];
+        assert_eq!(
+            share(SharingStrategy::Equal, 10000, &demands),
+            vec![3680, 6320]
+        );
+        // Both at their maximum, the rest is split between them
+        let shares = share(SharingStrategy::Equal, 20000, &demands);
+        assert_eq!(shares, vec![6320, 13680]);
+        assert!(shares.iter().sum::<i32>() <= 20000);
+        // Not enough for both, the later session gives up its share
+        assert_eq!(share(SharingStrategy::Equal, 6000, &demands), vec![6000, 0]);
+    }
+
+    #[test]
+    fn priority_and_first_come_serve_in_order() {
+        let demands = [demand(4140, 11040, 1, 20), demand(4140, 11040, 2, 10)];
+        // Not enough for the second one
+        assert_eq!(
+            share(SharingStrategy::Priority, 12000, &demands),
+            vec![0, 12000]
+        );
+        assert_eq!(
+            share(SharingStrategy::Priority, 16000, &demands),
+            vec![4960, 11040]
+        );
+        let demands = [demand(4140, 11040, 2, 20), demand(4140, 11040, 1, 10)];
+        assert_eq!(
+            share(SharingStrategy::FirstCome, 16000, &demands),
+            vec![4960, 11040]
+        );
+    }
+}
@@ -0,0 +1,815 @@
+use crate::*;
+use log::{debug, info, warn};
+use modbus::ExceptionCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use wallbox::charger::MIN_CHARGING_CURRENT;
+use wallbox::dctr::{AlarmBitField, Currents, DctrParams};
+use wallbox::e3dc::E3DCParams;
+use wallbox::mennekes::MennekesParams;
+use wallbox::modbus_server::{serve, RegisterBank};
+use wallbox::pac2200::Pac2200Params;
+use wallbox::poller::epoch_secs;
+use wallbox::register_map::RegisterMap;
+
+const DEFAULT_E3DC_BIND_TO: &str = "127.0.0.1:5020";
+const DEFAULT_MENNEKES_BIND_TO: &str = "127.0.0.1:5021";
+const DEFAULT_PAC2200_BIND_TO: &str = "127.0.0.1:5022";
+const DEFAULT_RCM_BIND_TO: &str = "127.0.0.1:5023";
+
+const PHASE_VOLTAGE: f64 = 230.0;
+/// The highest HEMS current the emulated wallbox accepts
+const MAX_HEMS_CURRENT: u16 = 32;
+/// The Mennekes' registers written to by the wallbox manager
+const HEMS_CURRENT_REGISTER: u16 = 1000;
+const AUTHORIZE_USER_REGISTER: u16 = 1110;
+/// Log the state of the simulation every this many simulated seconds
+const STATUS_INTERVAL: u64 = 60;
+
+/// A scripted course of events, read from a TOML file
+#[derive(Debug, Deserialize)]
+pub struct Scenario {
+    /// Capacity of the house battery in Wh
+    #[serde(default = "default_battery_capacity")]
+    pub battery_capacity: f64,
+    /// Maximum charging and discharging power of the house battery in W
+    #[serde(default = "default_battery_power")]
+    pub battery_power: f64,
+    /// Number of phases the vehicle charges with
+    #[serde(default = "default_vehicle_phases")]
+    pub vehicle_phases: u8,
+    /// Stop the simulation after this many simulated seconds. By
+    /// default, the values of the last steps are kept forever.
+    pub end: Option<u64>,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+fn default_battery_capacity() -> f64 {
+    10000.0
+}
+
+fn default_battery_power() -> f64 {
+    3000.0
+}
+
+fn default_vehicle_phases() -> u8 {
+    3
+}
+
+/// Values and events at a point in time. Values that are not given
+/// keep the value of the previous step.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Seconds since the start of the simulation
+    pub at: u64,
+    /// Change the values of this step linearly from those of the
+    /// previous step instead of jumping to them
+    #[serde(default)]
+    pub ramp: bool,
+    /// PV production in W
+    pub pv_power: Option<f64>,
+    /// Consumption of the house without the wallbox in W
+    pub house_power: Option<f64>,
+    /// Set the state of charge of the house battery in percent
+    pub battery_soc: Option<f64>,
+    /// Plug in a vehicle, identified by the given RFID user id. An
+    /// empty id leaves the vehicle waiting for authorization.
+    pub plug_in: Option<String>,
+    #[serde(default)]
+    pub unplug: bool,
+    /// Residual DC current in mA
+    pub residual_current_dc: Option<f64>,
+    /// Residual AC current in mA
+    pub residual_current_ac: Option<f64>,
+    /// Names of the raised alarms of type A, e.g. `["dc", "ac_total"]`
+    pub rcm_alarm_a: Option<Vec<String>>,
+    /// Names of the raised alarms of type B
+    pub rcm_alarm_b: Option<Vec<String>>,
+}
+
+impl Scenario {
+    pub fn from_file(path: &Path) -> Result<Scenario> {
+        let scenario = std::fs::read_to_string(path)?;
+        let scenario: Scenario = toml::from_str(&scenario)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))?;
+        scenario.validate()
+    }
+
+    fn validate(mut self) -> Result<Scenario> {
+        self.steps.sort_by_key(|s| s.at);
+        for step in &self.steps {
+            for alarms in [&step.rcm_alarm_a, &step.rcm_alarm_b].into_iter().flatten() {
+                alarm_bit_field(alarms)?;
+            }
+        }
+        if self.vehicle_phases != 1 && self.vehicle_phases != 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "vehicle_phases must be 1 or 3",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// The value at the given time, interpolated if the next step ramps
+    /// towards a new value
+    fn value_at<F: Fn(&Step) -> Option<f64>>(&self, time: u64, value: F) -> Option<f64> {
+        let previous = self
+            .steps
+            .iter()
+            .rev()
+            .filter(|s| s.at <= time)
+            .find_map(|s| value(s).map(|v| (s.at, v)));
+        let next = self
+            .steps
+            .iter()
+            .filter(|s| s.at > time)
+            .find_map(|s| value(s).map(|v| (s.at, v, s.ramp)));
+        match (previous, next) {
+            (Some((from, a)), Some((to, b, true))) => {
+                Some(a + (b - a) * (time - from) as f64 / (to - from) as f64)
+            }
+            (previous, _) => previous.map(|(_, v)| v),
+        }
+    }
+
+    fn alarms_at<F: Fn(&Step) -> Option<&Vec<String>>>(
+        &self,
+        time: u64,
+        alarms: F,
+    ) -> AlarmBitField {
+        self.steps
+            .iter()
+            .rev()
+            .filter(|s| s.at <= time)
+            .find_map(alarms)
+            .map(|a| alarm_bit_field(a).expect("Alarms are validated on load"))
+            .unwrap_or_default()
+    }
+}
+
+fn alarm_bit_field(alarms: &[String]) -> Result<AlarmBitField> {
+    let mut bits = serde_json::to_value(AlarmBitField::default()).map_err(Error::other)?;
+    for alarm in alarms {
+        match bits.get_mut(alarm) {
+            Some(bit) => *bit = serde_json::Value::Bool(true),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown alarm {}", alarm),
+                ))
+            }
+        }
+    }
+    serde_json::from_value(bits).map_err(Error::other)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Device {
+    E3DC,
+    Mennekes,
+    Pac2200,
+    Dctr,
+}
+
+struct Vehicle {
+    user_id: Option<String>,
+    plugged_in_at: u64,
+    session_energy: f64,
+    charging_duration: u64,
+}
+
+/// The simulated PV system, house, wallbox and vehicle
+struct Simulation {
+    scenario: Scenario,
+    register_maps: Vec<(Device, RegisterMap)>,
+    /// Simulated seconds since the start
+    time: u64,
+    battery_energy: f64,
+    vehicle: Option<Vehicle>,
+    hems_current: u16,
+    /// Energy meter reading of the wallbox in Wh
+    wallbox_energy: f64,
+    pv_power: f64,
+    house_power: f64,
+    battery_power: f64,
+    grid_power: f64,
+    charging_power: f64,
+    registers: Vec<(Device, BTreeMap<u16, u16>)>,
+}
+
+impl Simulation {
+    fn new(scenario: Scenario) -> Result<Simulation> {
+        let register_maps = vec![
+            (Device::E3DC, RegisterMap::builtin("e3dc")?),
+            (Device::Mennekes, RegisterMap::builtin("mennekes")?),
+            (Device::Pac2200, RegisterMap::builtin("pac2200")?),
+            (Device::Dctr, RegisterMap::builtin("dctr")?),
+        ];
+        let battery_energy = scenario.battery_capacity / 2.0;
+        let mut simulation = Simulation {
+            scenario,
+            register_maps,
+            time: 0,
+            battery_energy,
+            vehicle: None,
+            hems_current: 0,
+            wallbox_energy: 0.0,
+            pv_power: 0.0,
+            house_power: 0.0,
+            battery_power: 0.0,
+            grid_power: 0.0,
+            charging_power: 0.0,
+            registers: Vec::new(),
+        };
+        simulation.handle_events(0);
+        simulation.update(0)?;
+        Ok(simulation)
+    }
+
+    /// Execute the plug-in, unplug and state of charge events of the
+    /// steps at the given time
+    fn handle_events(&mut self, time: u64) {
+        let capacity = self.scenario.battery_capacity;
+        for step in self.scenario.steps.iter().filter(|s| s.at == time) {
+            if let Some(soc) = step.battery_soc {
+                self.battery_energy = capacity * soc.clamp(0.0, 100.0) / 100.0;
+            }
+            if step.unplug {
+                if let Some(vehicle) = self.vehicle.take() {
+                    info!(
+                        "{}s: vehicle unplugged after charging {:.0} Wh",
+                        time, vehicle.session_energy
+                    );
+                }
+            }
+            if let Some(user_id) = &step.plug_in {
+                info!("{}s: vehicle plugged in, user id {:?}", time, user_id);
+                self.vehicle = Some(Vehicle {
+                    user_id: Some(user_id.clone()).filter(|u| !u.is_empty()),
+                    plugged_in_at: time,
+                    session_energy: 0.0,
+                    charging_duration: 0,
+                });
+            }
+        }
+    }
+
+    /// Advance the simulation by one second
+    fn step(&mut self) -> Result<()> {
+        self.time += 1;
+        self.handle_events(self.time);
+        self.update(1)?;
+        if self.time.is_multiple_of(STATUS_INTERVAL) {
+            info!(
+                "{}s: PV {:.0} W, house {:.0} W, battery {:.0} W ({:.0}%), grid {:.0} W, vehicle {:.0} W at {} A",
+                self.time,
+                self.pv_power,
+                self.house_power,
+                self.battery_power,
+                self.battery_soc(),
+                self.grid_power,
+                self.charging_power,
+                self.hems_current
+            );
+        }
+        Ok(())
+    }
+
+    fn battery_soc(&self) -> f64 {
+        100.0 * self.battery_energy / self.scenario.battery_capacity
+    }
+
+    /// Compute the power flows of the current second and account for
+    /// the energy that flowed during the last `seconds`
+    fn update(&mut self, seconds: u64) -> Result<()> {
+        let hours = seconds as f64 / 3600.0;
+        self.pv_power = self
+            .scenario
+            .value_at(self.time, |s| s.pv_power)
+            .unwrap_or(0.0)
+            .max(0.0);
+        self.house_power = self
+            .scenario
+            .value_at(self.time, |s| s.house_power)
+            .unwrap_or(0.0)
+            .max(0.0);
+
+        let charging = self.vehicle.as_ref().is_some_and(|v| v.user_id.is_some())
+            && self.hems_current >= MIN_CHARGING_CURRENT;
+        self.charging_power = if charging {
+            self.hems_current as f64 * PHASE_VOLTAGE * self.scenario.vehicle_phases as f64
+        } else {
+            0.0
+        };
+        if let Some(vehicle) = self.vehicle.as_mut() {
+            vehicle.session_energy += self.charging_power * hours;
+            if charging {
+                vehicle.charging_duration += seconds;
+            }
+        }
+        self.wallbox_energy += self.charging_power * hours;
+
+        // The battery takes the surplus and covers the deficit as far
+        // as its power and state of charge permit
+        let surplus = self.pv_power - self.house_power - self.charging_power;
+        let capacity = self.scenario.battery_capacity;
+        let max_power = self.scenario.battery_power;
+        self.battery_power = match surplus.clamp(-max_power, max_power) {
+            p if p > 0.0 && self.battery_energy >= capacity => 0.0,
+            p if p < 0.0 && self.battery_energy <= 0.0 => 0.0,
+            p => p,
+        };
+        self.battery_energy =
+            (self.battery_energy + self.battery_power * hours).clamp(0.0, capacity);
+        self.grid_power = self.battery_power - surplus;
+
+        self.registers = Vec::new();
+        let now = epoch_secs()?;
+        for (device, map) in &self.register_maps {
+            let record = match device {
+                Device::E3DC => to_value(self.e3dc_params(now))?,
+                Device::Mennekes => to_value(self.mennekes_params(now))?,
+                Device::Pac2200 => to_value(self.pac2200_params(now))?,
+                Device::Dctr => to_value(self.dctr_params(now))?,
+            };
+            let mut registers = BTreeMap::new();
+            map.encode(&record, &mut registers)?;
+            self.registers.push((*device, registers));
+        }
+        Ok(())
+    }
+
+    fn e3dc_params(&self, now: u64) -> E3DCParams {
+        let consumption = self.house_power + self.charging_power;
+        let autarky = if consumption > 0.0 {
+            100.0 * (1.0 - self.grid_power.max(0.0) / consumption)
+        } else {
+            100.0
+        };
+        let self_utilisation = if self.pv_power > 0.0 {
+            100.0 * (1.0 + self.grid_power.min(0.0) / self.pv_power)
+        } else {
+            0.0
+        };
+        let string_power = (self.pv_power / 2.0) as u16;
+        let string_voltage = if self.pv_power > 0.0 { 600 } else { 0 };
+        let string_current = if string_voltage > 0 {
+            // in hundredths of an Amp
+            (100.0 * string_power as f64 / string_voltage as f64) as u16
+        } else {
+            0
+        };
+        E3DCParams {
+            update: now,
+            magic: 0xe3dc,
+            v1: 1,
+            v2: 0,
+            v3: 0,
+            version_strings: vec![
+                String::from("E3/DC GmbH"),
+                String::from("S10 simulator"),
+                String::from("SIM-0001"),
+                String::from("S10_2022_01"),
+            ],
+            pv_power: self.pv_power as i32,
+            batt_power: self.battery_power as i32,
+            haus_power: consumption as i32,
+            netz_power: self.grid_power as i32,
+            misc_1: 0,
+            misc_2: 0,
+            misc_3: 0,
+            autarky: autarky.clamp(0.0, 100.0) as u8,
+            self_utilisation: self_utilisation.clamp(0.0, 100.0) as u8,
+            akku_charge_percentage: self.battery_soc().round() as u16,
+            // Emergency power is available but not active
+            emergency_power: 2,
+            s1v: string_voltage,
+            s2v: string_voltage,
+            s1a: string_current,
+            s2a: string_current,
+            s1p: string_power,
+            s2p: string_power,
+        }
+    }
+
+    fn mennekes_params(&self, now: u64) -> MennekesParams {
+        let phases = self.scenario.vehicle_phases as u32;
+        let current = if self.charging_power > 0.0 {
+            self.hems_current as u32 * 1000
+        } else {
+            0
+        };
+        let control_pilot = match &self.vehicle {
+            None => 0,
+            Some(_) if self.charging_power > 0.0 => 3,
+            Some(_) => 2,
+        };
+        MennekesParams {
+            update: now,
+            control_pilot,
+            f_i_ac: 0,
+            f_i_dc: 0,
+            i_l1: current,
+            i_l2: if phases == 3 { current } else { 0 },
+            i_l3: if phases == 3 { current } else { 0 },
+            energy: self.wallbox_energy as u32,
+            power: self.charging_power as u32,
+            u_l1: PHASE_VOLTAGE as u32,
+            u_l2: PHASE_VOLTAGE as u32,
+            u_l3: PHASE_VOLTAGE as u32,
+            max_allowed_current_signalled: if self.vehicle.is_some() {
+                self.hems_current
+            } else {
+                0
+            },
+            start_time: self
+                .vehicle
+                .as_ref()
+                .map(|v| v.plugged_in_at as u32)
+                .unwrap_or(0),
+            ev_required_energy: 0,
+            max_allowed_ev_current: 16,
+            current_energy: self
+                .vehicle
+                .as_ref()
+                .map(|v| v.session_energy as u32)
+                .unwrap_or(0),
+            charging_duration: self
+                .vehicle
+                .as_ref()
+                .map(|v| v.charging_duration as u32)
+                .unwrap_or(0),
+            user_id: self.vehicle.as_ref().and_then(|v| v.user_id.clone()),
+            hems_current: self.hems_current,
+        }
+    }
+
+    fn pac2200_params(&self, now: u64) -> Pac2200Params {
+        let p = (self.grid_power / 3.0) as f32;
+        let u = PHASE_VOLTAGE as f32;
+        let u_ll = u * 3f32.sqrt();
+        let i = p.abs() / u;
+        let pf = if p < 0.0 { -1.0 } else { 1.0 };
+        Pac2200Params {
+            update: now,
+            u_l1: u,
+            u_l2: u,
+            u_l3: u,
+            u_l1l2: u_ll,
+            u_l2l3: u_ll,
+            u_l1l3: u_ll,
+            i_l1: i,
+            i_l2: i,
+            i_l3: i,
+            pva_l1: p.abs(),
+            pva_l2: p.abs(),
+            pva_l3: p.abs(),
+            p_l1: p,
+            p_l2: p,
+            p_l3: p,
+            pvar_l1: 0.0,
+            pvar_l2: 0.0,
+            pvar_l3: 0.0,
+            pf_l1: pf,
+            pf_l2: pf,
+            pf_l3: pf,
+            frequency: 50.0,
+            u_avg_ln: u,
+            u_avg_ll: u_ll,
+            i_avg: i,
+            p_avg: p,
+            pva_avg: p.abs(),
+            pvar_avg: 0.0,
+            pf_tot: pf,
+            i_n: 0.0,
+        }
+    }
+
+    fn dctr_params(&self, now: u64) -> DctrParams {
+        let dc = self
+            .scenario
+            .value_at(self.time, |s| s.residual_current_dc)
+            .unwrap_or(0.0) as u16;
+        let ac = self
+            .scenario
+            .value_at(self.time, |s| s.residual_current_ac)
+            .unwrap_or(0.0) as u16;
+        let all_alarms = AlarmBitField::from_int(0xff);
+        DctrParams {
+            update: now,
+            validity: 1,
+            f_i: Currents {
+                dc,
+                ac_total: ac,
+                ac_50hz: ac,
+                ac_lt100hz: 0,
+                ac_150hz: 0,
+                ac_100hz_1khz: 0,
+                ac_gt1khz: 0,
+                ac_gt10khz: 0,
+            },
+            raised_alarm_a: self
+                .scenario
+                .alarms_at(self.time, |s| s.rcm_alarm_a.as_ref()),
+            raised_alarm_b: self
+                .scenario
+                .alarms_at(self.time, |s| s.rcm_alarm_b.as_ref()),
+            thresholds_a: thresholds(3, 15),
+            activated_alarms_a: all_alarms.clone(),
+            thresholds_b: thresholds(6, 30),
+            activated_alarms_b: all_alarms,
+            alarm_delay: 0,
+        }
+    }
+
+    fn registers(&self, device: Device) -> Option<&BTreeMap<u16, u16>> {
+        self.registers
+            .iter()
+            .find(|(d, _)| *d == device)
+            .map(|(_, r)| r)
+    }
+
+    /// Handle a write to the wallbox's registers, the only writes the
+    /// real devices are sent
+    fn write_mennekes(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> std::result::Result<(), ExceptionCode> {
+        match address {
+            HEMS_CURRENT_REGISTER if values.len() == 1 => {
+                if values[0] > MAX_HEMS_CURRENT {
+                    return Err(ExceptionCode::IllegalDataValue);
+                }
+                if values[0] != self.hems_current {
+                    info!(
+                        "{}s: HEMS current set from {} to {} A",
+                        self.time, self.hems_current, values[0]
+                    );
+                }
+                self.hems_current = values[0];
+            }
+            AUTHORIZE_USER_REGISTER => {
+                let user_id = String::from_utf8(modbus::binary::unpack_bytes(values))
+                    .map_err(|_| ExceptionCode::IllegalDataValue)?;
+                let user_id = user_id.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+                match self.vehicle.as_mut() {
+                    Some(vehicle) => {
+                        info!("{}s: user {:?} authorized", self.time, user_id);
+                        vehicle.user_id = Some(String::from(user_id));
+                    }
+                    None => warn!(
+                        "{}s: user {:?} authorized, but no vehicle is plugged in",
+                        self.time, user_id
+                    ),
+                }
+            }
+            _ => return Err(ExceptionCode::IllegalDataAddress),
+        }
+        self.update(0).map_err(|e| {
+            warn!("Unable to update registers: {}", e);
+            ExceptionCode::SlaveOrServerFailure
+        })
+    }
+}
+
+fn thresholds(dc: u16, ac: u16) -> Currents {
+    Currents {
+        dc,
+        ac_total: ac,
+        ac_50hz: ac,
+        ac_lt100hz: ac,
+        ac_150hz: ac,
+        ac_100hz_1khz: ac,
+        ac_gt1khz: ac,
+        ac_gt10khz: ac,
+    }
+}
+
+fn to_value<T: Serialize>(params: T) -> Result<serde_json::Value> {
+    serde_json::to_value(params).map_err(Error::other)
+}
+
+/// One of the simulated devices, as served via Modbus TCP
+struct SimulatedDevice {
+    device: Device,
+    simulation: Arc<Mutex<Simulation>>,
+}
+
+impl RegisterBank for SimulatedDevice {
+    fn read(&self, address: u16, count: u16) -> std::result::Result<Vec<u16>, ExceptionCode> {
+        let simulation = self
+            .simulation
+            .lock()
+            .map_err(|_| ExceptionCode::SlaveOrServerFailure)?;
+        let registers = simulation.registers(self.device);
+        Ok((address..=address + (count - 1))
+            .map(|a| registers.and_then(|r| r.get(&a)).copied().unwrap_or(0))
+            .collect())
+    }
+
+    fn write(&self, address: u16, values: &[u16]) -> std::result::Result<(), ExceptionCode> {
+        let mut simulation = self
+            .simulation
+            .lock()
+            .map_err(|_| ExceptionCode::SlaveOrServerFailure)?;
+        match self.device {
+            Device::Mennekes => simulation.write_mennekes(address, values),
+            _ => Err(ExceptionCode::IllegalDataAddress),
+        }
+    }
+}
+
+pub fn simulate_devices(sdp: SimulateDevicesParams) -> Result<()> {
+    wallbox::init_stderr_logging()?;
+
+    let speed = sdp.speed.unwrap_or(1.0);
+    if !(speed > 0.0 && speed <= 1000.0) {
+        return Err(Error::other("The speed must be between 0 and 1000"));
+    }
+    let scenario = Scenario::from_file(&sdp.scenario)?;
+    let end = scenario.end;
+    let simulation = Arc::new(Mutex::new(Simulation::new(scenario)?));
+
+    for (device, name, bind_to, default) in [
+        (
+            Device::E3DC,
+            "e3dc system",
+            &sdp.e3dc_bind_to,
+            DEFAULT_E3DC_BIND_TO,
+        ),
+        (
+            Device::Mennekes,
+            "mennekes wallbox",
+            &sdp.mennekes_bind_to,
+            DEFAULT_MENNEKES_BIND_TO,
+        ),
+        (
+            Device::Pac2200,
+            "pac2200 meter",
+            &sdp.pac2200_bind_to,
+            DEFAULT_PAC2200_BIND_TO,
+        ),
+        (
+            Device::Dctr,
+            "RCM system",
+            &sdp.rcm_bind_to,
+            DEFAULT_RCM_BIND_TO,
+        ),
+    ] {
+        let bank = SimulatedDevice {
+            device,
+            simulation: simulation.clone(),
+        };
+        serve(name, bind_to.as_deref().unwrap_or(default), Arc::new(bank))?;
+    }
+
+    let tick = Duration::from_secs_f64(1.0 / speed);
+    loop {
+        std::thread::sleep(tick);
+        let mut simulation = simulation
+            .lock()
+            .map_err(|_| Error::other("Poisoned lock"))?;
+        simulation.step()?;
+        debug!("{}s: simulated", simulation.time);
+        if end.is_some_and(|end| simulation.time >= end) {
+            info!("{}s: end of scenario", simulation.time);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wallbox::dctr::Dctr;
+    use wallbox::e3dc::E3DC;
+    use wallbox::mennekes::Mennekes;
+    use wallbox::poller::TcpConnector;
+
+    fn serve_device(simulation: &Arc<Mutex<Simulation>>, device: Device) -> TcpConnector {
+        let bank = SimulatedDevice {
+            device,
+            simulation: simulation.clone(),
+        };
+        let address = serve("test device", "127.0.0.1:0", Arc::new(bank)).unwrap();
+        TcpConnector {
+            host: String::from("127.0.0.1"),
+            port: address.port(),
+            slave_id: 1,
+        }
+    }
+
+    fn wait_for<T, F: Fn() -> Option<T>>(f: F) -> T {
+        for _ in 0..100 {
+            if let Some(value) = f() {
+                return value;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Timed out");
+    }
+
+    fn simulation(scenario: &str, seconds: u64) -> Arc<Mutex<Simulation>> {
+        let scenario: Scenario = toml::from_str(scenario).unwrap();
+        let mut simulation = Simulation::new(scenario.validate().unwrap()).unwrap();
+        for _ in 0..seconds {
+            simulation.step().unwrap();
+        }
+        Arc::new(Mutex::new(simulation))
+    }
+
+    #[test]
+    fn drivers_read_the_simulated_devices() {
+        let simulation = simulation(
+            r#"
+            [[step]]
+            at = 0
+            pv_power = 2000
+            house_power = 500
+            plug_in = "04AABBCCDD"
+
+            [[step]]
+            at = 100
+            pv_power = 3000
+            ramp = true
+
+            [[step]]
+            at = 30
+            residual_current_dc = 4
+            rcm_alarm_a = ["dc"]
+            "#,
+            50,
+        );
+
+        let e3dc = E3DC::new(
+            serve_device(&simulation, Device::E3DC),
+            Duration::from_millis(100),
+            None,
+        )
+        .unwrap();
+        let params = wait_for(|| e3dc.get_current_params());
+        assert_eq!(params.pv_power, 2500);
+        assert_eq!(params.haus_power, 500);
+        assert_eq!(params.batt_power, 2000);
+        assert_eq!(params.netz_power, 0);
+
+        let dctr = Dctr::new(
+            serve_device(&simulation, Device::Dctr),
+            Duration::from_millis(100),
+            None,
+        )
+        .unwrap();
+        let params = wait_for(|| dctr.get_current_params());
+        assert_eq!(params.f_i.dc, 4);
+        assert!(params.raised_alarm_a.dc);
+        assert!(!params.raised_alarm_b.raised());
+
+        let mennekes = Mennekes::new(
+            serve_device(&simulation, Device::Mennekes),
+            Duration::from_millis(100),
+            None,
+        )
+        .unwrap();
+        let params = wait_for(|| mennekes.get_current_params());
+        assert_eq!(params.user_id.as_deref(), Some("04AABBCCDD"));
+        assert_eq!(params.hems_current, 0);
+
+        mennekes.set_amps(8, String::from("8 A"));
+        let params = wait_for(|| mennekes.get_current_params().filter(|p| p.power > 0));
+        assert_eq!(params.hems_current, 8);
+        assert_eq!(params.power, 8 * 230 * 3);
+        assert_eq!(params.i_l1, 8000);
+    }
+
+    #[test]
+    fn authorization_starts_charging() {
+        let simulation = simulation(
+            r#"
+            [[step]]
+            at = 0
+            plug_in = ""
+            "#,
+            0,
+        );
+        let mut s = simulation.lock().unwrap();
+        s.write_mennekes(HEMS_CURRENT_REGISTER, &[10]).unwrap();
+        assert_eq!(s.charging_power, 0.0);
+        let regs = modbus::binary::pack_bytes(b"04AABBCCDD").unwrap();
+        s.write_mennekes(AUTHORIZE_USER_REGISTER, &regs).unwrap();
+        assert_eq!(s.charging_power, 10.0 * 230.0 * 3.0);
+        s.step().unwrap();
+        assert_eq!(s.vehicle.as_ref().unwrap().charging_duration, 1);
+        assert!(matches!(
+            s.write_mennekes(HEMS_CURRENT_REGISTER, &[40]),
+            Err(ExceptionCode::IllegalDataValue)
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::config::{SmoothingConfig, SmoothingMethod};
+use crate::grid::GridParams;
+use crate::pv_source::PvParams;
+use std::collections::VecDeque;
+
+/// Seconds over which an increase of the surplus is smoothed by default
+const DEFAULT_RAMP_UP_WINDOW: u64 = 120;
+/// Seconds over which a decrease of the surplus is smoothed by default
+const DEFAULT_RAMP_DOWN_WINDOW: u64 = 20;
+
+/// Smooths a reading over sliding windows of time, one for rising and
+/// one for falling values. The value only rises once the estimates of
+/// both windows are above it and then follows the one for rising
+/// values, and vice versa, so a short window reacts fast in its
+/// direction while the long one holds the value in the other.
+#[derive(Debug, Clone)]
+pub struct Smoother {
+    method: SmoothingMethod,
+    rise_window: u64,
+    fall_window: u64,
+    /// The samples of the longer window, oldest first
+    samples: VecDeque<(u64, f64)>,
+    value: Option<f64>,
+}
+
+impl Smoother {
+    pub fn new(method: SmoothingMethod, rise_window: u64, fall_window: u64) -> Smoother {
+        Smoother {
+            method,
+            rise_window,
+            fall_window,
+            samples: VecDeque::new(),
+            value: None,
+        }
+    }
+
+    /// Add the reading taken at the given time, unless it isn't newer
+    /// than the last one
+    pub fn add(&mut self, time: u64, value: f64) {
+        if self.samples.back().is_some_and(|(last, _)| *last >= time) {
+            return;
+        }
+        self.samples.push_back((time, value));
+        let window = self.rise_window.max(self.fall_window);
+        while self
+            .samples
+            .front()
+            .is_some_and(|(first, _)| *first + window < time)
+        {
+            self.samples.pop_front();
+        }
+
+        let rising = self.estimate(self.rise_window);
+        let falling = self.estimate(self.fall_window);
+        self.value = match self.value {
+            Some(last) if rising > last && falling > last => Some(rising),
+            Some(last) if rising < last && falling < last => Some(falling),
+            Some(last) => Some(last),
+            None => Some(value),
+        };
+    }
+
+    /// The smoothed value, if there is a reading
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+
+    /// The estimate over the given number of seconds up to the latest
+    /// sample
+    fn estimate(&self, window: u64) -> f64 {
+        let Some(&(latest, _)) = self.samples.back() else {
+            return 0.0;
+        };
+        let samples = self
+            .samples
+            .iter()
+            .filter(|(time, _)| *time + window >= latest);
+        match self.method {
+            SmoothingMethod::Mean => {
+                let (sum, count) = samples.fold((0.0, 0), |(sum, count), (_, value)| {
+                    (sum + value, count + 1)
+                });
+                sum / count as f64
+            }
+            SmoothingMethod::Median => {
+                let mut values: Vec<f64> = samples.map(|(_, value)| *value).collect();
+                values.sort_by(f64::total_cmp);
+                let middle = values.len() / 2;
+                if values.len().is_multiple_of(2) {
+                    (values[middle - 1] + values[middle]) / 2.0
+                } else {
+                    values[middle]
+                }
+            }
+            SmoothingMethod::Exponential => {
+                // The window spans three time constants
+                let time_constant = window as f64 / 3.0;
+                let mut estimate: Option<(u64, f64)> = None;
+                for &(time, value) in samples {
+                    estimate = Some(match estimate {
+                        Some((last, estimate)) if time_constant > 0.0 => {
+                            let weight = 1.0 - (-((time - last) as f64) / time_constant).exp();
+                            (time, estimate + weight * (value - estimate))
+                        }
+                        _ => (time, value),
+                    });
+                }
+                estimate.map_or(0.0, |(_, estimate)| estimate)
+            }
+        }
+    }
+}
+
+/// Smooths the readings the surplus is computed from. An increase of
+/// the surplus follows the ramp-up window, a decrease the ramp-down
+/// window, so e.g. the charging current is reduced as soon as a cloud
+/// passes but only increased again once the sun is back for a while.
+/// The house consumption and the grid exchange are smoothed without
+/// what the vehicles draw, which is added back as it is: the controller
+/// sets the charging power itself, so a change of it must not lag
+/// behind. The battery's readings are used as they are.
+#[derive(Debug, Clone)]
+pub struct Smoothing {
+    pv_power: Smoother,
+    house_power: Smoother,
+    grid_power: Smoother,
+    /// The grid exchange measured by the grid meter
+    meter_power: Smoother,
+}
+
+impl From<&SmoothingConfig> for Smoothing {
+    fn from(config: &SmoothingConfig) -> Self {
+        let up = config.ramp_up_window.unwrap_or(DEFAULT_RAMP_UP_WINDOW);
+        let down = config.ramp_down_window.unwrap_or(DEFAULT_RAMP_DOWN_WINDOW);
+        // Production adds to the surplus, consumption and import take
+        // from it
+        let more = Smoother::new(config.method, up, down);
+        let less = Smoother::new(config.method, down, up);
+        Smoothing {
+            pv_power: more,
+            house_power: less.clone(),
+            grid_power: less.clone(),
+            meter_power: less,
+        }
+    }
+}
+
+impl Smoothing {
+    /// Add a reading of the PV system, taken while the vehicles drew
+    /// the given power
+    pub fn add_pv(&mut self, pv: &PvParams, charging_power: i32) {
+        let without_vehicles = |power: i32| (power - charging_power) as f64;
+        self.pv_power.add(pv.update, pv.pv_power as f64);
+        self.house_power
+            .add(pv.update, without_vehicles(pv.house_power));
+        self.grid_power
+            .add(pv.update, without_vehicles(pv.grid_power));
+    }
+
+    /// Add a reading of the grid meter, see add_pv
+    pub fn add_grid(&mut self, grid: &GridParams, charging_power: i32) {
+        self.meter_power
+            .add(grid.update, (grid.power - charging_power) as f64);
+    }
+
+    /// The given PV reading with smoothed values, for the power the
+    /// vehicles draw now
+    pub fn pv(&self, pv: &PvParams, charging_power: i32) -> PvParams {
+        PvParams {
+            pv_power: smoothed(&self.pv_power, pv.pv_power, 0),
+            house_power: smoothed(&self.house_power, pv.house_power, charging_power),
+            grid_power: smoothed(&self.grid_power, pv.grid_power, charging_power),
+            ..pv.clone()
+        }
+    }
+
+    /// The given grid meter reading with a smoothed grid exchange, see
+    /// pv. The phases are left as they are.
+    pub fn grid(&self, grid: &GridParams, charging_power: i32) -> GridParams {
+        GridParams {
+            power: smoothed(&self.meter_power, grid.power, charging_power),
+            ..grid.clone()
+        }
+    }
+}
+
+/// The smoothed value plus the power the vehicles draw, or the raw
+/// reading if there is no smoothed value yet
+fn smoothed(smoother: &Smoother, raw: i32, charging_power: i32) -> i32 {
+    smoother
+        .value()
+        .map_or(raw, |value| value.round() as i32 + charging_power)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Add one sample per second and return the smoothed values
+    fn smooth(smoother: &mut Smoother, values: &[f64]) -> Vec<f64> {
+        let start = smoother.samples.back().map_or(0, |(time, _)| time + 1);
+        let mut smoothed = Vec::new();
+        for (time, value) in (start..).zip(values) {
+            smoother.add(time, *value);
+            smoothed.push(smoother.value().unwrap());
+        }
+        smoothed
+    }
+
+    #[test]
+    fn median_ignores_spikes() {
+        let mut smoother = Smoother::new(SmoothingMethod::Median, 4, 4);
+        let smoothed = smooth(&mut smoother, &[1000.0, 1000.0, 5000.0, 1000.0, 0.0]);
+        assert_eq!(smoothed, vec![1000.0; 5]);
+    }
+
+    #[test]
+    fn falls_fast_and_rises_slowly() {
+        let mut smoother = Smoother::new(SmoothingMethod::Mean, 10, 0);
+        smooth(&mut smoother, &[4000.0; 11]);
+        // A cloud is followed right away
+        assert_eq!(smooth(&mut smoother, &[1000.0; 11]), vec![1000.0; 11]);
+        // The sun has to be back for the whole window to count fully
+        let smoothed = smooth(&mut smoother, &[4000.0; 11]);
+        assert!(smoothed[0] > 1000.0 && smoothed[0] < 1500.0);
+        assert!(smoothed[5] < 4000.0);
+        assert_eq!(smoothed[10], 4000.0);
+        // Readings that aren't newer are ignored
+        smoother.add(5, 0.0);
+        assert_eq!(smoother.value(), Some(4000.0));
+    }
+
+    #[test]
+    fn charging_power_changes_are_not_smoothed() {
+        let config = SmoothingConfig {
+            method: SmoothingMethod::Mean,
+            ramp_up_window: Some(60),
+            ramp_down_window: Some(20),
+        };
+        let mut smoothing = Smoothing::from(&config);
+        // 8000W of PV and 500W of house consumption, while the vehicle
+        // charges at 6A and, from the 30th second on, at 8A
+        for time in 0..90 {
+            let charging = if time < 30 { 4140 } else { 5520 };
+            let reading = PvParams {
+                update: time,
+                pv_power: 8000,
+                house_power: 500 + charging,
+                grid_power: 500 + charging - 8000,
+                battery_power: 0,
+                battery_soc: 0,
+                island: false,
+            };
+            smoothing.add_pv(&reading, charging);
+            let pv = smoothing.pv(&reading, charging);
+            assert_eq!(charging + pv.pv_power - pv.house_power, 7500, "{}", time);
+            assert_eq!(pv.grid_power, reading.grid_power);
+        }
+    }
+
+    #[test]
+    fn exponential_follows_gradually() {
+        let mut smoother = Smoother::new(SmoothingMethod::Exponential, 30, 30);
+        smooth(&mut smoother, &[0.0; 31]);
+        let smoothed = smooth(&mut smoother, &[3000.0; 30]);
+        assert!(smoothed[0] > 0.0 && smoothed[0] < 500.0);
+        assert!(smoothed.windows(2).all(|w| w[0] <= w[1]));
+        assert!(smoothed[29] > 2800.0);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::time_series::TimeSeries;
+use std::io::Result;
+use std::path::Path;
+
+/// Prices of a dynamic electricity tariff in €/kWh, see TimeSeries for
+/// the file format. The key of the prices in JSON files is "price".
+#[derive(Debug, Clone)]
+pub struct Tariff {
+    prices: TimeSeries,
+}
+
+impl Tariff {
+    pub fn new(path: &Path) -> Tariff {
+        Tariff {
+            prices: TimeSeries::new(path, "price"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.prices.path()
+    }
+
+    /// The number of known prices
+    pub fn len(&self) -> usize {
+        self.prices.slots().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.slots().is_empty()
+    }
+
+    /// Reload the prices if the file was modified, see TimeSeries
+    pub fn refresh(&mut self) -> Result<bool> {
+        self.prices.refresh()
+    }
+
+    /// The price at the given time, if known
+    pub fn price_at(&self, time: u64) -> Option<f64> {
+        self.prices.value_at(time)
+    }
+
+    /// Whether the given time falls into the cheapest slots that add up
+    /// to the given number of seconds from then on until the given time
+    pub fn is_cheapest(&self, now: u64, until: u64, seconds: u64) -> bool {
+        let mut slots: Vec<_> = self
+            .prices
+            .slots()
+            .iter()
+            .filter(|slot| slot.end > now && slot.start < until)
+            .collect();
+        slots.sort_by(|a, b| a.value.total_cmp(&b.value).then(a.start.cmp(&b.start)));
+        let mut covered = 0;
+        for slot in slots {
+            if covered >= seconds {
+                break;
+            }
+            if slot.start <= now && now < slot.end {
+                return true;
+            }
+            covered += slot.end.min(until) - slot.start.max(now);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time_series;
+
+    #[test]
+    fn cheapest_slots_are_found() {
+        let tariff = Tariff {
+            prices: time_series::from_values(&[(0, 0.3), (3600, 0.1), (7200, 0.4), (10800, 0.2)]),
+        };
+        // The two cheapest hours until 14400
+        assert!(!tariff.is_cheapest(0, 14400, 7200));
+        assert!(tariff.is_cheapest(3600, 14400, 7200));
+        assert!(tariff.is_cheapest(10800, 14400, 7200));
+        // Later on, only the slots from then on count
+        assert!(!tariff.is_cheapest(7200, 14400, 3600));
+        assert!(tariff.is_cheapest(7200, 14400, 7200));
+        // The second cheapest hour is after the departure
+        assert!(tariff.is_cheapest(0, 3600, 7200));
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Seconds a value applies if the file doesn't tell otherwise, i.e.
+/// for the last one of a file with a single value
+const DEFAULT_SLOT_DURATION: u64 = 3600;
+
+/// A value that applies during a time slot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Slot {
+    /// Start and end of the slot, in seconds since the UNIX epoch
+    pub start: u64,
+    pub end: u64,
+    pub value: f64,
+}
+
+/// Values over time, loaded from a file that is reloaded whenever it
+/// changes. Each value applies until the next one starts; the last one
+/// as long as the one before it.
+///
+/// CSV files have a start time and a value per line, separated by a
+/// comma, and optionally a header line. JSON files contain an array of
+/// objects with start and the value under the series' key. Start times
+/// are given in seconds since the UNIX epoch or as RFC 3339 timestamps,
+/// e.g. 2024-01-01T13:00:00+01:00.
+#[derive(Debug, Clone)]
+pub struct TimeSeries {
+    path: PathBuf,
+    /// The key of the values in JSON files
+    key: &'static str,
+    /// Whether the file was read already and when it was modified then
+    checked: bool,
+    modified: Option<SystemTime>,
+    slots: Vec<Slot>,
+}
+
+impl TimeSeries {
+    pub fn new(path: &Path, key: &'static str) -> TimeSeries {
+        TimeSeries {
+            path: path.to_path_buf(),
+            key,
+            checked: false,
+            modified: None,
+            slots: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    /// Reload the file if it was modified since it was last read and
+    /// return whether it was. The values are kept if it can't be read,
+    /// but dropped once it is removed, so stale values aren't acted on.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+        if self.checked && modified == self.modified {
+            return Ok(false);
+        }
+        self.checked = true;
+        self.modified = modified;
+        if modified.is_none() {
+            self.slots.clear();
+        }
+        let content = std::fs::read_to_string(&self.path)?;
+        let values = if self.path.extension().is_some_and(|e| e == "json") {
+            parse_json(&content, self.key)?
+        } else {
+            parse_csv(&content)?
+        };
+        self.slots = slots(values);
+        Ok(true)
+    }
+
+    /// The value at the given time, if known
+    pub fn value_at(&self, time: u64) -> Option<f64> {
+        self.slots
+            .iter()
+            .find(|slot| slot.start <= time && time < slot.end)
+            .map(|slot| slot.value)
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parse_time(s: &str) -> Option<u64> {
+    s.parse().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(s)
+            .ok()
+            .and_then(|t| u64::try_from(t.timestamp()).ok())
+    })
+}
+
+fn parse_csv(content: &str) -> Result<Vec<(u64, f64)>> {
+    let mut values = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let value = line.split_once(',').and_then(|(start, value)| {
+            Some((parse_time(start.trim())?, value.trim().parse().ok()?))
+        });
+        match value {
+            Some(value) => values.push(value),
+            // A header
+            None if number == 0 => (),
+            None => return Err(invalid(format!("Invalid value in line {}", number + 1))),
+        }
+    }
+    Ok(values)
+}
+
+fn parse_json(content: &str, key: &str) -> Result<Vec<(u64, f64)>> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let start = match &entry["start"] {
+                serde_json::Value::String(s) => parse_time(s),
+                start => start.as_u64(),
+            };
+            start
+                .zip(entry[key].as_f64())
+                .ok_or_else(|| invalid(format!("Invalid {} at index {}", key, i)))
+        })
+        .collect()
+}
+
+fn slots(mut values: Vec<(u64, f64)>) -> Vec<Slot> {
+    values.sort_by_key(|(start, _)| *start);
+    let mut slots: Vec<Slot> = Vec::with_capacity(values.len());
+    for (i, (start, value)) in values.iter().enumerate() {
+        let end = match values.get(i + 1) {
+            Some((next, _)) => *next,
+            None => {
+                let duration = slots
+                    .last()
+                    .map_or(DEFAULT_SLOT_DURATION, |s| s.end - s.start);
+                start + duration
+            }
+        };
+        slots.push(Slot {
+            start: *start,
+            end,
+            value: *value,
+        });
+    }
+    slots
+}
+
+/// A time series with the given values, for tests of its users
+#[cfg(test)]
+pub(crate) fn from_values(values: &[(u64, f64)]) -> TimeSeries {
+    TimeSeries {
+        slots: slots(values.to_vec()),
+        ..TimeSeries::new(Path::new("values.csv"), "value")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_and_json_files_are_parsed() {
+        let csv = "start,price\n3600,0.30\n2024-01-01T01:00:00+01:00,0.25\n";
+        assert_eq!(
+            parse_csv(csv).unwrap(),
+            vec![(3600, 0.30), (1_704_067_200, 0.25)]
+        );
+        assert!(parse_csv("start,price\n3600,expensive\n").is_err());
+
+        let json =
+            r#"[{"start": 3600, "price": 0.3}, {"start": "1970-01-01T02:00:00Z", "price": 0.2}]"#;
+        assert_eq!(
+            parse_json(json, "price").unwrap(),
+            vec![(3600, 0.3), (7200, 0.2)]
+        );
+        assert!(parse_json(json, "power").is_err());
+    }
+
+    #[test]
+    fn file_is_reloaded_when_modified() {
+        let path = std::env::temp_dir().join(format!("prices-{}.csv", std::process::id()));
+        std::fs::write(&path, "0,0.30\n").unwrap();
+        let mut series = TimeSeries::new(&path, "price");
+        assert!(series.refresh().unwrap());
+        assert!(!series.refresh().unwrap());
+        assert_eq!(series.value_at(0), Some(0.30));
+
+        // Make sure the modification time differs on coarse file systems
+        std::fs::write(&path, "0,0.20\n").unwrap();
+        let file = std::fs::File::options().append(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(series.refresh().unwrap());
+        assert_eq!(series.value_at(0), Some(0.20));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn values_are_dropped_when_file_is_removed() {
+        let path = std::env::temp_dir().join(format!("forecast-{}.csv", std::process::id()));
+        std::fs::write(&path, "0,1000\n").unwrap();
+        let mut series = TimeSeries::new(&path, "power");
+        assert!(series.refresh().unwrap());
+        assert_eq!(series.value_at(0), Some(1000.0));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(series.refresh().is_err());
+        assert_eq!(series.value_at(0), None);
+        assert!(series.slots().is_empty());
+        // The missing file is only reported once
+        assert!(!series.refresh().unwrap());
+    }
+
+    #[test]
+    fn values_apply_until_the_next_one() {
+        let series = from_values(&[(7200, 0.2), (3600, 0.3)]);
+        assert_eq!(series.value_at(3599), None);
+        assert_eq!(series.value_at(3600), Some(0.3));
+        assert_eq!(series.value_at(7199), Some(0.3));
+        assert_eq!(series.value_at(10799), Some(0.2));
+        assert_eq!(series.value_at(10800), None);
+    }
+}
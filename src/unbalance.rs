@@ -0,0 +1,172 @@
+use crate::charger::{reduced_current, Settling, VehicleLoad};
+use crate::config::UnbalancedLoadConfig;
+
+/// The unbalanced load allowed by German grid rules, in VA
+const DEFAULT_MAX_UNBALANCED_LOAD: u32 = 4600;
+
+/// The unbalanced load of the grid connection, for the status socket
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Unbalance {
+    /// The difference between the most and the least loaded phase, in VA
+    pub imbalance: i32,
+    pub limit: i32,
+    /// How much more each charger could add to the imbalance, in VA.
+    /// None for vehicles charging on all phases, which don't add to it.
+    pub headroom: Vec<Option<i32>>,
+}
+
+/// Limits the unbalanced load of the grid connection, i.e. the
+/// difference between the currents of the most and the least loaded
+/// phase, while vehicles charge on fewer than three phases. The house's
+/// own loads are included as far as the currents measure them.
+#[derive(Debug, Clone)]
+pub struct UnbalanceGuard {
+    phase_voltage: f32,
+    /// The limit per phase, in Amps
+    max_current: f32,
+    settling: Settling,
+    status: Option<Unbalance>,
+}
+
+impl UnbalanceGuard {
+    pub fn new(config: &UnbalancedLoadConfig, phase_voltage: u16) -> UnbalanceGuard {
+        let max_power = config.max_power.unwrap_or(DEFAULT_MAX_UNBALANCED_LOAD);
+        UnbalanceGuard {
+            phase_voltage: phase_voltage as f32,
+            max_current: max_power as f32 / phase_voltage as f32,
+            settling: Settling::default(),
+            status: None,
+        }
+    }
+
+    /// The Amps the vehicle could draw in addition on its phases without
+    /// exceeding the limit, negative if it is exceeded. None if it
+    /// charges on all phases.
+    fn headroom(&self, currents: [f32; 3], phases: [bool; 3]) -> Option<f32> {
+        let (loaded, others): (Vec<usize>, Vec<usize>) = (0..3).partition(|&p| phases[p]);
+        loaded
+            .iter()
+            .flat_map(|&a| others.iter().map(move |&b| currents[a] - currents[b]))
+            .map(|imbalance| self.max_current - imbalance)
+            .reduce(f32::min)
+    }
+
+    /// The highest charging current the vehicle may draw, if limited
+    pub fn limit(&self, currents: [f32; 3], vehicle: &VehicleLoad) -> Option<u16> {
+        let phases = vehicle.phases();
+        let headroom = self.headroom(currents, phases)?;
+        Some(reduced_current(
+            (vehicle.draw(phases) + headroom).floor() as i32
+        ))
+    }
+
+    /// Check the currents of the grid connection and return the
+    /// charging current to reduce each charger's setpoint to, if the
+    /// unbalanced load exceeds the limit
+    pub fn check(
+        &mut self,
+        currents: [f32; 3],
+        vehicles: &[VehicleLoad],
+        now: u64,
+    ) -> Vec<Option<u16>> {
+        let (min, max) = currents.iter().fold((f32::MAX, f32::MIN), |(min, max), c| {
+            (min.min(*c), max.max(*c))
+        });
+        self.status = Some(Unbalance {
+            imbalance: ((max - min) * self.phase_voltage).round() as i32,
+            limit: (self.max_current * self.phase_voltage).round() as i32,
+            headroom: vehicles
+                .iter()
+                .map(|vehicle| {
+                    self.headroom(currents, vehicle.phases())
+                        .map(|headroom| (headroom * self.phase_voltage).round() as i32)
+                })
+                .collect(),
+        });
+
+        if !self.settling.settled(now) {
+            return vec![None; vehicles.len()];
+        }
+        let reductions: Vec<Option<u16>> = vehicles
+            .iter()
+            .map(|vehicle| {
+                self.limit(currents, vehicle)
+                    .filter(|limit| *limit < vehicle.setpoint)
+            })
+            .collect();
+        if reductions.iter().any(Option::is_some) {
+            self.settling.reduced(now);
+        }
+        reductions
+    }
+
+    /// The unbalanced load as of the last check
+    pub fn status(&self) -> Option<&Unbalance> {
+        self.status.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn guard() -> UnbalanceGuard {
+        let config = UnbalancedLoadConfig {
+            max_power: Some(4600),
+        };
+        UnbalanceGuard::new(&config, 230)
+    }
+
+    fn vehicle(currents: Option<[f32; 3]>, phases: u16, setpoint: u16) -> VehicleLoad {
+        VehicleLoad {
+            currents,
+            phases,
+            setpoint,
+            priority: 0,
+            since: NOW,
+        }
+    }
+
+    #[test]
+    fn single_phase_charging_is_limited() {
+        let guard = guard();
+        // 20A are allowed between the phases; the vehicle draws 16A on
+        // the first one and the house 3A on the second one
+        let charging = vehicle(Some([16.0, 0.0, 0.0]), 1, 16);
+        assert_eq!(guard.limit([17.0, 4.0, 1.0], &charging), Some(20));
+        // Not yet charging, but switched to one phase
+        let waiting = vehicle(Some([0.0; 3]), 1, 0);
+        assert_eq!(guard.limit([1.0, 4.0, 1.0], &waiting), Some(20));
+        // Vehicles charging on all phases don't add to the imbalance
+        let balanced = vehicle(Some([16.0; 3]), 3, 16);
+        assert_eq!(guard.limit([17.0, 20.0, 16.0], &balanced), None);
+        // Too little room left for the minimum with a house load of 16A
+        // on the same phase
+        assert_eq!(guard.limit([17.0, 1.0, 1.0], &waiting), Some(0));
+    }
+
+    #[test]
+    fn exceeding_limit_reduces_current() {
+        let mut guard = guard();
+        // A single phase load of 10A on the third phase
+        let vehicles = [vehicle(Some([16.0, 0.0, 0.0]), 1, 16)];
+        let reductions = guard.check([16.5, 0.5, 10.5], &vehicles, NOW);
+        assert_eq!(reductions, vec![None]);
+        let reductions = guard.check([16.5, 0.5, 0.0], &vehicles, NOW);
+        assert_eq!(reductions, vec![None]);
+        // The vehicle draws 24A, 4A over
+        let vehicles = [vehicle(Some([24.0, 0.0, 0.0]), 1, 24)];
+        let reductions = guard.check([24.5, 0.5, 0.5], &vehicles, NOW);
+        assert_eq!(reductions, vec![Some(20)]);
+        let status = guard.status().unwrap();
+        assert_eq!(status.imbalance, 5520);
+        assert_eq!(status.headroom, vec![Some(-920)]);
+        // The readings need to settle first
+        assert_eq!(
+            guard.check([24.5, 0.5, 0.5], &vehicles, NOW + 1),
+            vec![None]
+        );
+    }
+}
@@ -1,26 +1,80 @@
-use crate::e3dc::E3DCParams;
-use crate::mennekes::MennekesParams;
 use crate::*;
 use log::{debug, error, info, warn};
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::io::{Read, Result, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::sync::mpsc::{channel, Receiver};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::thread::sleep;
 use std::time::Duration;
+use wallbox::charger::{chargers_from_config, switch_phases, Charger, ChargerParams, VehicleLoad};
+use wallbox::config::{adopt_rfid, Config, ConfigRfid, RfidMatch, SharingStrategy};
+use wallbox::controller::{
+    Controller, ControllerSettings, CurrSettings, Inputs, SessionChange, DEFAULT_MAX_PV_DATA_AGE,
+};
+use wallbox::forecast::Forecast;
+use wallbox::fuse::FuseGuard;
+use wallbox::grid::{grid_meter_from_config, GridMeter, GridParams};
+use wallbox::poller::{epoch_secs, PollerStats};
+use wallbox::pv_source::{pv_source_from_config, PvParams, PvSource};
+use wallbox::schedule::{local_time, Plan};
+use wallbox::sharing::{self, Demand};
+use wallbox::smoothing::Smoothing;
+use wallbox::tariff::Tariff;
+use wallbox::unbalance::{Unbalance, UnbalanceGuard};
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct CurrSettings {
-    pub max_session_energy: Option<u32>,
+/// How often the readings are sampled for smoothing
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the phase currents are checked against the main fuse and
+/// the unbalanced load limit
+const FUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Seconds after which the grid meter's phase currents are too old to
+/// protect the main fuse with
+const MAX_FUSE_DATA_AGE: u64 = 5;
+/// The charging currents of adopted tags without a default profile
+const ADOPTED_MIN_AMP: u16 = 6;
+const ADOPTED_MAX_AMP: u16 = 16;
+/// The longest the main loop waits before reading the PV source again,
+/// so backup mode is noticed in time
+const MAX_LOOP_WAIT: u64 = 2;
+/// Seconds after which a charger's readings are too old, raising an
+/// alarm
+const DEFAULT_MAX_CHARGER_DATA_AGE: u64 = 60;
+
+/// A charger and the state of its session, shared with the threads
+/// that protect the fuse and publish the status
+#[derive(Clone)]
+struct Wallbox {
+    name: String,
+    charger: Arc<dyn Charger>,
+    curr_settings: Arc<Mutex<CurrSettings>>,
+    plan: Arc<Mutex<Option<Plan>>>,
+}
+
+/// An RFID tag that has no section in the configuration
+#[derive(Debug, Clone, Serialize)]
+struct UnknownRfid {
+    /// The charger it was last seen at
+    charger: String,
+    first_seen: u64,
+    last_seen: u64,
+}
+
+type UnknownRfids = Arc<Mutex<BTreeMap<String, UnknownRfid>>>;
+
+/// The readings that are too old to act on, for the status socket
+#[derive(Debug, Clone, Default, Serialize)]
+struct Failsafe {
+    /// The age of the PV reading in seconds, while the chargers fall
+    /// back to the failsafe current
+    pv_data_age: Option<u64>,
+    /// The age of each charger's reading in seconds, if it is too old
+    charger_data_age: Vec<Option<u64>>,
 }
 
 pub fn wallbox_manager(cmp: WallboxManagerParams) -> Result<()> {
-    let config: config::Config = {
-        let config_file = std::fs::read_to_string(cmp.config_path).expect("Config file");
-        toml::from_str(&config_file).expect("TOML parsing")
-    };
+    let mut config = Config::from_file(&cmp.config_path)?;
 
     fern::Dispatch::new()
         // Perform allocation-free log formatting
@@ -41,36 +95,93 @@ pub fn wallbox_manager(cmp: WallboxManagerParams) -> Result<()> {
 
     info!("Wallbox manager initializing");
 
-    let e3dc = Arc::new(
-        E3DC::new(
-            &config.e3dc.host,
-            config.e3dc.port.unwrap_or(MODBUS_DEFAULT_PORT),
-            std::time::Duration::from_secs(2),
-        )
-        .expect("Create e3dc object"),
-    );
-    let mennekes = Arc::new(
-        Mennekes::new(
-            &config.wallbox.host,
-            config.wallbox.port.unwrap_or(MODBUS_DEFAULT_PORT),
-            std::time::Duration::from_secs(2),
-        )
-        .expect("Create mennekes object"),
-    );
-
-    let curr_settings = Arc::new(Mutex::new(CurrSettings {
-        max_session_energy: None,
-    }));
-
-    let (mennekes_send, mennekes_recv) = channel();
+    let pv_source = pv_source_from_config(&config).expect("Create PV source");
+    let chargers = chargers_from_config(&config).expect("Create chargers");
+    let grid_meter = grid_meter_from_config(&config).expect("Create grid meter");
+
+    let wallboxes: Vec<Wallbox> = chargers
+        .into_iter()
+        .zip(config.wallboxes())
+        .enumerate()
+        .map(|(i, (charger, wallbox))| Wallbox {
+            name: wallbox
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("wallbox{}", i + 1)),
+            charger,
+            curr_settings: Arc::new(Mutex::new(CurrSettings {
+                max_session_energy: None,
+            })),
+            plan: Arc::new(Mutex::new(None)),
+        })
+        .collect();
+    // Only name the charger in the log if there are several
+    let label = |wallbox: &Wallbox, message: String| {
+        if wallboxes.len() > 1 {
+            format!("{}: {}", wallbox.name, message)
+        } else {
+            message
+        }
+    };
+
+    let fuse_guard = config
+        .fuse
+        .as_ref()
+        .map(|fuse| Arc::new(Mutex::new(FuseGuard::new(fuse, config.sharing))));
+    let unbalance_guard = config.unbalanced_load.as_ref().map(|unbalanced_load| {
+        Arc::new(Mutex::new(UnbalanceGuard::new(
+            unbalanced_load,
+            config.phase_voltage,
+        )))
+    });
+    if fuse_guard.is_some() || unbalance_guard.is_some() {
+        let guards = Guards {
+            fuse: fuse_guard.clone(),
+            unbalance: unbalance_guard.clone(),
+        };
+        let wallboxes = wallboxes.clone();
+        let grid_meter = grid_meter.clone();
+        let config = config.clone();
+        std::thread::spawn(move || protect_connection(guards, wallboxes, grid_meter, config));
+    }
+
+    let smoothing = config
+        .smoothing
+        .as_ref()
+        .map(|smoothing| Arc::new(Mutex::new(Smoothing::from(smoothing))));
+    if let Some(smoothing) = smoothing.clone() {
+        let pv_source = pv_source.clone();
+        let wallboxes = wallboxes.clone();
+        let grid_meter = grid_meter.clone();
+        std::thread::spawn(move || sample_readings(smoothing, pv_source, wallboxes, grid_meter));
+    }
+
+    let unknown_rfids: UnknownRfids = Arc::new(Mutex::new(BTreeMap::new()));
+    let failsafe = Arc::new(Mutex::new(Failsafe::default()));
+    let (send_adoption, recv_adoption) = channel();
+
     if let Some(bind_to) = config.bind_to.as_ref() {
-        let e3dc = e3dc.clone();
-        let curr_settings = curr_settings.clone();
+        let pv_source = pv_source.clone();
+        let wallboxes = wallboxes.clone();
+        let grid_meter = grid_meter.clone();
+        let state = State {
+            smoothing: smoothing.clone(),
+            unbalance_guard: unbalance_guard.clone(),
+            unknown_rfids: unknown_rfids.clone(),
+            failsafe: failsafe.clone(),
+        };
         let listener = std::net::TcpListener::bind(bind_to)?;
         listener.set_nonblocking(false)?;
         let (send_socket, recv_socket) = channel();
         std::thread::spawn(move || {
-            handle_requests(e3dc, mennekes_recv, curr_settings, recv_socket)
+            handle_requests(
+                pv_source,
+                wallboxes,
+                grid_meter,
+                state,
+                send_adoption,
+                recv_socket,
+            )
         });
         std::thread::spawn(move || {
             for socket in listener.incoming() {
@@ -95,12 +206,12 @@ pub fn wallbox_manager(cmp: WallboxManagerParams) -> Result<()> {
         });
     }
 
-    let mut e3dcparams;
-    let mut mennekesparams;
+    let mut pvparams;
+    let mut chargerparams = Vec::with_capacity(wallboxes.len());
     let t1 = Timeouter::new(config.initial_connection_timeout);
     loop {
-        if let Some(n) = e3dc.get_current_params() {
-            e3dcparams = n;
+        if let Some(n) = pv_source.get_pv_params() {
+            pvparams = n;
             break;
         }
         if !t1.ok() {
@@ -109,210 +220,777 @@ pub fn wallbox_manager(cmp: WallboxManagerParams) -> Result<()> {
         }
     }
 
-    let t2 = Timeouter::new(config.initial_connection_timeout);
-    loop {
-        if let Some(n) = mennekes.get_current_params() {
-            mennekesparams = n;
-            break;
-        }
-        if !t2.ok() {
-            error!("Timeout while making initial connection to wallbox");
-            std::process::exit(11);
+    for wallbox in &wallboxes {
+        let t2 = Timeouter::new(config.initial_connection_timeout);
+        loop {
+            if let Some(n) = wallbox.charger.get_charger_params() {
+                chargerparams.push(n);
+                break;
+            }
+            if !t2.ok() {
+                error!(
+                    "Timeout while making initial connection to {}",
+                    wallbox.name
+                );
+                std::process::exit(11);
+            }
         }
     }
 
-    {
-        let mennekes = mennekes.clone();
-        thread::spawn(move || loop {
-            if let Some(mennekesparams) = mennekes.get_current_params() {
-                if let Err(e) = mennekes_send.send(mennekesparams) {
-                    warn!("Unable to send mennekes params: {}", e.to_string());
-                }
-            }
-            sleep(Duration::from_secs(1));
-        });
-    }
     info!("Successfully connected to the PV and EV systems.");
 
     info!("Starting main event loop");
-    let mut current_rfid = None::<String>;
+    let mut controllers: Vec<Controller> = wallboxes
+        .iter()
+        .map(|_| Controller::new(ControllerSettings::from(&config)))
+        .collect();
+    let mut next_evaluation = vec![0; wallboxes.len()];
+    let mut tariff = config.tariff.as_ref().map(|t| Tariff::new(&t.file));
+    let mut forecast = config.forecast.as_ref().map(Forecast::from);
+    let mut island = false;
     loop {
-        if let Some(n) = e3dc.get_current_params() {
-            e3dcparams = n;
+        while let Ok((user_id, name)) = recv_adoption.try_recv() {
+            if !adopt(&mut config, &cmp.config_path, &user_id, name) {
+                continue;
+            }
+            if let Ok(mut unknown_rfids) = unknown_rfids.lock() {
+                unknown_rfids.remove(&user_id.to_uppercase());
+            }
+        }
+        if let Some(tariff) = tariff.as_mut() {
+            let refreshed = tariff.refresh();
+            log_refresh(refreshed, "prices", tariff.len(), tariff.path());
+        }
+        if let Some(forecast) = forecast.as_mut() {
+            let refreshed = forecast.refresh();
+            log_refresh(
+                refreshed,
+                "forecast values",
+                forecast.len(),
+                forecast.path(),
+            );
+        }
+        if let Some(n) = pv_source.get_pv_params() {
+            pvparams = n;
+        }
+        if pvparams.island != island {
+            island = pvparams.island;
+            if island {
+                warn!(
+                    "The PV system runs in backup mode without the grid, limiting the charging current to {}A",
+                    config.island_amps.unwrap_or(0)
+                );
+            } else {
+                info!("The grid is back, resuming normal control");
+            }
+            // Don't wait for the next evaluation
+            next_evaluation.fill(0);
+        }
+        for (wallbox, chargerparams) in wallboxes.iter().zip(chargerparams.iter_mut()) {
+            if let Some(n) = wallbox.charger.get_charger_params() {
+                *chargerparams = n;
+            }
+        }
+
+        let sessions: Vec<CurrSettings> = wallboxes
+            .iter()
+            .map(|wallbox| {
+                wallbox
+                    .curr_settings
+                    .lock()
+                    .map(|cs| (*cs).clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        let now = epoch_secs()?;
+        let pv_changed = check_data_age(
+            &failsafe,
+            &wallboxes,
+            &config,
+            &pvparams,
+            &chargerparams,
+            now,
+        );
+        if pv_changed {
+            // Fall back to the failsafe current or resume right away
+            next_evaluation.fill(0);
         }
-        if let Some(n) = mennekes.get_current_params() {
-            mennekesparams = n;
+        for (wallbox, chargerparams) in wallboxes.iter().zip(&chargerparams) {
+            let user_id = chargerparams.user_id.as_deref().unwrap_or("");
+            if chargerparams.connected && !user_id.is_empty() {
+                record_unknown_rfid(&config, &unknown_rfids, wallbox, user_id, now);
+            }
         }
+        let rfids: Vec<Option<&ConfigRfid>> = chargerparams
+            .iter()
+            .map(|chargerparams| config.rfid_for(chargerparams.user_id.as_deref().unwrap_or("")))
+            .collect();
+        // Decide on the smoothed readings, if configured
+        let readings = match &smoothing {
+            Some(smoothing) => smoothed(
+                smoothing,
+                grid_meter.as_deref(),
+                &pvparams,
+                charging_power(&chargerparams),
+            ),
+            None => Readings {
+                grid: grid_params(grid_meter.as_deref(), &pvparams),
+                pv: pvparams.clone(),
+            },
+        };
+        let (pv, grid) = (&readings.pv, readings.grid.as_ref());
+        let inputs = |i: usize, budget: Option<i32>| Inputs {
+            pv,
+            charger: &chargerparams[i],
+            grid,
+            rfid: rfids[i],
+            session: &sessions[i],
+            now,
+            local_time: local_time(now),
+            tariff: tariff.as_ref(),
+            forecast: forecast.as_ref(),
+            budget,
+        };
+        let budgets = share_surplus(
+            config.sharing,
+            &controllers,
+            &chargerparams,
+            &rfids,
+            &inputs,
+        );
 
-        if mennekesparams.control_pilot == 0 {
-            if let Some(vn) = current_rfid.take() {
-                info!("Vehicle disconnected ({})", vn);
-                if let Ok(mut cs) = curr_settings.lock() {
-                    cs.max_session_energy = None;
+        let currents = unbalance_guard.as_ref().and_then(|_| {
+            phase_currents(grid_meter.as_deref(), chargerparams.iter().map(Some), now)
+        });
+
+        for (i, wallbox) in wallboxes.iter().enumerate() {
+            if now < next_evaluation[i] {
+                continue;
+            }
+            let controller = &mut controllers[i];
+            let mut decision = controller.decide(&inputs(i, budgets[i]));
+            next_evaluation[i] = decision.next_evaluation;
+            if let Ok(mut plan) = wallbox.plan.lock() {
+                *plan = controller.plan().cloned();
+            }
+            // The fuse and the unbalanced load limit override whatever
+            // the controller decided
+            let fuse_cap = fuse_guard
+                .as_ref()
+                .and_then(|g| g.lock().ok().and_then(|g| g.cap(i, now)));
+            if let Some(cap) = fuse_cap {
+                if decision.amps.unwrap_or(chargerparams[i].current_setpoint) > cap {
+                    decision.amps = Some(cap);
+                    decision.reason = format!(
+                        "Limiting charging current to {}A to protect the main fuse",
+                        cap
+                    );
                 }
             }
-            let msg = format!(
-                "No vehicle connected, setting MAX_AMPS to the configured default of {}A",
-                config.default_amps
-            );
-            mennekes.set_amps(config.default_amps, msg);
-        } else {
-            let current_vehicle = mennekesparams
-                .user_id
+            let vehicle = VehicleLoad {
+                currents: chargerparams[i].phase_current,
+                phases: controller.phases(),
+                setpoint: chargerparams[i].current_setpoint,
+                priority: 0,
+                since: now,
+            };
+            let unbalance_cap = unbalance_guard
                 .as_ref()
-                .map(std::string::String::as_str)
-                .unwrap_or("")
-                .to_uppercase();
-            if let Some(vehicle_settings) = config.rfid.get(&current_vehicle) {
-                if current_rfid.is_none()
-                    || current_rfid.as_ref().unwrap().ne(&vehicle_settings.name)
-                {
-                    info!("Vehicle connected: {}", vehicle_settings.name);
-                    current_rfid = Some(vehicle_settings.name.clone());
-                    if let Ok(mut cs) = curr_settings.lock() {
-                        cs.max_session_energy = vehicle_settings.max_charge;
+                .zip(currents)
+                .and_then(|(g, currents)| g.lock().ok().and_then(|g| g.limit(currents, &vehicle)));
+            if let Some(cap) = unbalance_cap {
+                if decision.amps.unwrap_or(chargerparams[i].current_setpoint) > cap {
+                    decision.amps = Some(cap);
+                    decision.reason = format!(
+                        "Limiting charging current to {}A to keep the unbalanced load between the phases within the limit",
+                        cap
+                    );
+                }
+            }
+
+            match &decision.session {
+                Some(SessionChange::Connected {
+                    name,
+                    max_session_energy,
+                }) => {
+                    info!("{}", label(wallbox, format!("Vehicle connected: {}", name)));
+                    if let Ok(mut cs) = wallbox.curr_settings.lock() {
+                        cs.max_session_energy = *max_session_energy;
                     }
                 }
-                let curr_session_energy = curr_settings
-                    .lock()
-                    .map(|i| i.max_session_energy)
-                    .unwrap_or(None);
-                if mennekesparams.charging_duration < config.initial_phase_duration {
-                    let msg = format!(
-                        "Vehicle {} connected for less than {} seconds, signalling {} amps",
-                        vehicle_settings.name, config.initial_phase_duration, config.default_amps
-                    );
-                    mennekes.set_amps(config.default_amps, msg);
-                    std::thread::sleep(std::time::Duration::from_secs(60));
-                } else if curr_session_energy.is_some()
-                    && curr_session_energy.unwrap() < mennekesparams.current_energy
-                {
-                    let msg = format!(
-                        "Vehicle {} has charged {}Wh, the limit is {}Wh. Stopping the charging.",
-                        vehicle_settings.name,
-                        mennekesparams.current_energy,
-                        curr_session_energy.unwrap()
+                Some(SessionChange::Disconnected(name)) => {
+                    info!(
+                        "{}",
+                        label(wallbox, format!("Vehicle disconnected ({})", name))
                     );
-                    mennekes.set_amps(0, msg);
-                } else {
-                    let charging_power = mennekesparams.power as i32;
-                    let available_power =
-                        e3dcparams.pv_power + charging_power - e3dcparams.haus_power;
-                    let step_power =
-                        (1/* amps */) * config.phase_voltage as i32 * config.phases.number() as i32;
-                    let charging_power_computed = (mennekesparams.hems_current as i32) * step_power;
-                    let minimum_charging_power = vehicle_settings
-                        .minimum_charging_power
-                        .unwrap_or(step_power * vehicle_settings.min_amp as i32);
-                    debug!(
-                        "PV_Power {}W HausPower {}W",
-                        e3dcparams.pv_power, e3dcparams.haus_power
-                    );
-                    debug!("Charging power {}W Available power {}W Step power {}W ChargingPowerComputed {}W",
-                        charging_power, available_power, step_power, charging_power_computed);
-                    if available_power < minimum_charging_power {
-                        if vehicle_settings.pv_only {
-                            let msg = format!("Available PV power of {}Watts is less than minimum charging power of {}Watts. Halting charging.", available_power, minimum_charging_power);
-                            mennekes.set_amps(0, msg);
-                            std::thread::sleep(std::time::Duration::from_secs(120));
-                            continue;
-                        } else {
-                            debug!("Available PV power of {}Watts is less than minimum charging power of {}Watts. Proceeding nevertheless.", available_power, minimum_charging_power);
-                        }
-                    }
-                    let step_power_with_hysteresis = step_power + config.hysteresis_watts;
-                    if available_power < charging_power
-                        && mennekesparams.hems_current > vehicle_settings.min_amp
-                    {
-                        let num_amps = std::cmp::max(
-                            vehicle_settings.min_amp,
-                            std::cmp::min(
-                                vehicle_settings.max_amp,
-                                ((available_power as f64) / (step_power as f64)).floor() as u16,
-                            ),
-                        );
-                        let msg = format!("Reducing charging current to {}A", num_amps);
-                        mennekes.set_amps(num_amps, msg);
-                    } else if available_power
-                        > (charging_power_computed + step_power_with_hysteresis)
-                        && mennekesparams.hems_current < vehicle_settings.max_amp
-                    {
-                        let set_to = std::cmp::max(
-                            mennekesparams.hems_current + 1,
-                            vehicle_settings.min_amp,
-                        );
-                        let msg = format!(
-                            "Excessive power of {} Watts is available, increasing charging current to {}A"
-                            , available_power, set_to
-                        );
-                        mennekes.set_amps(set_to, msg);
-                    } else if mennekesparams.hems_current < vehicle_settings.min_amp {
-                        let set_to =
-                            std::cmp::max(mennekesparams.hems_current + 1, config.default_amps);
-                        let msg = format!(
-                            "HEMS current {}A < than min_amp of {}A, increasing power to {}A",
-                            mennekesparams.hems_current, vehicle_settings.min_amp, set_to
-                        );
-                        mennekes.set_amps(set_to, msg);
+                    if let Ok(mut cs) = wallbox.curr_settings.lock() {
+                        cs.max_session_energy = None;
                     }
                 }
-            } else {
-                let msg = format!(
-                    "Unknown RFID tag {}, setting MAX_AMPS to 0A!",
-                    current_vehicle
-                );
-                mennekes.set_amps(0, msg);
-                std::thread::sleep(std::time::Duration::from_secs(60));
+                None => (),
+            }
+            match decision.amps {
+                Some(amps) => wallbox
+                    .charger
+                    .set_amps(amps, label(wallbox, decision.reason)),
+                None => debug!("{}", label(wallbox, decision.reason)),
+            }
+            if let (Some(phases), Some(phase_switch)) = (decision.phases, &config.phase_switch) {
+                switch_phases(wallbox.charger.as_ref(), &phase_switch.output, phases);
             }
         }
 
-        std::thread::sleep(std::time::Duration::from_secs(20));
+        let next = next_evaluation.iter().min().copied().unwrap_or_default();
+        let wait = next.saturating_sub(epoch_secs()?).min(MAX_LOOP_WAIT);
+        std::thread::sleep(Duration::from_secs(wait));
+    }
+}
+
+/// Check the age of the readings, log when they become too old or fresh
+/// again and publish which ones are too old. The controllers fall back
+/// to the failsafe current themselves while the PV reading is too old.
+/// Returns whether the PV reading became too old or fresh again.
+fn check_data_age(
+    failsafe: &Mutex<Failsafe>,
+    wallboxes: &[Wallbox],
+    config: &Config,
+    pv: &PvParams,
+    chargerparams: &[ChargerParams],
+    now: u64,
+) -> bool {
+    let Ok(mut failsafe) = failsafe.lock() else {
+        return false;
+    };
+    let max_pv_data_age = config.max_pv_data_age.unwrap_or(DEFAULT_MAX_PV_DATA_AGE);
+    let max_charger_data_age = config
+        .max_charger_data_age
+        .unwrap_or(DEFAULT_MAX_CHARGER_DATA_AGE);
+    let too_old =
+        |update: u64, max_age: u64| Some(now.saturating_sub(update)).filter(|age| *age > max_age);
+
+    let pv_data_age = too_old(pv.update, max_pv_data_age);
+    match (failsafe.pv_data_age, pv_data_age) {
+        (None, Some(age)) => warn!(
+            "The PV reading is {} seconds old, falling back to the failsafe current",
+            age
+        ),
+        (Some(_), None) => info!("The PV readings are fresh again, resuming normal control"),
+        _ => (),
+    }
+    let pv_changed = failsafe.pv_data_age.is_some() != pv_data_age.is_some();
+    failsafe.pv_data_age = pv_data_age;
+
+    failsafe.charger_data_age.resize(wallboxes.len(), None);
+    for (i, (wallbox, chargerparams)) in wallboxes.iter().zip(chargerparams).enumerate() {
+        let age = too_old(chargerparams.update, max_charger_data_age);
+        match (failsafe.charger_data_age[i], age) {
+            (None, Some(age)) => error!(
+                "ALARM: The reading of charger {} is {} seconds old",
+                wallbox.name, age
+            ),
+            (Some(_), None) => info!("The readings of charger {} are fresh again", wallbox.name),
+            _ => (),
+        }
+        failsafe.charger_data_age[i] = age;
+    }
+    pv_changed
+}
+
+/// Record the tag if the configuration doesn't know it, so it can be
+/// adopted later on
+fn record_unknown_rfid(
+    config: &Config,
+    unknown_rfids: &UnknownRfids,
+    wallbox: &Wallbox,
+    user_id: &str,
+    now: u64,
+) {
+    if config.known_rfid(user_id).is_some() {
+        return;
+    }
+    let Ok(mut unknown_rfids) = unknown_rfids.lock() else {
+        return;
+    };
+    let user_id = user_id.to_uppercase();
+    let unknown = unknown_rfids.entry(user_id.clone()).or_insert_with(|| {
+        match &config.default_rfid {
+            Some(guest) => info!(
+                "Unknown RFID tag {} at {}, charging it as {}",
+                user_id, wallbox.name, guest.name
+            ),
+            None => info!("Unknown RFID tag {} at {}", user_id, wallbox.name),
+        }
+        UnknownRfid {
+            charger: wallbox.name.clone(),
+            first_seen: now,
+            last_seen: now,
+        }
+    });
+    unknown.charger = wallbox.name.clone();
+    unknown.last_seen = now;
+}
+
+/// Add a section for the tag to the configuration and its file, with the
+/// settings of the default profile or, without one, charging from PV
+/// only. Returns whether the tag was adopted.
+fn adopt(config: &mut Config, path: &Path, user_id: &str, name: String) -> bool {
+    let user_id = user_id.to_uppercase();
+    let rfid = match &config.default_rfid {
+        Some(guest) => ConfigRfid {
+            name,
+            matching: RfidMatch::Exact,
+            ..guest.clone()
+        },
+        None => ConfigRfid {
+            name,
+            matching: RfidMatch::Exact,
+            pv_only: true,
+            min_amp: ADOPTED_MIN_AMP,
+            max_amp: ADOPTED_MAX_AMP,
+            max_charge: None,
+            minimum_charging_power: None,
+            priority: 0,
+            schedule: Vec::new(),
+            departure: Vec::new(),
+            price_policy: None,
+            min_charge_duration: None,
+            min_pause_duration: None,
+            max_cycles: None,
+        },
+    };
+    match adopt_rfid(path, &user_id, &rfid) {
+        Ok(()) => {
+            info!("Adopted RFID tag {} as {}", user_id, rfid.name);
+            config.rfid.insert(user_id, rfid);
+            true
+        }
+        Err(e) => {
+            warn!("Cannot adopt RFID tag {} into {:?}: {}", user_id, path, e);
+            false
+        }
     }
 }
 
+/// The power budget of each charger's session if several vehicles
+/// share the surplus: what is available to all of them together, split
+/// by the configured strategy
+fn share_surplus<'a>(
+    strategy: SharingStrategy,
+    controllers: &[Controller],
+    chargerparams: &[ChargerParams],
+    rfids: &[Option<&ConfigRfid>],
+    inputs: &impl Fn(usize, Option<i32>) -> Inputs<'a>,
+) -> Vec<Option<i32>> {
+    let mut budgets = vec![None; controllers.len()];
+    let sessions: Vec<(usize, &ConfigRfid)> = (0..controllers.len())
+        .filter(|&i| chargerparams[i].connected)
+        .filter_map(|i| rfids[i].map(|rfid| (i, rfid)))
+        .collect();
+    if sessions.len() < 2 {
+        return budgets;
+    }
+    let charging_power: i32 = sessions
+        .iter()
+        .map(|&(i, _)| chargerparams[i].power as i32)
+        .sum();
+    let inputs = inputs(sessions[0].0, None);
+    let total = controllers[sessions[0].0].surplus(&inputs) + charging_power;
+    let demands: Vec<Demand> = sessions
+        .iter()
+        .map(|&(i, rfid)| {
+            let (min, max) = controllers[i].power_range(rfid);
+            Demand {
+                min,
+                max,
+                priority: rfid.priority,
+                since: inputs
+                    .now
+                    .saturating_sub(chargerparams[i].session_duration as u64),
+            }
+        })
+        .collect();
+    for (&(i, _), share) in sessions
+        .iter()
+        .zip(sharing::share(strategy, total, &demands))
+    {
+        budgets[i] = Some(share);
+    }
+    budgets
+}
+
 #[derive(Serialize)]
 struct CV {
-    e3dc: E3DCParams,
-    mennekes: MennekesParams,
+    /// The raw readings of the PV source, kept under this key so
+    /// existing consumers of the status socket continue to work
+    e3dc: Option<serde_json::Value>,
+    pv: PvParams,
+    /// The raw readings of the charger, see above
+    mennekes: Option<serde_json::Value>,
+    charger: ChargerParams,
+    /// The raw readings of the PAC2200, if one is configured
+    pac2200: Option<serde_json::Value>,
+    grid: Option<GridParams>,
+    /// The readings the decisions are based on, if they are smoothed
+    smoothed: Option<Readings>,
+    /// The unbalanced load between the phases and the headroom of each
+    /// charger, if limited
+    unbalanced_load: Option<Unbalance>,
+    curr_session: Option<CurrSettings>,
+    /// The plan of the connected vehicle's session
+    plan: Option<Plan>,
+    /// The state of every charger, the first one included
+    chargers: Vec<ChargerStatus>,
+    /// Tags seen since the start that the configuration doesn't know
+    unknown_rfids: BTreeMap<String, UnknownRfid>,
+    /// The readings that are too old to act on
+    failsafe: Failsafe,
+    devices: DeviceStats,
+}
+
+#[derive(Serialize)]
+struct ChargerStatus {
+    name: String,
+    /// The raw readings of the charger
+    mennekes: Option<serde_json::Value>,
+    charger: Option<ChargerParams>,
     curr_session: Option<CurrSettings>,
+    plan: Option<Plan>,
+    stats: PollerStats,
+}
+
+impl ChargerStatus {
+    fn new(wallbox: &Wallbox) -> ChargerStatus {
+        ChargerStatus {
+            name: wallbox.name.clone(),
+            mennekes: wallbox.charger.get_raw_params(),
+            charger: wallbox.charger.get_charger_params(),
+            curr_session: wallbox.curr_settings.lock().map(|cs| (*cs).clone()).ok(),
+            plan: wallbox
+                .plan
+                .lock()
+                .map(|p| (*p).clone())
+                .unwrap_or_default(),
+            stats: wallbox.charger.stats(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceStats {
+    pv_source: PollerStats,
+    /// The first charger, see above
+    charger: PollerStats,
+    /// Every charger, the first one included
+    chargers: Vec<PollerStats>,
+    grid_meter: Option<PollerStats>,
+}
+
+impl DeviceStats {
+    fn new(
+        pv_source: &dyn PvSource,
+        wallboxes: &[Wallbox],
+        grid_meter: Option<&dyn GridMeter>,
+    ) -> DeviceStats {
+        DeviceStats {
+            pv_source: pv_source.stats(),
+            charger: wallboxes[0].charger.stats(),
+            chargers: wallboxes.iter().map(|w| w.charger.stats()).collect(),
+            grid_meter: grid_meter.map(|g| g.stats()),
+        }
+    }
+}
+
+/// Log the outcome of reloading a file the decisions are based on
+fn log_refresh(refreshed: Result<bool>, what: &str, count: usize, path: &Path) {
+    match refreshed {
+        Ok(true) => info!("Loaded {} {} from {:?}", count, what, path),
+        Ok(false) => (),
+        Err(e) => warn!("Cannot load the {} from {:?}: {}", what, path, e),
+    }
+}
+
+/// The grid reading of the grid meter or, without one, of the PV source
+fn grid_params(grid_meter: Option<&dyn GridMeter>, pv: &PvParams) -> Option<GridParams> {
+    match grid_meter {
+        Some(grid_meter) => grid_meter.get_grid_params(),
+        None => Some(GridParams::from(pv)),
+    }
+}
+
+/// The readings of the PV source and the grid meter
+#[derive(Serialize)]
+struct Readings {
+    pv: PvParams,
+    grid: Option<GridParams>,
+}
+
+/// Sample the readings of the PV source and the grid meter for smoothing
+fn sample_readings(
+    smoothing: Arc<Mutex<Smoothing>>,
+    pv_source: Arc<dyn PvSource>,
+    wallboxes: Vec<Wallbox>,
+    grid_meter: Option<Arc<dyn GridMeter>>,
+) {
+    loop {
+        std::thread::sleep(SAMPLE_INTERVAL);
+        let pv = pv_source.get_pv_params();
+        let grid = grid_meter.as_ref().and_then(|g| g.get_grid_params());
+        let charging = current_charging_power(&wallboxes);
+        if let Ok(mut smoothing) = smoothing.lock() {
+            if let Some(pv) = pv {
+                smoothing.add_pv(&pv, charging);
+            }
+            if let Some(grid) = grid {
+                smoothing.add_grid(&grid, charging);
+            }
+        }
+    }
+}
+
+/// The power all vehicles draw together
+fn charging_power<'a>(chargerparams: impl IntoIterator<Item = &'a ChargerParams>) -> i32 {
+    chargerparams
+        .into_iter()
+        .map(|chargerparams| chargerparams.power as i32)
+        .sum()
+}
+
+/// The power all vehicles draw together, by the latest readings of the
+/// wallboxes
+fn current_charging_power(wallboxes: &[Wallbox]) -> i32 {
+    let chargerparams: Vec<ChargerParams> = wallboxes
+        .iter()
+        .filter_map(|wallbox| wallbox.charger.get_charger_params())
+        .collect();
+    charging_power(&chargerparams)
+}
+
+/// The smoothed readings, based on the given PV reading and the power
+/// the vehicles draw
+fn smoothed(
+    smoothing: &Mutex<Smoothing>,
+    grid_meter: Option<&dyn GridMeter>,
+    pv: &PvParams,
+    charging_power: i32,
+) -> Readings {
+    let Ok(smoothing) = smoothing.lock() else {
+        return Readings {
+            grid: grid_params(grid_meter, pv),
+            pv: pv.clone(),
+        };
+    };
+    let pv = smoothing.pv(pv, charging_power);
+    let grid = match grid_meter {
+        Some(grid_meter) => grid_meter
+            .get_grid_params()
+            .map(|grid| smoothing.grid(&grid, charging_power)),
+        None => Some(GridParams::from(&pv)),
+    };
+    Readings { pv, grid }
+}
+
+/// The guards of the grid connection
+struct Guards {
+    fuse: Option<Arc<Mutex<FuseGuard>>>,
+    unbalance: Option<Arc<Mutex<UnbalanceGuard>>>,
+}
+
+/// The currents per phase of the grid connection, measured by the grid
+/// meter or, without one, the sum of the chargers' currents
+fn phase_currents<'a>(
+    grid_meter: Option<&dyn GridMeter>,
+    mut chargerparams: impl Iterator<Item = Option<&'a ChargerParams>>,
+    now: u64,
+) -> Option<[f32; 3]> {
+    match grid_meter {
+        Some(grid_meter) => grid_meter
+            .get_grid_params()
+            .filter(|g| now.saturating_sub(g.update) <= MAX_FUSE_DATA_AGE)
+            .and_then(|g| g.phase_current),
+        // Without a grid meter, only the chargers are known to draw
+        None => chargerparams.try_fold([0.0; 3], |sum, params| {
+            let currents = params?.phase_current?;
+            Some([0, 1, 2].map(|phase| sum[phase] + currents[phase]))
+        }),
+    }
+}
+
+/// Check the phase currents every second and reduce the charging
+/// current as soon as the main fuse comes close to its limit or the
+/// unbalanced load between the phases exceeds its limit
+fn protect_connection(
+    guards: Guards,
+    wallboxes: Vec<Wallbox>,
+    grid_meter: Option<Arc<dyn GridMeter>>,
+    config: Config,
+) {
+    // Vehicles that don't draw current yet may charge on one phase
+    // as soon as they start
+    let phases = match config.phase_switch {
+        Some(_) => 1,
+        None => config.phases.number(),
+    };
+    let mut missing_currents = false;
+    loop {
+        std::thread::sleep(FUSE_CHECK_INTERVAL);
+        let Ok(now) = epoch_secs() else {
+            continue;
+        };
+        let chargerparams: Vec<Option<ChargerParams>> = wallboxes
+            .iter()
+            .map(|wallbox| wallbox.charger.get_charger_params())
+            .collect();
+        let currents = phase_currents(
+            grid_meter.as_deref(),
+            chargerparams.iter().map(Option::as_ref),
+            now,
+        );
+        let Some(currents) = currents else {
+            if !missing_currents {
+                warn!("No phase currents to protect the grid connection with");
+                missing_currents = true;
+            }
+            continue;
+        };
+        missing_currents = false;
+
+        let loads: Vec<VehicleLoad> = chargerparams
+            .iter()
+            .map(|params| match params {
+                Some(params) => VehicleLoad {
+                    currents: params.phase_current,
+                    phases,
+                    setpoint: params.current_setpoint,
+                    priority: params
+                        .user_id
+                        .as_ref()
+                        .and_then(|id| config.rfid_for(id))
+                        .map_or(0, |rfid| rfid.priority),
+                    since: now.saturating_sub(params.session_duration as u64),
+                },
+                // Unknown, so it can't be reduced either
+                None => VehicleLoad {
+                    currents: None,
+                    phases,
+                    setpoint: 0,
+                    priority: 0,
+                    since: now,
+                },
+            })
+            .collect();
+
+        if let Some(Ok(mut guard)) = guards.fuse.as_ref().map(|g| g.lock()) {
+            let reductions = guard.check(currents, &loads, now);
+            for ((wallbox, load), amps) in wallboxes.iter().zip(&loads).zip(reductions) {
+                if let Some(amps) = amps {
+                    wallbox.charger.set_amps(
+                        amps,
+                        format!(
+                            "Phase currents of {:?}A come close to the main fuse's limit, reducing the charging current of {} from {}A to {}A",
+                            currents, wallbox.name, load.setpoint, amps
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Some(Ok(mut guard)) = guards.unbalance.as_ref().map(|g| g.lock()) {
+            let reductions = guard.check(currents, &loads, now);
+            for ((wallbox, vehicle), amps) in wallboxes.iter().zip(&loads).zip(reductions) {
+                if let Some(amps) = amps {
+                    wallbox.charger.set_amps(
+                        amps,
+                        format!(
+                            "Phase currents of {:?}A exceed the unbalanced load limit, reducing the charging current of {} from {}A to {}A",
+                            currents, wallbox.name, vehicle.setpoint, amps
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// What the manager keeps track of besides the readings, for the status
+/// socket
+struct State {
+    smoothing: Option<Arc<Mutex<Smoothing>>>,
+    unbalance_guard: Option<Arc<Mutex<UnbalanceGuard>>>,
+    unknown_rfids: UnknownRfids,
+    failsafe: Arc<Mutex<Failsafe>>,
 }
 
 fn handle_requests(
-    e3dc: Arc<E3DC>,
-    mennekes_recv: Receiver<MennekesParams>,
-    curr_settings: Arc<Mutex<CurrSettings>>,
+    pv_source: Arc<dyn PvSource>,
+    wallboxes: Vec<Wallbox>,
+    grid_meter: Option<Arc<dyn GridMeter>>,
+    state: State,
+    adoptions: Sender<(String, String)>,
     new_sockets: Receiver<(TcpStream, SocketAddr)>,
 ) {
+    let grid_meter = grid_meter.as_deref();
+    let State {
+        smoothing,
+        unbalance_guard,
+        unknown_rfids,
+        failsafe,
+    } = state;
     let mut sockets: Vec<(TcpStream, SocketAddr)> = Vec::new();
     let interval = Duration::from_secs(1);
 
-    let cur_values_e3dc;
-    let cur_values_mennekes;
+    let cur_values_pv;
+    let cur_values_charger;
+    // The first charger is published under the keys of a single one
+    let first = &wallboxes[0];
 
     loop {
-        if let Some(cv) = e3dc.get_current_params() {
-            cur_values_e3dc = cv;
+        if let Some(cv) = pv_source.get_pv_params() {
+            cur_values_pv = cv;
             break;
         }
     }
 
     loop {
-        if let Ok(cv) = mennekes_recv.try_recv() {
-            cur_values_mennekes = cv;
+        if let Some(cv) = first.charger.get_charger_params() {
+            cur_values_charger = cv;
             break;
         }
     }
 
-    let cs = curr_settings.lock().map(|cs| (*cs).clone()).ok();
+    let cs = first.curr_settings.lock().map(|cs| (*cs).clone()).ok();
+    let unbalance = || {
+        unbalance_guard
+            .as_ref()
+            .and_then(|g| g.lock().ok().and_then(|g| g.status().cloned()))
+    };
     let mut cv = CV {
-        e3dc: cur_values_e3dc,
-        mennekes: cur_values_mennekes,
+        unbalanced_load: unbalance(),
+        smoothed: smoothing.as_ref().map(|smoothing| {
+            let charging = current_charging_power(&wallboxes);
+            smoothed(smoothing, grid_meter, &cur_values_pv, charging)
+        }),
+        e3dc: pv_source.get_raw_params(),
+        grid: grid_params(grid_meter, &cur_values_pv),
+        pv: cur_values_pv,
+        mennekes: first.charger.get_raw_params(),
+        charger: cur_values_charger,
+        pac2200: grid_meter.and_then(|g| g.get_raw_params()),
         curr_session: cs,
+        plan: first.plan.lock().map(|p| (*p).clone()).unwrap_or_default(),
+        chargers: wallboxes.iter().map(ChargerStatus::new).collect(),
+        unknown_rfids: unknown_rfids
+            .lock()
+            .map(|u| (*u).clone())
+            .unwrap_or_default(),
+        failsafe: failsafe.lock().map(|f| (*f).clone()).unwrap_or_default(),
+        devices: DeviceStats::new(pv_source.as_ref(), &wallboxes, grid_meter),
     };
 
     let mut sockets_to_remove = Vec::new();
     let mut read_buf = [0u8; 1024];
-    let re_set_energy = Regex::new("set-energy ([0-9]+)").expect("Our regex at 0x0132");
+    let re_set_energy =
+        Regex::new("set-energy ([0-9]+)(?: ([0-9]+))?").expect("Our regex at 0x0132");
+    let re_adopt_rfid = Regex::new("adopt-rfid ([0-9A-Za-z]+) ([^\r\n]+)").expect("Our regex");
     loop {
         let mut load = serde_json::to_string(&cv).expect("serde_json");
         load.push('\n');
@@ -332,10 +1010,25 @@ fn handle_requests(
                         if let Some(set_energy) = re_set_energy.captures(&read_string) {
                             let m1 = set_energy.get(1).expect("Get capture at 0x0145").as_str();
                             let m1_p: u32 = m1.parse().expect("Parse number at 0x0146");
-                            if let Ok(mut cs) = curr_settings.lock() {
-                                cs.max_session_energy = Some(m1_p);
+                            // The index of the charger, the first one by default
+                            let m2_p = set_energy
+                                .get(2)
+                                .and_then(|m2| m2.as_str().parse::<usize>().ok())
+                                .unwrap_or(0);
+                            match wallboxes.get(m2_p) {
+                                Some(wallbox) => {
+                                    if let Ok(mut cs) = wallbox.curr_settings.lock() {
+                                        cs.max_session_energy = Some(m1_p);
+                                    }
+                                }
+                                None => debug!("No charger with index {}", m2_p),
                             }
                         }
+                        if let Some(adopt_rfid) = re_adopt_rfid.captures(&read_string) {
+                            let user_id = String::from(&adopt_rfid[1]);
+                            let name = String::from(adopt_rfid[2].trim());
+                            adoptions.send((user_id, name)).expect("Channel");
+                        }
                     }
                 }
             }
@@ -349,12 +1042,29 @@ fn handle_requests(
         }
         std::thread::sleep(interval);
 
-        if let Some(cve) = e3dc.get_current_params() {
-            cv.e3dc = cve;
+        if let Some(cvp) = pv_source.get_pv_params() {
+            cv.pv = cvp;
+            cv.e3dc = pv_source.get_raw_params();
         }
-        while let Ok(cvm) = mennekes_recv.try_recv() {
-            cv.mennekes = cvm;
+        if let Some(cvc) = first.charger.get_charger_params() {
+            cv.charger = cvc;
+            cv.mennekes = first.charger.get_raw_params();
         }
-        cv.curr_session = curr_settings.lock().map(|cs| (*cs).clone()).ok();
+        cv.grid = grid_params(grid_meter, &cv.pv);
+        cv.smoothed = smoothing.as_ref().map(|smoothing| {
+            let charging = current_charging_power(&wallboxes);
+            smoothed(smoothing, grid_meter, &cv.pv, charging)
+        });
+        cv.pac2200 = grid_meter.and_then(|g| g.get_raw_params());
+        cv.curr_session = first.curr_settings.lock().map(|cs| (*cs).clone()).ok();
+        cv.plan = first.plan.lock().map(|p| (*p).clone()).unwrap_or_default();
+        cv.chargers = wallboxes.iter().map(ChargerStatus::new).collect();
+        cv.unbalanced_load = unbalance();
+        cv.unknown_rfids = unknown_rfids
+            .lock()
+            .map(|u| (*u).clone())
+            .unwrap_or_default();
+        cv.failsafe = failsafe.lock().map(|f| (*f).clone()).unwrap_or_default();
+        cv.devices = DeviceStats::new(pv_source.as_ref(), &wallboxes, grid_meter);
     }
 }